@@ -1,3 +1,52 @@
 fn main() {
+    check_deep_link_scheme();
     tauri_build::build()
 }
+
+/// Fail the build if tauri.conf.json doesn't register the scheme the app handles
+///
+/// The app's scheme is ADDIE_DEEP_LINK_SCHEME (default "addie", as in lib.rs), but the OS
+/// registration comes from the deep-link plugin config. Whitelabel builds must override
+/// both, the config with `tauri build --config`, which reaches us as TAURI_CONFIG.
+fn check_deep_link_scheme() {
+    println!("cargo:rerun-if-changed=tauri.conf.json");
+    println!("cargo:rerun-if-env-changed=ADDIE_DEEP_LINK_SCHEME");
+    println!("cargo:rerun-if-env-changed=TAURI_CONFIG");
+
+    let scheme = std::env::var("ADDIE_DEEP_LINK_SCHEME").unwrap_or_else(|_| "addie".to_string());
+    let file: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string("tauri.conf.json").expect("tauri.conf.json is readable"),
+    )
+    .expect("tauri.conf.json is valid JSON");
+    let overrides: Option<serde_json::Value> = std::env::var("TAURI_CONFIG")
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok());
+    // A --config value replaces the file's, as Tauri merges them
+    let setting = |pointer: &str| {
+        overrides
+            .as_ref()
+            .and_then(|config| config.pointer(pointer))
+            .or_else(|| file.pointer(pointer))
+            .cloned()
+            .unwrap_or_default()
+    };
+
+    let registers = |schemes: &serde_json::Value| {
+        schemes
+            .as_array()
+            .is_some_and(|schemes| schemes.iter().any(|s| s.as_str() == Some(scheme.as_str())))
+    };
+    let desktop = setting("/plugins/deep-link/desktop/schemes");
+    let mobile = setting("/plugins/deep-link/mobile");
+    let mobile_ok = mobile
+        .as_array()
+        .is_some_and(|entries| entries.iter().any(|entry| registers(&entry["scheme"])));
+
+    if !registers(&desktop) || !mobile_ok {
+        panic!(
+            "The app handles {scheme}:// links, but the deep-link plugin config registers \
+             desktop {desktop} and mobile {mobile}. Set plugins.deep-link in tauri.conf.json, \
+             or pass the same scheme with `tauri build --config`."
+        );
+    }
+}
@@ -0,0 +1,23 @@
+//! Fuzz the deep link callback parser
+//!
+//! Run from src-tauri/fuzz with: cargo +nightly fuzz run parse_callback
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Deep links reach us as strings, so non-UTF-8 input can't happen
+    let Ok(url) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    if let Ok(Some(callback)) = addie_desktop_lib::parse_callback(url) {
+        let addie_desktop_lib::CallbackGrant::Session(session) = callback.grant else {
+            return;
+        };
+        assert!(!session.sealed_session.trim().is_empty());
+        assert!(session.first_name.is_none_or(|n| n.chars().count() <= 128));
+        assert!(session.last_name.is_none_or(|n| n.chars().count() <= 128));
+    }
+});
@@ -0,0 +1,1085 @@
+//! API client for AgenticAdvertising.org
+//!
+//! Small wrapper around reqwest for the calls the backend makes itself.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::get_api_base_url;
+use crate::install::install_id;
+use crate::{ApiResponse, UserSession};
+
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Attempts per mutating request when the connection fails before a response
+pub(crate) const MAX_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled on each further attempt
+pub(crate) const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Timeout for the reachability probe, kept short so login preflight stays snappy
+const PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Timeout for revoking a session at logout, which never retries
+const REVOKE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a token handle is honoured before the frontend has to ask for a fresh one
+const TOKEN_HANDLE_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Longest idempotency key accepted from the frontend
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Longest we'll wait on a server's Retry-After before retrying an idempotent request
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Errors from calls to the AAO API
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("Network error: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("Unauthorized ({})", .code.as_deref().unwrap_or("no error code"))]
+    Unauthorized { code: Option<String> },
+    #[error("Forbidden")]
+    Forbidden { body: Option<serde_json::Value> },
+    #[error("Rate limited{}", .retry_after_secs.map(|s| format!(", retry after {}s", s)).unwrap_or_default())]
+    RateLimited { retry_after_secs: Option<u64> },
+    /// 404 on an endpoint we expect to exist: the server is older or newer than this app
+    #[error("Endpoint unavailable: {path}")]
+    EndpointUnavailable { path: String },
+    #[error("Unexpected status {status}")]
+    Status {
+        status: u16,
+        body: Option<serde_json::Value>,
+    },
+    #[error("Invalid response: {0}")]
+    InvalidResponse(#[from] serde_json::Error),
+    /// The session this call was made with was cleared before it finished
+    #[error("Cancelled by logout")]
+    Cancelled,
+    /// Request not sent because network simulation is set to Offline
+    #[cfg(debug_assertions)]
+    #[error("Network error: offline (simulated)")]
+    Offline,
+}
+
+impl ApiError {
+    /// Stable identifier the frontend can branch on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Network(_) => "network",
+            ApiError::Unauthorized { .. } => "unauthorized",
+            ApiError::Forbidden { .. } => "forbidden",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::EndpointUnavailable { .. } => "not_found",
+            ApiError::Status { .. } => "http_error",
+            ApiError::InvalidResponse(_) => "invalid_response",
+            ApiError::Cancelled => "cancelled",
+            #[cfg(debug_assertions)]
+            ApiError::Offline => "network",
+        }
+    }
+
+    /// HTTP status the server answered with, if it answered
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ApiError::Unauthorized { .. } => Some(401),
+            ApiError::Forbidden { .. } => Some(403),
+            ApiError::RateLimited { .. } => Some(429),
+            ApiError::EndpointUnavailable { .. } => Some(404),
+            ApiError::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// JSON body the server sent with the error status, for the frontend to show or branch on
+    pub fn body(&self) -> Option<&serde_json::Value> {
+        match self {
+            ApiError::Forbidden { body } | ApiError::Status { body, .. } => body.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Network conditions QA can simulate for every API call (debug builds only)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkSimulation {
+    #[default]
+    Normal,
+    /// Delay each request by this many milliseconds
+    Slow(u64),
+    /// Fail each request without sending it
+    Offline,
+}
+
+/// Network conditions API calls are run under (debug builds only)
+#[cfg(all(debug_assertions, not(test)))]
+fn network_simulation() -> &'static Mutex<NetworkSimulation> {
+    static SIMULATION: Mutex<NetworkSimulation> = Mutex::new(NetworkSimulation::Normal);
+    &SIMULATION
+}
+
+/// Per thread in unit tests, so a test going offline doesn't take every test with it
+#[cfg(test)]
+fn network_simulation() -> &'static Mutex<NetworkSimulation> {
+    thread_local! {
+        static SIMULATION: &'static Mutex<NetworkSimulation> =
+            Box::leak(Box::new(Mutex::new(NetworkSimulation::Normal)));
+    }
+    SIMULATION.with(|simulation| *simulation)
+}
+
+/// Profile returned by `/auth/me`
+#[derive(Debug, Clone, Deserialize)]
+pub struct MeResponse {
+    pub id: String,
+    pub email: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+}
+
+/// Release info returned by `/app/version`
+#[derive(Debug, Clone, Deserialize)]
+pub struct LatestVersionResponse {
+    pub latest_version: String,
+    pub notes_url: Option<String>,
+    /// Whether builds older than latest_version must update before use
+    #[serde(default)]
+    pub mandatory: bool,
+}
+
+/// A way to sign in, as listed by `/auth/providers`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginProviderInfo {
+    /// Value to pass as start_login's `provider` option
+    pub id: String,
+    pub display_name: String,
+    pub icon_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginProvidersResponse {
+    providers: Vec<LoginProviderInfo>,
+}
+
+/// Session returned by `/auth/token` for a PKCE authorization code
+///
+/// Carries the same fields the direct auth callback does, validated the same way.
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub sealed_session: String,
+    pub user_id: String,
+    pub email: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub max_session_age: Option<u64>,
+    pub token_type: Option<String>,
+    pub scope: Option<String>,
+    pub expires_at: Option<u64>,
+    pub refresh_token: Option<String>,
+    pub device_token: Option<String>,
+}
+
+/// Sessions returned by `/auth/redeem-batch`, one per provisioned account
+#[derive(Debug, Deserialize)]
+pub struct RedeemBatchResponse {
+    pub sessions: Vec<TokenResponse>,
+}
+
+/// New sealed session returned by `/auth/refresh`
+#[derive(Debug, Deserialize)]
+pub struct RefreshResponse {
+    pub sealed_session: String,
+    pub expires_at: Option<u64>,
+    /// Present when the server rotates refresh tokens
+    pub refresh_token: Option<String>,
+}
+
+/// One-time login ticket returned by `/auth/portal-ticket`
+#[derive(Debug, Deserialize)]
+struct PortalTicketResponse {
+    ticket: String,
+}
+
+/// Error body the API returns alongside 4xx responses
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: Option<String>,
+}
+
+/// Bumped on logout; authenticated calls started under an older generation are cancelled
+#[cfg(not(test))]
+fn session_generation() -> &'static AtomicU64 {
+    static GENERATION: AtomicU64 = AtomicU64::new(0);
+    &GENERATION
+}
+
+/// Per thread in unit tests, so one test's logout can't cancel another test's calls
+#[cfg(test)]
+fn session_generation() -> &'static AtomicU64 {
+    thread_local! {
+        static GENERATION: &'static AtomicU64 = Box::leak(Box::new(AtomicU64::new(0)));
+    }
+    GENERATION.with(|generation| *generation)
+}
+
+/// Wakes authenticated calls in flight when the generation changes
+#[cfg(not(test))]
+fn logout_notify() -> &'static tokio::sync::Notify {
+    static NOTIFY: OnceLock<tokio::sync::Notify> = OnceLock::new();
+    NOTIFY.get_or_init(tokio::sync::Notify::new)
+}
+
+/// Per thread in unit tests, like session_generation
+#[cfg(test)]
+fn logout_notify() -> &'static tokio::sync::Notify {
+    thread_local! {
+        static NOTIFY: &'static tokio::sync::Notify = Box::leak(Box::new(tokio::sync::Notify::new()));
+    }
+    NOTIFY.with(|notify| *notify)
+}
+
+/// Handle standing in for the session token: (handle, session generation, issued at)
+type TokenHandle = Option<(String, u64, Instant)>;
+
+#[cfg(not(test))]
+fn token_handle_slot() -> &'static Mutex<TokenHandle> {
+    static TOKEN_HANDLE: Mutex<TokenHandle> = Mutex::new(None);
+    &TOKEN_HANDLE
+}
+
+/// Per thread in unit tests, like session_generation
+#[cfg(test)]
+fn token_handle_slot() -> &'static Mutex<TokenHandle> {
+    thread_local! {
+        static TOKEN_HANDLE: &'static Mutex<TokenHandle> = Box::leak(Box::new(Mutex::new(None)));
+    }
+    TOKEN_HANDLE.with(|slot| *slot)
+}
+
+/// Whether the frontend gets an opaque handle instead of the raw session token
+/// (ADDIE_TOKEN_HANDLES=1); authenticated calls then go through the api_request command
+pub fn token_handles_enabled() -> bool {
+    std::env::var("ADDIE_TOKEN_HANDLES").is_ok_and(|v| v == "1")
+}
+
+/// The current token handle, issuing a new one if the last expired or the session changed
+pub fn token_handle() -> String {
+    let generation = session_generation().load(Ordering::SeqCst);
+    let mut current = token_handle_slot().lock().unwrap();
+    match current.as_ref() {
+        Some((handle, issued_for, issued_at))
+            if *issued_for == generation && issued_at.elapsed() < TOKEN_HANDLE_TTL =>
+        {
+            handle.clone()
+        }
+        _ => {
+            let handle = format!("addie_h_{}", uuid::Uuid::new_v4().simple());
+            *current = Some((handle.clone(), generation, Instant::now()));
+            handle
+        }
+    }
+}
+
+/// Whether a handle from the frontend is the live one for the current session
+pub fn is_valid_token_handle(handle: &str) -> bool {
+    let generation = session_generation().load(Ordering::SeqCst);
+    token_handle_slot()
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|(current, issued_for, issued_at)| {
+            current == handle && *issued_for == generation && issued_at.elapsed() < TOKEN_HANDLE_TTL
+        })
+}
+
+/// Bumped by every cancel_in_flight; work that started under an older value is stale
+pub(crate) fn current_generation() -> u64 {
+    session_generation().load(Ordering::SeqCst)
+}
+
+/// Cancel every authenticated call in flight, so none acts on or reports a cleared session
+pub fn cancel_in_flight() {
+    session_generation().fetch_add(1, Ordering::SeqCst);
+    *token_handle_slot().lock().unwrap() = None;
+    logout_notify().notify_waiters();
+}
+
+/// Run an authenticated call, abandoning it if cancel_in_flight runs first
+///
+/// A call that completes just after the cancel has its result discarded too.
+async fn cancellable<T>(call: impl Future<Output = Result<T, ApiError>>) -> Result<T, ApiError> {
+    // Created before reading the generation, so a cancel in between still wakes us
+    let cancelled = logout_notify().notified();
+    let generation = session_generation().load(Ordering::SeqCst);
+
+    let result = tokio::select! {
+        result = call => result,
+        _ = cancelled => return Err(ApiError::Cancelled),
+    };
+
+    if session_generation().load(Ordering::SeqCst) != generation {
+        return Err(ApiError::Cancelled);
+    }
+    result
+}
+
+/// Token and scheme sent in the Authorization header
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials<'a> {
+    pub token_type: &'a str,
+    pub token: &'a str,
+}
+
+impl<'a> Credentials<'a> {
+    /// A bare token with the default Bearer scheme
+    pub fn bearer(token: &'a str) -> Self {
+        Credentials {
+            token_type: crate::DEFAULT_TOKEN_TYPE,
+            token,
+        }
+    }
+
+    /// The stored session's token with the scheme the server issued it for
+    pub fn for_session(session: &'a UserSession) -> Self {
+        Credentials {
+            token_type: &session.token_type,
+            token: &session.sealed_session,
+        }
+    }
+}
+
+/// Attach credentials to a request
+fn authorize(
+    request: reqwest::RequestBuilder,
+    credentials: Credentials,
+) -> reqwest::RequestBuilder {
+    request.header(
+        reqwest::header::AUTHORIZATION,
+        format!("{} {}", credentials.token_type, credentials.token),
+    )
+}
+
+/// Shared HTTP client so connections are reused across calls
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("failed to build HTTP client")
+    })
+}
+
+/// Change the simulated network conditions; unavailable in release builds
+pub fn set_network_simulation(mode: NetworkSimulation) -> Result<(), String> {
+    #[cfg(debug_assertions)]
+    {
+        log::info!("Network simulation: {:?}", mode);
+        *network_simulation().lock().unwrap() = mode;
+        Ok(())
+    }
+
+    #[cfg(not(debug_assertions))]
+    {
+        let _ = mode;
+        Err("Network simulation is only available in debug builds".to_string())
+    }
+}
+
+/// Apply the simulated network conditions before a real request is sent
+async fn simulate_network() -> Result<(), ApiError> {
+    #[cfg(debug_assertions)]
+    {
+        let mode = *network_simulation().lock().unwrap();
+        match mode {
+            NetworkSimulation::Normal => {}
+            NetworkSimulation::Slow(delay_ms) => {
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            }
+            NetworkSimulation::Offline => return Err(ApiError::Offline),
+        }
+    }
+    Ok(())
+}
+
+/// Parse a Retry-After header value, given either as delay-seconds or an HTTP-date
+fn parse_retry_after(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(secs);
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    // A date in the past means "now"
+    Some(
+        date.duration_since(SystemTime::now())
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    )
+}
+
+/// How long to wait out a rate limit before the one retry: as asked, but capped
+fn rate_limit_wait(retry_after_secs: Option<u64>) -> Duration {
+    Duration::from_secs(retry_after_secs.unwrap_or(1)).min(MAX_RETRY_AFTER)
+}
+
+/// Start a request to the API, tagged with this install's support id
+fn new_request(method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+    client()
+        .request(method, url)
+        .header("X-Addie-Install-Id", install_id())
+}
+
+/// Map a non-2xx response from `path` to an ApiError
+async fn error_for_status(response: reqwest::Response, path: &str) -> ApiError {
+    match response.status().as_u16() {
+        429 => {
+            let retry_after_secs = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after);
+            ApiError::RateLimited { retry_after_secs }
+        }
+        401 => {
+            let code = response
+                .json::<ErrorBody>()
+                .await
+                .ok()
+                .and_then(|body| body.error);
+            ApiError::Unauthorized { code }
+        }
+        403 => ApiError::Forbidden {
+            body: response.json().await.ok(),
+        },
+        404 => ApiError::EndpointUnavailable {
+            path: path.split('?').next().unwrap_or(path).to_string(),
+        },
+        status => ApiError::Status {
+            status,
+            body: response.json().await.ok(),
+        },
+    }
+}
+
+/// GET an API path, waiting out one rate limit (capped) before giving up
+///
+/// Pass no credentials for endpoints that don't need them. Authenticated calls are
+/// cancelled by logout.
+pub async fn get<T: DeserializeOwned>(
+    path: &str,
+    credentials: Option<Credentials<'_>>,
+) -> Result<T, ApiError> {
+    match credentials {
+        Some(_) => cancellable(send_get(path, credentials)).await,
+        None => send_get(path, credentials).await,
+    }
+}
+
+async fn send_get<T: DeserializeOwned>(
+    path: &str,
+    credentials: Option<Credentials<'_>>,
+) -> Result<T, ApiError> {
+    let url = format!("{}{}", get_api_base_url(), path);
+
+    let mut waited_for_rate_limit = false;
+    loop {
+        let mut request = new_request(reqwest::Method::GET, &url);
+        if let Some(credentials) = credentials {
+            request = authorize(request, credentials);
+        }
+        simulate_network().await?;
+        let response = request.send().await?;
+
+        if response.status().is_success() {
+            return Ok(response.json::<T>().await?);
+        }
+
+        match error_for_status(response, path).await {
+            ApiError::RateLimited { retry_after_secs } if !waited_for_rate_limit => {
+                let wait = rate_limit_wait(retry_after_secs);
+                log::warn!("GET {} rate limited, retrying in {}s", path, wait.as_secs());
+                tokio::time::sleep(wait).await;
+                waited_for_rate_limit = true;
+            }
+            error => return Err(error),
+        }
+    }
+}
+
+/// Check the API host answers at all; any HTTP status counts as reachable
+pub async fn ping() -> Result<(), ApiError> {
+    simulate_network().await?;
+    new_request(reqwest::Method::HEAD, &get_api_base_url())
+        .timeout(PING_TIMEOUT)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Open a keep-alive connection to the API host so the first real call skips the TLS handshake
+///
+/// Sends no credentials and never fails; a cold first request is the worst case.
+pub async fn warm_connection() {
+    match ping().await {
+        Ok(()) => log::info!("API connection warmed"),
+        Err(e) => log::debug!("Skipped warming API connection: {}", e),
+    }
+}
+
+/// Fetch the user the given session token belongs to
+pub async fn get_me(credentials: Credentials<'_>) -> Result<MeResponse, ApiError> {
+    get("/auth/me", Some(credentials)).await
+}
+
+/// Login providers the server currently offers
+pub async fn get_login_providers() -> Result<Vec<LoginProviderInfo>, ApiError> {
+    let response: LoginProvidersResponse = get("/auth/providers", None).await?;
+    Ok(response.providers)
+}
+
+/// Signed internal route allowlist, verified by routes::refresh_from_server (unauthenticated)
+pub async fn get_route_list() -> Result<crate::routes::SignedRouteList, ApiError> {
+    get("/app/routes", None).await
+}
+
+/// Latest published app version for a platform and release channel
+pub async fn get_latest_version(
+    platform: &str,
+    channel: &str,
+    current_version: &str,
+) -> Result<LatestVersionResponse, ApiError> {
+    let path = format!(
+        "/app/version?platform={}&channel={}&version={}",
+        urlencoding::encode(platform),
+        urlencoding::encode(channel),
+        urlencoding::encode(current_version)
+    );
+    get(&path, None).await
+}
+
+/// Fresh key identifying one logical mutating operation
+pub fn new_idempotency_key() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Whether a caller-supplied idempotency key can be sent as a header as is
+pub fn is_valid_idempotency_key(key: &str) -> bool {
+    (1..=MAX_IDEMPOTENCY_KEY_LEN).contains(&key.len()) && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// Whether a request failed before any response, so a retry with the same key is safe
+///
+/// Includes a connection dropped mid-request: the server may have applied it, which is
+/// what the key is for.
+fn is_transport_failure(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.is_request()
+}
+
+/// POST to an API path with an `Idempotency-Key`, retrying transport failures with the same key
+///
+/// A caller that retries a logical operation itself should pass the key from its first
+/// attempt so the server can dedupe; otherwise a new key is generated for this call.
+/// Rate limits are returned as ApiError::RateLimited rather than retried, so the UI
+/// decides whether to repeat the operation. Authenticated posts are cancelled by logout.
+pub async fn post<T: DeserializeOwned>(
+    path: &str,
+    credentials: Option<Credentials<'_>>,
+    body: Option<&serde_json::Value>,
+    idempotency_key: Option<&str>,
+) -> Result<T, ApiError> {
+    match credentials {
+        Some(_) => cancellable(send_post(path, credentials, body, idempotency_key)).await,
+        None => send_post(path, credentials, body, idempotency_key).await,
+    }
+}
+
+async fn send_post<T: DeserializeOwned>(
+    path: &str,
+    credentials: Option<Credentials<'_>>,
+    body: Option<&serde_json::Value>,
+    idempotency_key: Option<&str>,
+) -> Result<T, ApiError> {
+    let key = idempotency_key
+        .map(str::to_string)
+        .unwrap_or_else(new_idempotency_key);
+    let url = format!("{}{}", get_api_base_url(), path);
+
+    let mut attempt = 1;
+    loop {
+        let mut request = new_request(reqwest::Method::POST, &url).header("Idempotency-Key", &key);
+        if let Some(credentials) = credentials {
+            request = authorize(request, credentials);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        simulate_network().await?;
+
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                // Endpoints with nothing to say answer 204; read that as JSON null
+                let bytes = response.bytes().await?;
+                let body: &[u8] = if bytes.is_empty() { b"null" } else { &bytes };
+                return Ok(serde_json::from_slice(body)?);
+            }
+            Ok(response) => return Err(error_for_status(response, path).await),
+            Err(e) if attempt < MAX_ATTEMPTS && is_transport_failure(&e) => {
+                log::warn!(
+                    "POST {} failed (attempt {}), retrying: {}",
+                    path,
+                    attempt,
+                    e
+                );
+                tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Authenticated call to any API path, returning the status and JSON body
+///
+/// Backs the api_request command; the fixed endpoints in this module use get/post.
+/// Never retried here, since the method may not be idempotent. Anything but a GET carries
+/// an `Idempotency-Key`, `idempotency_key` if given or a new one, so a caller repeating
+/// the operation with the same key can't have it applied twice.
+pub async fn request(
+    method: reqwest::Method,
+    path: &str,
+    credentials: Credentials<'_>,
+    body: Option<&serde_json::Value>,
+    idempotency_key: Option<&str>,
+) -> Result<ApiResponse, ApiError> {
+    cancellable(send_request(
+        method,
+        path,
+        credentials,
+        body,
+        idempotency_key,
+    ))
+    .await
+}
+
+async fn send_request(
+    method: reqwest::Method,
+    path: &str,
+    credentials: Credentials<'_>,
+    body: Option<&serde_json::Value>,
+    idempotency_key: Option<&str>,
+) -> Result<ApiResponse, ApiError> {
+    let url = format!("{}{}", get_api_base_url(), path);
+    let mutating = method != reqwest::Method::GET;
+    let mut request = authorize(new_request(method, &url), credentials);
+    if mutating {
+        let key = idempotency_key
+            .map(str::to_string)
+            .unwrap_or_else(new_idempotency_key);
+        request = request.header("Idempotency-Key", key);
+    }
+    if let Some(body) = body {
+        request = request.json(body);
+    }
+    simulate_network().await?;
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(error_for_status(response, path).await);
+    }
+    let status = response.status().as_u16();
+    let bytes = response.bytes().await?;
+    let body = if bytes.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_slice(&bytes)?
+    };
+    Ok(ApiResponse { status, body })
+}
+
+/// Exchange a refresh token for a new sealed session
+pub async fn refresh_session(
+    credentials: Credentials<'_>,
+    refresh_token: &str,
+) -> Result<RefreshResponse, ApiError> {
+    let body = serde_json::json!({ "refresh_token": refresh_token });
+    post("/auth/refresh", Some(credentials), Some(&body), None).await
+}
+
+/// Redeem a signed account batch for one session per account (unauthenticated)
+pub async fn redeem_batch(token: &str) -> Result<RedeemBatchResponse, ApiError> {
+    let body = serde_json::json!({ "token": token });
+    post("/auth/redeem-batch", None, Some(&body), None).await
+}
+
+/// Exchange a PKCE authorization code and its verifier for a session (unauthenticated)
+pub async fn exchange_code(code: &str, code_verifier: &str) -> Result<TokenResponse, ApiError> {
+    let body = serde_json::json!({ "code": code, "code_verifier": code_verifier });
+    post("/auth/token", None, Some(&body), None).await
+}
+
+/// Revoke the session server-side so the sealed session can't be replayed
+///
+/// One attempt with a short timeout: logout has already cleared the session locally and
+/// mustn't keep the user waiting on a dead network. Not cancelled by logout for the same
+/// reason.
+pub async fn revoke_session(credentials: Credentials<'_>) -> Result<(), ApiError> {
+    let url = format!("{}/auth/logout", get_api_base_url());
+    let request = authorize(new_request(reqwest::Method::POST, &url), credentials)
+        .header("Idempotency-Key", new_idempotency_key())
+        .timeout(REVOKE_TIMEOUT);
+    simulate_network().await?;
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(error_for_status(response, "/auth/logout").await);
+    }
+    Ok(())
+}
+
+/// Exchange the sealed session for a short-lived, one-time web login ticket
+pub async fn create_portal_ticket(credentials: Credentials<'_>) -> Result<String, ApiError> {
+    let response: PortalTicketResponse =
+        post("/auth/portal-ticket", Some(credentials), None, None).await?;
+    Ok(response.ticket)
+}
+
+/// Local HTTP server standing in for the API in unit tests
+#[cfg(test)]
+pub(crate) mod mock_api {
+    use std::cell::RefCell;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    thread_local! {
+        static BASE_URL: RefCell<Option<String>> = const { RefCell::new(None) };
+    }
+
+    /// A request the server received
+    #[derive(Debug)]
+    pub(crate) struct Received {
+        pub method: String,
+        pub path: String,
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+        /// Which connection it came in on, counting from 0 (always 0 unless kept alive)
+        pub connection: usize,
+    }
+
+    impl Received {
+        pub(crate) fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// Serves this thread's API calls until dropped
+    pub(crate) struct MockApi {
+        received: mpsc::Receiver<Received>,
+    }
+
+    impl MockApi {
+        /// Every request received so far, in order
+        pub(crate) fn received(&self) -> Vec<Received> {
+            self.received.try_iter().collect()
+        }
+    }
+
+    impl Drop for MockApi {
+        fn drop(&mut self) {
+            BASE_URL.with(|url| *url.borrow_mut() = None);
+        }
+    }
+
+    /// Answer one connection per reply, in order; None drops the connection unanswered
+    ///
+    /// The thread's API base URL points at the server until the returned guard is dropped.
+    pub(crate) fn serve(replies: Vec<Option<(u16, &'static str)>>) -> MockApi {
+        let connections = replies.len();
+        let mut replies = replies.into_iter();
+        serve_with(connections, move |_| {
+            replies
+                .next()
+                .flatten()
+                .map(|(status, body)| (status, body.to_string()))
+        })
+    }
+
+    /// Answer `connections` connections with whatever `reply` makes of each request
+    ///
+    /// For calls made concurrently, whose connections arrive in no particular order.
+    pub(crate) fn serve_with(
+        connections: usize,
+        mut reply: impl FnMut(&Received) -> Option<(u16, String)> + Send + 'static,
+    ) -> MockApi {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for _ in 0..connections {
+                let Ok((stream, _)) = listener.accept() else {
+                    return;
+                };
+                let mut reader = BufReader::new(stream);
+                let request = read_request(&mut reader);
+                let reply = reply(&request);
+                let _ = sender.send(request);
+                if let Some((status, body)) = reply {
+                    let response = format!(
+                        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = reader.get_mut().write_all(response.as_bytes());
+                }
+            }
+        });
+
+        BASE_URL.with(|base| *base.borrow_mut() = Some(url));
+        MockApi { received }
+    }
+
+    /// Give out `replies` in order, keeping each connection open for further requests
+    ///
+    /// For checking that calls reuse a connection; see Received::connection.
+    pub(crate) fn serve_keep_alive(replies: Vec<(u16, &'static str)>) -> MockApi {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::channel();
+        let connections = replies.len();
+        let replies = Arc::new(Mutex::new(replies.into_iter()));
+
+        std::thread::spawn(move || {
+            for connection in 0..connections {
+                let Ok((stream, _)) = listener.accept() else {
+                    return;
+                };
+                let (sender, replies) = (sender.clone(), replies.clone());
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream);
+                    loop {
+                        let mut request = read_request(&mut reader);
+                        // Closed by the client
+                        if request.method.is_empty() {
+                            return;
+                        }
+                        let Some((status, body)) = replies.lock().unwrap().next() else {
+                            return;
+                        };
+                        request.connection = connection;
+                        let body = if request.method == "HEAD" { "" } else { body };
+                        let _ = sender.send(request);
+                        let response = format!(
+                            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            status,
+                            body.len(),
+                            body
+                        );
+                        if reader.get_mut().write_all(response.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        BASE_URL.with(|base| *base.borrow_mut() = Some(url));
+        MockApi { received }
+    }
+
+    fn read_request(reader: &mut impl BufRead) -> Received {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                headers.push((key.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        let mut request = Received {
+            method,
+            path,
+            headers,
+            body: String::new(),
+            connection: 0,
+        };
+        let len = request
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .unwrap_or(0);
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        request.body = String::from_utf8_lossy(&body).into_owned();
+        request
+    }
+
+    /// The mock server's URL, when one is serving this thread
+    pub(crate) fn base_url() -> Option<String> {
+        BASE_URL.with(|url| url.borrow().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(120));
+        assert_eq!(parse_retry_after(" 0 "), Some(0));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn parses_retry_after_http_date() {
+        let in_a_minute = SystemTime::now() + Duration::from_secs(60);
+        let secs = parse_retry_after(&httpdate::fmt_http_date(in_a_minute)).unwrap();
+        // The date only has whole seconds
+        assert!((58..=60).contains(&secs), "got {}", secs);
+
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT"), Some(0));
+    }
+
+    #[test]
+    fn rate_limit_wait_is_honoured_and_capped() {
+        assert_eq!(rate_limit_wait(Some(5)), Duration::from_secs(5));
+        assert_eq!(rate_limit_wait(None), Duration::from_secs(1));
+        assert_eq!(rate_limit_wait(Some(3600)), MAX_RETRY_AFTER);
+    }
+
+    #[test]
+    fn validates_caller_idempotency_keys() {
+        assert!(is_valid_idempotency_key(&new_idempotency_key()));
+        assert!(is_valid_idempotency_key("save-campaign-42"));
+        for key in ["", "has space", "line\nbreak", "caf\u{e9}"] {
+            assert!(!is_valid_idempotency_key(key), "{:?}", key);
+        }
+        assert!(!is_valid_idempotency_key(
+            &"k".repeat(MAX_IDEMPOTENCY_KEY_LEN + 1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn retried_post_reuses_its_idempotency_key() {
+        let api = mock_api::serve(vec![None, Some((200, "{}"))]);
+
+        let _: serde_json::Value = post("/campaigns", None, None, None).await.unwrap();
+        let received = api.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(
+            (received[1].method.as_str(), received[1].path.as_str()),
+            ("POST", "/campaigns")
+        );
+        let key = received[0].header("Idempotency-Key").unwrap();
+        assert!(uuid::Uuid::parse_str(key).is_ok(), "{}", key);
+        assert_eq!(received[1].header("Idempotency-Key"), Some(key));
+    }
+
+    #[tokio::test]
+    async fn each_post_gets_a_new_idempotency_key_unless_given_one() {
+        let api = mock_api::serve(vec![Some((200, "{}")); 3]);
+
+        for key in [None, None, Some("save-campaign-42")] {
+            let _: serde_json::Value = post("/campaigns", None, None, key).await.unwrap();
+        }
+        let keys: Vec<_> = api
+            .received()
+            .iter()
+            .map(|request| request.header("Idempotency-Key").unwrap().to_string())
+            .collect();
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(keys[2], "save-campaign-42");
+    }
+
+    #[tokio::test]
+    async fn offline_simulation_fails_calls_without_sending_them() {
+        let api = mock_api::serve(vec![Some((200, "{}"))]);
+        set_network_simulation(NetworkSimulation::Offline).unwrap();
+
+        let err = get::<serde_json::Value>("/me", None).await.unwrap_err();
+        assert!(matches!(err, ApiError::Offline));
+        let err = post::<serde_json::Value>("/campaigns", None, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ApiError::Offline));
+        assert!(api.received().is_empty());
+
+        set_network_simulation(NetworkSimulation::Normal).unwrap();
+        let _: serde_json::Value = get("/me", None).await.unwrap();
+        assert_eq!(api.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn slow_simulation_delays_calls() {
+        let _api = mock_api::serve(vec![Some((200, "{}"))]);
+        set_network_simulation(NetworkSimulation::Slow(200)).unwrap();
+
+        let started = std::time::Instant::now();
+        let _: serde_json::Value = get("/me", None).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        set_network_simulation(NetworkSimulation::Normal).unwrap();
+    }
+
+    #[tokio::test]
+    async fn warming_leaves_a_connection_for_the_next_call() {
+        let api = mock_api::serve_keep_alive(vec![(200, ""), (200, "{}")]);
+
+        warm_connection().await;
+        let _: serde_json::Value = get("/auth/providers", None).await.unwrap();
+
+        let received = api.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].method, "HEAD");
+        assert_eq!(received[0].header("Authorization"), None);
+        assert_eq!(received[1].path, "/auth/providers");
+        assert_eq!(received[1].connection, received[0].connection);
+    }
+
+    #[tokio::test]
+    async fn warming_fails_silently() {
+        let api = mock_api::serve(vec![None]);
+        warm_connection().await;
+        assert_eq!(api.received().len(), 1);
+
+        set_network_simulation(NetworkSimulation::Offline).unwrap();
+        warm_connection().await;
+        set_network_simulation(NetworkSimulation::Normal).unwrap();
+        assert!(api.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn result_arriving_after_a_cancel_is_discarded() {
+        let result = cancellable(async {
+            cancel_in_flight();
+            Ok("stale")
+        })
+        .await;
+        assert!(matches!(result, Err(ApiError::Cancelled)));
+
+        // Calls started after the cancel go through
+        assert_eq!(cancellable(async { Ok("fresh") }).await.unwrap(), "fresh");
+    }
+}
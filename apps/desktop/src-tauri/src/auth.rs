@@ -2,156 +2,6947 @@
 //!
 //! Handles OAuth flow with WorkOS via deep links and secure session storage.
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
 use keyring::Entry;
-use tauri::{AppHandle, Emitter, Manager};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
 use tauri_plugin_opener::OpenerExt;
 
-use crate::UserSession;
+use crate::api::{self, ApiError, Credentials, LoginProviderInfo};
+use crate::quarantine;
+use crate::redact;
+use crate::routes::is_allowed_route;
+use crate::session_timer;
+use crate::telemetry::{self, Metric};
+use crate::{
+    AccountHealth, AccountStatus, ApiRequestError, ApiResponse, AuthErrorReport, AuthState,
+    AuthStatus, BuildInfo, DeepLinkSelfTest, LoginOptions, LoginPhase, LoginPreflight,
+    LogoutOutcome, LogoutReason, PreflightCheck, SessionVerification, StorageHealth,
+    StorageOutcome, UserInfo, UserSession,
+};
 
 const KEYRING_SERVICE: &str = "org.agenticadvertising.addie";
 const KEYRING_USER: &str = "session";
 
-/// Get API base URL
-fn get_api_base_url() -> String {
-    std::env::var("ADDIE_API_URL")
-        .unwrap_or_else(|_| "https://agenticadvertising.org".to_string())
-}
+/// Upper bound on the raw deep link length we are willing to parse
+pub(crate) const MAX_CALLBACK_URL_LEN: usize = 16 * 1024;
 
-/// Start OAuth flow by opening browser to login page
-pub fn start_oauth_flow(app: &AppHandle) -> Result<(), Box<dyn std::error::Error>> {
-    let api_base = get_api_base_url();
-    // The login URL will redirect to WorkOS, which will callback with sealed session in deep link
-    let login_url = format!(
-        "{}/auth/login?native=true&redirect_uri={}",
-        api_base,
-        urlencoding::encode("addie://auth/callback")
-    );
+/// Callback params that must appear at most once; a second copy could be an injected value
+const SINGLE_VALUE_PARAMS: &[&str] = &[
+    "sealed_session",
+    "state",
+    "code",
+    "user_id",
+    "email",
+    "token_type",
+    "expires_at",
+    "refresh_token",
+];
 
-    // Open in system browser using OpenerExt trait
-    app.opener().open_url(&login_url, None::<&str>)?;
+/// Longest sealed session we'll store; well above real tokens, well below keychain limits
+const MAX_SEALED_SESSION_LEN: usize = 8 * 1024;
 
-    Ok(())
+/// Longest refresh token we'll store
+const MAX_REFRESH_TOKEN_LEN: usize = 4 * 1024;
+
+/// Longest PKCE authorization code we'll send back to the server
+const MAX_AUTH_CODE_LEN: usize = 2 * 1024;
+
+/// Refresh a session this close (in seconds) to expiry before handing its token out
+pub(crate) const REFRESH_MARGIN_SECS: u64 = 60;
+
+/// Upper bound on the number of query pairs in an auth callback
+const MAX_CALLBACK_PARAMS: usize = 32;
+
+/// Domains (and their subdomains) that server-supplied links may point to
+pub(crate) const TRUSTED_LINK_DOMAINS: &[&str] =
+    &["agenticadvertising.org", "adcontextprotocol.org"];
+
+/// Longest display name (first or last) we keep, in characters
+const MAX_NAME_CHARS: usize = 128;
+
+/// How many started-but-unfinished logins we remember
+const MAX_PENDING_LOGINS: usize = 5;
+
+/// How long the server's login provider list is reused before asking again
+const LOGIN_PROVIDERS_TTL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// How long a started login stays pending (retryable, and restorable after a crash)
+const PENDING_LOGIN_TTL_SECS: u64 = 10 * 60;
+
+/// Where pending logins are kept so a callback arriving after a crash still matches
+const PENDING_LOGINS_FILE: &str = ".addie-pending-logins.json";
+
+/// Everything older versions kept directly in $HOME, moved by init_data_dir
+const LEGACY_DATA_FILES: &[&str] = &[
+    ".addie-session.json",
+    PENDING_LOGINS_FILE,
+    ".addie-preferences.json",
+    ".addie-install-id",
+    ".addie-device-token",
+    ".addie-login-locale",
+    ".addie-telemetry",
+    ".addie-quarantine",
+];
+
+/// A login started from this app, identified by the opaque state sent to the server
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct PendingLogin {
+    state: String,
+    return_to: Option<String>,
+    /// Label of the window the login was started from
+    window_label: Option<String>,
+    /// Authorize URL opened for this login, kept so a failed browser launch can be retried
+    login_url: String,
+    /// Unix time (seconds) the login was started
+    started_at: u64,
+    /// PKCE code verifier for this login; None for logins started before PKCE
+    #[serde(default)]
+    code_verifier: Option<String>,
 }
 
-/// Handle deep link callback from OAuth flow
-/// URL format: addie://auth/callback?sealed_session=xxx&user_id=xxx&email=xxx&first_name=xxx&last_name=xxx
-pub fn handle_deep_link(app: &AppHandle, url: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let parsed = url::Url::parse(url)?;
+/// Logins awaiting their callback, oldest first (held in Tauri managed state)
+#[derive(Default)]
+pub struct PendingLogins(Mutex<Vec<PendingLogin>>);
 
-    // Check if this is an auth callback
-    if parsed.scheme() != "addie" || parsed.host_str() != Some("auth") {
-        return Ok(()); // Not an auth URL, ignore
+impl PendingLogins {
+    /// Logins a previous run started that haven't timed out, so their callbacks still verify
+    pub fn restore() -> Self {
+        if is_headless() {
+            return Self::default();
+        }
+
+        let mut pending: Vec<PendingLogin> = data_file_path(PENDING_LOGINS_FILE)
+            .and_then(std::fs::read_to_string)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+
+        let now = now_secs();
+        pending.retain(|login| now.saturating_sub(login.started_at) < PENDING_LOGIN_TTL_SECS);
+        if !pending.is_empty() {
+            log::info!(
+                "Restored {} pending login(s) from a previous run",
+                pending.len()
+            );
+        }
+        persist_pending_logins(&pending);
+
+        PendingLogins(Mutex::new(pending))
     }
+}
 
-    if parsed.path() != "/callback" {
-        return Ok(());
+/// Mirror pending logins to disk, deleting the file once there are none (best effort)
+///
+/// Holds only state nonces, return routes and login URLs, never a session.
+fn persist_pending_logins(pending: &[PendingLogin]) {
+    if is_headless() {
+        return;
     }
 
-    // Extract session data from query params (server sends sealed session directly)
-    let params: std::collections::HashMap<_, _> = parsed.query_pairs().collect();
+    let Ok(path) = data_file_path(PENDING_LOGINS_FILE) else {
+        return;
+    };
+    let result = if pending.is_empty() {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    } else {
+        // Holds PKCE verifiers, so it's as private as the session file
+        serde_json::to_string(pending)
+            .map_err(std::io::Error::other)
+            .and_then(|json| write_private_file(&path, json.as_bytes()))
+    };
 
-    let sealed_session = params
-        .get("sealed_session")
-        .map(|v| v.to_string())
-        .ok_or("Missing sealed_session")?;
+    if let Err(e) = result {
+        log::error!("Failed to persist pending logins: {}", e);
+    }
+}
 
-    let user_id = params
-        .get("user_id")
-        .map(|v| v.to_string())
-        .ok_or("Missing user_id")?;
+/// Most recent login failure, kept for UI that mounts after the auth-error event (managed state)
+#[derive(Default)]
+pub struct LastAuthError(Mutex<Option<AuthErrorReport>>);
 
-    let email = params
-        .get("email")
-        .map(|v| v.to_string())
-        .ok_or("Missing email")?;
+/// Why an auth operation failed, sent to the frontend as `{ code, message }`
+///
+/// `code` is stable for the UI to branch on; the message is for humans and may change.
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Missing {0}")]
+    MissingParam(&'static str),
+    #[error("Invalid {0}")]
+    InvalidParam(&'static str),
+    #[error("Duplicate callback param: {0}")]
+    DuplicateParam(String),
+    #[error("Callback too large")]
+    CallbackTooLarge,
+    #[error("Session token too large")]
+    TokenTooLarge,
+    #[error("Invalid callback URL: {0}")]
+    InvalidCallbackUrl(#[from] url::ParseError),
+    #[error("Login callback is missing its state parameter")]
+    MissingState,
+    #[error("Login callback doesn't match a login started here (or the login expired)")]
+    StateMismatch,
+    #[error("return_to is not an internal route: {0}")]
+    InvalidReturnTo(String),
+    #[error("{0}")]
+    InvalidLoginOption(String),
+    #[error("Session expired, please log in again")]
+    Expired,
+    #[error("This session can't be refreshed, please log in again")]
+    NoRefreshToken,
+    #[error("No stored account with id {0}")]
+    UnknownAccount(String),
+    #[error("Account batch rejected: {0}")]
+    InvalidBatch(String),
+    #[error("This login was started without PKCE, please log in again")]
+    PkceUnavailable,
+    #[error("Server sent the session directly, but PKCE code exchange is required")]
+    PkceRequired,
+    #[error("Session storage failed: {0}")]
+    StorageFailed(String),
+    #[error("Could not open the browser: {0}")]
+    BrowserFailed(String),
+    #[error(transparent)]
+    Network(#[from] ApiError),
+}
 
-    let first_name = params.get("first_name").map(|v| v.to_string());
-    let last_name = params.get("last_name").map(|v| v.to_string());
+impl AuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::MissingParam(_) => "missing_param",
+            AuthError::InvalidParam(_) => "invalid_param",
+            AuthError::DuplicateParam(_) => "duplicate_param",
+            AuthError::CallbackTooLarge => "callback_too_large",
+            AuthError::TokenTooLarge => "token_too_large",
+            AuthError::InvalidCallbackUrl(_) => "invalid_callback_url",
+            AuthError::MissingState => "missing_state",
+            AuthError::StateMismatch => "state_mismatch",
+            AuthError::InvalidReturnTo(_) => "invalid_return_to",
+            AuthError::InvalidLoginOption(_) => "invalid_login_option",
+            AuthError::Expired => "expired",
+            AuthError::NoRefreshToken => "no_refresh_token",
+            AuthError::UnknownAccount(_) => "unknown_account",
+            AuthError::InvalidBatch(_) => "invalid_batch",
+            AuthError::PkceUnavailable => "pkce_unavailable",
+            AuthError::PkceRequired => "pkce_required",
+            AuthError::StorageFailed(_) => "storage_failed",
+            AuthError::BrowserFailed(_) => "browser_failed",
+            AuthError::Network(_) => "network",
+        }
+    }
 
-    // Create session from params
-    let session = UserSession {
-        sealed_session,
-        user_id: user_id.clone(),
-        email: email.clone(),
-        first_name: first_name.clone(),
-        last_name: last_name.clone(),
-    };
+    /// For the session store's (and other local I/O's) untyped errors
+    pub(crate) fn storage(e: Box<dyn std::error::Error>) -> Self {
+        AuthError::StorageFailed(e.to_string())
+    }
+}
 
-    // Store session securely
-    if let Err(e) = save_session(&session) {
-        eprintln!("Failed to save session: {}", e);
-        let _ = app.emit("auth-error", format!("Failed to save session: {}", e));
-        return Err(format!("Failed to save session: {}", e).into());
+impl serde::Serialize for AuthError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut error = serializer.serialize_struct("AuthError", 2)?;
+        error.serialize_field("code", self.code())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
     }
+}
 
-    println!("Auth callback received for user: {}", email);
+/// Current login phase (managed state); see transition
+pub struct AuthPhase(Mutex<LoginPhase>);
 
-    // Notify frontend of successful login
-    let _ = app.emit("auth-success", serde_json::json!({
-        "user": {
-            "id": user_id,
-            "email": email,
-            "first_name": first_name,
-            "last_name": last_name,
+impl AuthPhase {
+    /// Authenticated if a previous run left a session behind, else signed out
+    pub fn restore() -> Self {
+        let phase = match get_session() {
+            Ok(Some(_)) => LoginPhase::Authenticated,
+            _ => LoginPhase::SignedOut,
+        };
+        AuthPhase(Mutex::new(phase))
+    }
+
+    /// Move to `next` if that's a legal edge, else leave the phase as it was
+    fn advance(&self, next: LoginPhase) -> bool {
+        let mut phase = self.0.lock().unwrap();
+        if !phase.can_transition_to(next) {
+            log::warn!(
+                "Illegal auth transition {:?} -> {:?}, ignored",
+                *phase,
+                next
+            );
+            return false;
         }
-    }));
+        *phase = next;
+        true
+    }
+}
 
-    println!("auth-success event emitted");
+/// Sessions held in memory instead of on disk (headless mode)
+#[cfg(not(test))]
+static MEMORY_STORE: Mutex<Option<SessionStore>> = Mutex::new(None);
 
-    // Bring window to foreground (desktop only)
-    #[cfg(desktop)]
+// Per thread in unit tests, like the fake store
+#[cfg(test)]
+thread_local! {
+    static MEMORY_STORE: Mutex<Option<SessionStore>> = const { Mutex::new(None) };
+}
+
+fn with_memory_store<T>(f: impl FnOnce(&mut Option<SessionStore>) -> T) -> T {
+    #[cfg(not(test))]
     {
-        if let Some(window) = app.get_webview_window("main") {
-            let _ = window.set_focus();
-            let _ = window.show();
-            println!("Window focused");
+        f(&mut MEMORY_STORE.lock().unwrap())
+    }
+    #[cfg(test)]
+    MEMORY_STORE.with(|store| f(&mut store.lock().unwrap()))
+}
+
+/// Serializes every read and write of the session store
+///
+/// Cold-start and running deep links can arrive together on launch, and each save is a
+/// read-modify-write of the whole store. Taken by the public entry points only; the
+/// helpers under them (read_store, save_store, clear_*_storage) expect it to be held.
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+fn lock_store() -> std::sync::MutexGuard<'static, ()> {
+    // Writes go through a temp file and rename, so a panic mid-write left nothing half-done
+    STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Everything in the session store: each signed-in account and which one is active
+///
+/// Kept as one blob in the keychain entry (or session file), so switching accounts is a
+/// single write. Commands act on the active account only.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+struct SessionStore {
+    active: Option<String>,
+    accounts: std::collections::BTreeMap<String, UserSession>,
+    /// Picker labels by user id; kept apart from the sessions so a new login keeps them
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    labels: std::collections::BTreeMap<String, AccountLabel>,
+}
+
+/// How an account is shown in the account picker
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct AccountLabel {
+    label: Option<String>,
+    color: Option<String>,
+}
+
+impl SessionStore {
+    fn active_session(&self) -> Option<UserSession> {
+        self.accounts.get(self.active.as_ref()?).cloned()
+    }
+
+    /// Add or update an account and make it the active one
+    fn upsert_active(&mut self, session: UserSession) {
+        self.active = Some(session.user_id.clone());
+        self.accounts.insert(session.user_id.clone(), session);
+    }
+
+    /// An account stored under another user id for the same person as `session`
+    ///
+    /// The server can reissue a user id for an email; emails are compared case-insensitively.
+    fn duplicate_of(&self, session: &UserSession) -> Option<&UserSession> {
+        let email = normalize_email(&session.email);
+        self.accounts.values().find(|stored| {
+            stored.user_id != session.user_id && normalize_email(&stored.email) == email
+        })
+    }
+
+    /// Parse a stored blob, wrapping the single-session layout older versions wrote
+    fn parse(bytes: &[u8]) -> serde_json::Result<Self> {
+        let value: serde_json::Value = serde_json::from_slice(bytes)?;
+        if value.get("accounts").is_some() {
+            return serde_json::from_value(value);
+        }
+        let mut store = SessionStore::default();
+        store.upsert_active(serde_json::from_value(value)?);
+        Ok(store)
+    }
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Two stored accounts for one person folded into one, reported as `account-merged`
+#[derive(Debug, Clone)]
+struct AccountMerge {
+    /// Whichever session expires later (no expiry counts as latest); the new one on a tie
+    kept: UserSession,
+    removed_user_id: String,
+}
+
+impl AccountMerge {
+    fn between(existing: &UserSession, new: &UserSession) -> Self {
+        let expiry = |session: &UserSession| session.expires_at.unwrap_or(u64::MAX);
+        let (kept, removed) = if expiry(existing) > expiry(new) {
+            (existing, new)
         } else {
-            eprintln!("Could not find main window to focus");
+            (new, existing)
+        };
+        AccountMerge {
+            kept: kept.clone(),
+            removed_user_id: removed.user_id.clone(),
         }
     }
+}
 
-    Ok(())
+/// Payload of the account-merged event
+#[derive(Clone, serde::Serialize)]
+struct AccountMergedPayload<'a> {
+    user_id: &'a str,
+    removed_user_id: &'a str,
 }
 
-/// Get session file path
-fn get_session_file_path() -> std::path::PathBuf {
-    let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-    std::path::PathBuf::from(home).join(".addie-session.json")
+/// Stand-in for the keychain and session file in tests, injected per thread
+///
+/// read_store and write_store go here instead while a FakeStore is injected, so the
+/// logic above storage (verification, account handling, auth state) can be exercised
+/// without a keychain or data directory.
+#[cfg(test)]
+pub(crate) mod fake_store {
+    use std::cell::RefCell;
+    use std::sync::{Arc, Mutex};
+
+    use super::SessionStore;
+    use crate::UserSession;
+
+    #[derive(Default)]
+    pub(crate) struct FakeStore {
+        store: SessionStore,
+        fail_reads: bool,
+        drop_writes: bool,
+        fail_read_after_write: bool,
+        fail_next_read: bool,
+    }
+
+    impl FakeStore {
+        /// A store whose reads fail, as they do with a locked keychain
+        pub(crate) fn failing_reads() -> Self {
+            FakeStore {
+                fail_reads: true,
+                ..Default::default()
+            }
+        }
+
+        /// A store whose writes report success but are not kept
+        pub(crate) fn dropping_writes() -> Self {
+            FakeStore {
+                drop_writes: true,
+                ..Default::default()
+            }
+        }
+
+        /// A store whose first write is kept but makes the next read fail, as a keychain
+        /// locking mid-login would
+        pub(crate) fn unreadable_after_write() -> Self {
+            FakeStore {
+                fail_read_after_write: true,
+                ..Default::default()
+            }
+        }
+    }
+
+    thread_local! {
+        static INJECTED: RefCell<Option<Shared>> = const { RefCell::new(None) };
+    }
+
+    /// An injected store, which other threads can be given too
+    #[derive(Clone)]
+    pub(crate) struct Shared(Arc<Mutex<FakeStore>>);
+
+    impl Shared {
+        /// Use this store as the current thread's too, until the guard is dropped
+        pub(crate) fn inject(&self) -> Injected {
+            INJECTED.with(|injected| *injected.borrow_mut() = Some(self.clone()));
+            Injected
+        }
+    }
+
+    /// Removes the injected store when dropped, so it can't leak into the next test
+    pub(crate) struct Injected;
+
+    impl Drop for Injected {
+        fn drop(&mut self) {
+            INJECTED.with(|injected| *injected.borrow_mut() = None);
+        }
+    }
+
+    /// Use `fake` as this thread's session store until the guard is dropped
+    pub(crate) fn inject(fake: FakeStore) -> Injected {
+        Shared(Arc::new(Mutex::new(fake))).inject()
+    }
+
+    /// This thread's injected store, for sharing with other threads
+    pub(crate) fn shared() -> Shared {
+        INJECTED.with(|injected| injected.borrow().clone().expect("a FakeStore is injected"))
+    }
+
+    fn with_injected<T>(f: impl FnOnce(&mut FakeStore) -> T) -> Option<T> {
+        let shared = INJECTED.with(|injected| injected.borrow().clone())?;
+        let mut fake = shared.0.lock().unwrap();
+        Some(f(&mut fake))
+    }
+
+    /// Replace the stored accounts, as another process would
+    pub(crate) fn set_accounts(active: Option<&UserSession>, others: &[UserSession]) {
+        with_injected(|fake| {
+            fake.store = SessionStore::default();
+            for session in others {
+                fake.store
+                    .accounts
+                    .insert(session.user_id.clone(), session.clone());
+            }
+            if let Some(session) = active {
+                fake.store.upsert_active(session.clone());
+            }
+        })
+        .expect("a FakeStore is injected");
+    }
+
+    pub(super) fn read() -> Option<Result<SessionStore, Box<dyn std::error::Error>>> {
+        with_injected(|fake| {
+            if fake.fail_reads || std::mem::take(&mut fake.fail_next_read) {
+                Err("keychain is locked".into())
+            } else {
+                Ok(fake.store.clone())
+            }
+        })
+    }
+
+    pub(super) fn write(store: &SessionStore) -> Option<Result<(), Box<dyn std::error::Error>>> {
+        with_injected(|fake| {
+            if !fake.drop_writes {
+                fake.store = store.clone();
+            }
+            fake.fail_next_read = std::mem::take(&mut fake.fail_read_after_write);
+            Ok(())
+        })
+    }
+
+    /// Empty the store, as clearing the keychain and session file would
+    pub(super) fn clear() -> Option<()> {
+        with_injected(|fake| fake.store = SessionStore::default())
+    }
 }
 
-/// Save session to file (keychain unreliable for unsigned debug builds)
-pub fn save_session(session: &UserSession) -> Result<(), Box<dyn std::error::Error>> {
-    let json = serde_json::to_string(session)?;
-    let path = get_session_file_path();
-    std::fs::write(&path, &json)?;
-    println!("Session saved to file: {:?}", path);
-    Ok(())
+/// Scripted keychain reads for unit tests, beneath the session store rather than replacing it
+#[cfg(test)]
+pub(crate) mod fake_keychain {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    thread_local! {
+        static SCRIPT: RefCell<Option<VecDeque<keyring::Result<String>>>> =
+            const { RefCell::new(None) };
+    }
+
+    /// Stops answering keychain reads from the script when dropped
+    pub(crate) struct Scripted;
+
+    impl Drop for Scripted {
+        fn drop(&mut self) {
+            SCRIPT.with(|script| *script.borrow_mut() = None);
+        }
+    }
+
+    /// Answer this thread's keychain reads with `results` in order, then with NoEntry
+    pub(crate) fn script(results: Vec<keyring::Result<String>>) -> Scripted {
+        SCRIPT.with(|script| *script.borrow_mut() = Some(results.into()));
+        Scripted
+    }
+
+    /// Scripted results not read yet
+    pub(crate) fn remaining() -> usize {
+        SCRIPT.with(|script| script.borrow().as_ref().map_or(0, VecDeque::len))
+    }
+
+    pub(super) fn get_password() -> Option<keyring::Result<String>> {
+        SCRIPT.with(|script| {
+            script
+                .borrow_mut()
+                .as_mut()
+                .map(|results| results.pop_front().unwrap_or(Err(keyring::Error::NoEntry)))
+        })
+    }
 }
 
-/// Get session from file
-pub fn get_session() -> Result<Option<UserSession>, Box<dyn std::error::Error>> {
-    let path = get_session_file_path();
-    if path.exists() {
-        let json = std::fs::read_to_string(&path)?;
-        let session: UserSession = serde_json::from_str(&json)?;
-        println!("Session loaded from file");
-        return Ok(Some(session));
+/// Throwaway data directories for unit tests, standing in for the app-data dir
+#[cfg(test)]
+pub(crate) mod temp_data_dir {
+    use std::cell::RefCell;
+    use std::path::{Path, PathBuf};
+
+    thread_local! {
+        static DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    }
+
+    /// This thread's data directory until dropped, when it's deleted
+    pub(crate) struct TempDataDir {
+        path: PathBuf,
+    }
+
+    impl TempDataDir {
+        pub(crate) fn path(&self) -> &Path {
+            &self.path
+        }
+    }
+
+    impl Drop for TempDataDir {
+        fn drop(&mut self) {
+            DIR.with(|dir| *dir.borrow_mut() = None);
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    /// Create an empty directory and use it as this thread's data directory
+    pub(crate) fn create() -> TempDataDir {
+        let path = std::env::temp_dir().join(format!("addie-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        DIR.with(|dir| *dir.borrow_mut() = Some(path.clone()));
+        TempDataDir { path }
+    }
+
+    pub(super) fn get() -> Option<PathBuf> {
+        DIR.with(|dir| dir.borrow().clone())
     }
-    Ok(None)
 }
 
-/// Clear session from both keychain and file
-pub fn clear_session() -> Result<(), Box<dyn std::error::Error>> {
-    // Try keychain
-    if let Ok(entry) = Entry::new(KEYRING_SERVICE, KEYRING_USER) {
-        let _ = entry.delete_credential();
+/// Headless mode for one test thread, without setting ADDIE_HEADLESS for every test
+#[cfg(test)]
+pub(crate) mod headless {
+    use std::cell::Cell;
+
+    thread_local! {
+        static SIMULATED: Cell<bool> = const { Cell::new(false) };
     }
 
-    // Also clear file
-    let path = get_session_file_path();
-    if path.exists() {
-        std::fs::remove_file(&path)?;
+    /// This thread runs as if ADDIE_HEADLESS=1 until dropped
+    pub(crate) struct Headless;
+
+    impl Drop for Headless {
+        fn drop(&mut self) {
+            SIMULATED.with(|simulated| simulated.set(false));
+        }
+    }
+
+    pub(crate) fn simulate() -> Headless {
+        SIMULATED.with(|simulated| simulated.set(true));
+        Headless
+    }
+
+    pub(super) fn is_simulated() -> bool {
+        SIMULATED.with(Cell::get)
+    }
+}
+
+/// Mock-runtime app with the state the auth code expects, for unit tests
+#[cfg(test)]
+pub(crate) mod test_app {
+    use std::sync::{Arc, Mutex};
+
+    use tauri::test::MockRuntime;
+    use tauri::{App, Listener, Manager};
+
+    use super::{AuthPhase, LastAuthError, PendingLogins};
+    use crate::session_timer::SessionTimer;
+
+    /// Payloads of one event, in the order they were emitted
+    #[derive(Clone, Default)]
+    pub(crate) struct Events(Arc<Mutex<Vec<serde_json::Value>>>);
+
+    impl Events {
+        pub(crate) fn payloads(&self) -> Vec<serde_json::Value> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    /// An app whose phase is restored from the session store, as at startup
+    ///
+    /// Inject a fake store first, or this reads the real keychain.
+    pub(crate) fn create() -> App<MockRuntime> {
+        with_pending_logins(PendingLogins::default())
+    }
+
+    /// An app as after a restart, also restoring the pending logins a previous run saved
+    ///
+    /// Needs a temp_data_dir, or this reads the real data directory.
+    pub(crate) fn restarted() -> App<MockRuntime> {
+        with_pending_logins(PendingLogins::restore())
+    }
+
+    fn with_pending_logins(pending: PendingLogins) -> App<MockRuntime> {
+        let app = tauri::test::mock_app();
+        app.manage(LastAuthError::default());
+        app.manage(pending);
+        app.manage(SessionTimer::default());
+        #[cfg(desktop)]
+        app.manage(crate::window::FocusTracker::default());
+        app.manage(AuthPhase::restore());
+        app
+    }
+
+    /// Record every `event` the app emits from now on
+    pub(crate) fn record(app: &App<MockRuntime>, event: &str) -> Events {
+        let events = Events::default();
+        let sink = events.clone();
+        app.listen_any(event, move |event| {
+            let payload = serde_json::from_str(event.payload()).unwrap_or_default();
+            sink.0.lock().unwrap().push(payload);
+        });
+        events
+    }
+
+    /// Record the name of each of `events` as it's emitted, to check their order
+    pub(crate) fn record_order(app: &App<MockRuntime>, events: &[&str]) -> Events {
+        let order = Events::default();
+        for &event in events {
+            let sink = order.clone();
+            let name = serde_json::Value::from(event);
+            app.listen_any(event, move |_| sink.0.lock().unwrap().push(name.clone()));
+        }
+        order
+    }
+}
+
+/// Set once the data directory turned out to be read-only; the session then lives in memory
+#[cfg(not(test))]
+static STORAGE_READONLY: AtomicBool = AtomicBool::new(false);
+
+// Per thread in unit tests, so a test of the fallback doesn't move every other test to memory
+#[cfg(test)]
+thread_local! {
+    static STORAGE_READONLY: AtomicBool = const { AtomicBool::new(false) };
+}
+
+fn set_storage_readonly() {
+    #[cfg(not(test))]
+    STORAGE_READONLY.store(true, Ordering::SeqCst);
+    #[cfg(test)]
+    STORAGE_READONLY.with(|readonly| readonly.store(true, Ordering::SeqCst));
+}
+
+/// Last provider list fetched from the server, and when
+type CachedProviders = Option<(std::time::Instant, Vec<LoginProviderInfo>)>;
+
+#[cfg(not(test))]
+static LOGIN_PROVIDERS: Mutex<CachedProviders> = Mutex::new(None);
+
+// Per thread in unit tests, so each test's mock server fills its own cache
+#[cfg(test)]
+thread_local! {
+    static LOGIN_PROVIDERS: Mutex<CachedProviders> = const { Mutex::new(None) };
+}
+
+fn with_login_providers<T>(f: impl FnOnce(&mut CachedProviders) -> T) -> T {
+    #[cfg(not(test))]
+    {
+        f(&mut LOGIN_PROVIDERS.lock().unwrap())
+    }
+    #[cfg(test)]
+    LOGIN_PROVIDERS.with(|cached| f(&mut cached.lock().unwrap()))
+}
+
+/// How the last session write and read went, for storage_health
+#[cfg(not(test))]
+fn last_session_write() -> &'static Mutex<Option<StorageOutcome>> {
+    static LAST_SESSION_WRITE: Mutex<Option<StorageOutcome>> = Mutex::new(None);
+    &LAST_SESSION_WRITE
+}
+
+#[cfg(not(test))]
+fn last_session_read() -> &'static Mutex<Option<StorageOutcome>> {
+    static LAST_SESSION_READ: Mutex<Option<StorageOutcome>> = Mutex::new(None);
+    &LAST_SESSION_READ
+}
+
+// Per thread in unit tests, so storage_health sees only that test's reads and writes
+#[cfg(test)]
+thread_local! {
+    static LAST_SESSION_WRITE: &'static Mutex<Option<StorageOutcome>> =
+        Box::leak(Box::new(Mutex::new(None)));
+    static LAST_SESSION_READ: &'static Mutex<Option<StorageOutcome>> =
+        Box::leak(Box::new(Mutex::new(None)));
+}
+
+#[cfg(test)]
+fn last_session_write() -> &'static Mutex<Option<StorageOutcome>> {
+    LAST_SESSION_WRITE.with(|slot| *slot)
+}
+
+#[cfg(test)]
+fn last_session_read() -> &'static Mutex<Option<StorageOutcome>> {
+    LAST_SESSION_READ.with(|slot| *slot)
+}
+
+/// App data directory, set once by init_data_dir
+static DATA_DIR: std::sync::OnceLock<std::path::PathBuf> = std::sync::OnceLock::new();
+
+/// Store the session was last read from or written to: "keychain" or "file"
+#[cfg(not(test))]
+fn last_session_store() -> &'static Mutex<Option<&'static str>> {
+    static LAST_SESSION_STORE: Mutex<Option<&'static str>> = Mutex::new(None);
+    &LAST_SESSION_STORE
+}
+
+#[cfg(test)]
+thread_local! {
+    static LAST_SESSION_STORE: &'static Mutex<Option<&'static str>> =
+        Box::leak(Box::new(Mutex::new(None)));
+}
+
+#[cfg(test)]
+fn last_session_store() -> &'static Mutex<Option<&'static str>> {
+    LAST_SESSION_STORE.with(|slot| *slot)
+}
+
+/// Id of a session quarantined by read_store that hasn't been reported as a logout yet
+#[cfg(not(test))]
+static UNREPORTED_QUARANTINE: Mutex<Option<String>> = Mutex::new(None);
+
+// Per thread in unit tests, so one test's get_valid_session can't report another's
+#[cfg(test)]
+thread_local! {
+    static UNREPORTED_QUARANTINE: Mutex<Option<String>> = const { Mutex::new(None) };
+}
+
+/// Set (or, with None, take) the quarantine get_valid_session will report, returning the last
+fn replace_unreported_quarantine(id: Option<String>) -> Option<String> {
+    #[cfg(not(test))]
+    {
+        std::mem::replace(&mut *UNREPORTED_QUARANTINE.lock().unwrap(), id)
+    }
+    #[cfg(test)]
+    UNREPORTED_QUARANTINE
+        .with(|quarantined| std::mem::replace(&mut *quarantined.lock().unwrap(), id))
+}
+
+/// Statuses sent in auth-state-changed, in order (headless mode)
+#[cfg(not(test))]
+static EMITTED_AUTH_STATES: Mutex<Vec<AuthStatus>> = Mutex::new(Vec::new());
+
+// Per thread in unit tests, like the opened URLs
+#[cfg(test)]
+thread_local! {
+    static EMITTED_AUTH_STATES: Mutex<Vec<AuthStatus>> = const { Mutex::new(Vec::new()) };
+}
+
+fn with_emitted_auth_states<T>(f: impl FnOnce(&mut Vec<AuthStatus>) -> T) -> T {
+    #[cfg(not(test))]
+    {
+        f(&mut EMITTED_AUTH_STATES.lock().unwrap())
+    }
+    #[cfg(test)]
+    EMITTED_AUTH_STATES.with(|states| f(&mut states.lock().unwrap()))
+}
+
+/// URLs that would have been opened in the browser (headless mode)
+#[cfg(not(test))]
+static OPENED_URLS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Per thread in unit tests, so each test sees only the URLs it opened
+#[cfg(test)]
+thread_local! {
+    static OPENED_URLS: Mutex<Vec<String>> = const { Mutex::new(Vec::new()) };
+}
+
+fn with_opened_urls<T>(f: impl FnOnce(&mut Vec<String>) -> T) -> T {
+    #[cfg(not(test))]
+    {
+        f(&mut OPENED_URLS.lock().unwrap())
+    }
+    #[cfg(test)]
+    OPENED_URLS.with(|urls| f(&mut urls.lock().unwrap()))
+}
+
+/// Whether we're running under automated tests/CI with no display, keychain or browser
+pub fn is_headless() -> bool {
+    #[cfg(test)]
+    if headless::is_simulated() {
+        return true;
+    }
+    std::env::var("ADDIE_HEADLESS").is_ok_and(|v| v == "1")
+}
+
+/// Whether sessions are kept in memory for this run (headless, or the data dir isn't writable)
+fn uses_memory_store() -> bool {
+    is_headless() || is_storage_readonly()
+}
+
+/// Whether the session file couldn't be written and logins won't survive a restart
+pub fn is_storage_readonly() -> bool {
+    #[cfg(not(test))]
+    {
+        STORAGE_READONLY.load(Ordering::SeqCst)
+    }
+    #[cfg(test)]
+    STORAGE_READONLY.with(|readonly| readonly.load(Ordering::SeqCst))
+}
+
+/// Open a URL in the system browser, or just record it when headless (and in unit tests)
+fn open_url<R: Runtime>(app: &AppHandle<R>, url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if cfg!(test) || is_headless() {
+        log::info!("Headless mode, not opening browser");
+        with_opened_urls(|urls| urls.push(url.to_string()));
+        return Ok(());
     }
 
+    // Open in system browser using OpenerExt trait
+    app.opener().open_url(url, None::<&str>)?;
     Ok(())
 }
+
+/// Open a help, docs or other web link from the frontend in the system browser
+///
+/// Only plain http(s) URLs are opened. Links that came from server content must
+/// also point at a trusted domain (TRUSTED_LINK_DOMAINS or the API host).
+pub fn open_external<R: Runtime>(
+    app: &AppHandle<R>,
+    url: &str,
+    server_supplied: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let parsed = check_external_url(url, server_supplied)?;
+    open_url(app, parsed.as_str())
+}
+
+/// The URL to open for open_external, or why it's refused
+fn check_external_url(
+    url: &str,
+    server_supplied: bool,
+) -> Result<url::Url, Box<dyn std::error::Error>> {
+    let parsed = url::Url::parse(url).map_err(|e| format!("Invalid URL: {}", e))?;
+
+    if !matches!(parsed.scheme(), "https" | "http") {
+        log::warn!(
+            "Refusing to open {}: scheme {} not allowed",
+            redact::redact_url(url),
+            parsed.scheme()
+        );
+        return Err(format!(
+            "Refusing to open {}: links must be http(s)",
+            parsed.scheme()
+        )
+        .into());
+    }
+    // user:pass@ lets a link show one host and go to another
+    if !parsed.username().is_empty() || parsed.password().is_some() {
+        log::warn!(
+            "Refusing to open {}: URL carries credentials",
+            redact::redact_url(url)
+        );
+        return Err("Refusing to open a URL with credentials".into());
+    }
+    let host = parsed.host_str().ok_or("URL has no host")?;
+
+    if server_supplied && !is_trusted_link_host(host) {
+        log::warn!(
+            "Refusing to open server-supplied link to untrusted host {}",
+            host
+        );
+        return Err(format!("Refusing to open link to untrusted host {}", host).into());
+    }
+
+    Ok(parsed)
+}
+
+/// Whether a host is a trusted domain, a subdomain of one, or the API host
+fn is_trusted_link_host(host: &str) -> bool {
+    let api_host = url::Url::parse(&get_api_base_url())
+        .ok()
+        .and_then(|api| api.host_str().map(str::to_string));
+
+    TRUSTED_LINK_DOMAINS
+        .iter()
+        .copied()
+        .chain(api_host.as_deref())
+        .any(|domain| host == domain || host.ends_with(&format!(".{}", domain)))
+}
+
+/// Move the login lifecycle to `state.phase` and emit auth-state-changed
+///
+/// The single place the phase changes. Illegal edges (see LoginPhase::can_transition_to)
+/// are logged and ignored, leaving the phase as it was; returns whether it moved. The
+/// session timer is re-armed for whatever session the new state is for.
+pub fn transition<R: Runtime>(app: &AppHandle<R>, state: AuthState) -> bool {
+    if !app.state::<AuthPhase>().advance(state.phase) {
+        return false;
+    }
+    emit_auth_state(app, state);
+    session_timer::reschedule(app);
+    true
+}
+
+/// Signed-out state as the lifecycle sees it: still pending or failed if a login is under way
+pub fn signed_out_state<R: Runtime>(app: &AppHandle<R>) -> AuthState {
+    let phase = *app.state::<AuthPhase>().0.lock().unwrap();
+    match phase {
+        LoginPhase::LoginPending => AuthState::pending(),
+        LoginPhase::Failed => AuthState::failed(
+            last_auth_error(app)
+                .map_or_else(|| "Login failed".to_string(), |report| report.message),
+        ),
+        LoginPhase::SignedOut | LoginPhase::Authenticated => AuthState::unauthenticated(),
+    }
+}
+
+/// Emit auth-state-changed, recording the status when headless so tests can follow transitions
+fn emit_auth_state<R: Runtime>(app: &AppHandle<R>, state: AuthState) {
+    if is_headless() {
+        with_emitted_auth_states(|states| states.push(state.status));
+    }
+    let fallback = serde_json::json!({
+        "status": state.status,
+        "is_authenticated": state.is_authenticated,
+    });
+    emit_critical(app, "auth-state-changed", state, fallback);
+}
+
+/// Emit an event, logging a failure instead of dropping it silently
+pub(crate) fn emit_logged<R: Runtime, S: serde::Serialize + Clone>(
+    app: &AppHandle<R>,
+    event: &str,
+    payload: S,
+) {
+    if let Err(e) = app.emit(event, payload) {
+        log::warn!("Failed to emit {}: {}", event, e);
+    }
+}
+
+/// Emit an event the UI can't miss, sending a minimal fallback payload if the full one fails
+///
+/// The fallback only carries plain values, so the UI can still transition with less detail.
+fn emit_critical<R: Runtime, S: serde::Serialize + Clone>(
+    app: &AppHandle<R>,
+    event: &str,
+    payload: S,
+    fallback: serde_json::Value,
+) {
+    if let Err(e) = app.emit(event, payload) {
+        log::warn!("Failed to emit {} ({}), sending minimal payload", event, e);
+        emit_logged(app, event, fallback);
+    }
+}
+
+/// Statuses sent in auth-state-changed so far (headless mode)
+pub fn emitted_auth_states() -> Vec<AuthStatus> {
+    with_emitted_auth_states(|states| states.clone())
+}
+
+/// Most recent URL recorded in place of opening the browser (headless mode)
+pub fn last_opened_url() -> Option<String> {
+    with_opened_urls(|urls| urls.last().cloned())
+}
+
+/// Run `task` in the background on the app's async runtime
+#[cfg(not(test))]
+fn spawn_background<T: Send + 'static>(
+    task: impl std::future::Future<Output = T> + Send + 'static,
+) -> tauri::async_runtime::JoinHandle<T> {
+    tauri::async_runtime::spawn(task)
+}
+
+/// On the test's own runtime in unit tests, so the task sees that thread's fake store and mock API
+#[cfg(test)]
+fn spawn_background<T: Send + 'static>(
+    task: impl std::future::Future<Output = T> + Send + 'static,
+) -> tauri::async_runtime::JoinHandle<T> {
+    tauri::async_runtime::JoinHandle::Tokio(tokio::spawn(task))
+}
+
+/// Current Unix time in seconds
+pub(crate) fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Maximum session age in seconds from the environment, used when the server doesn't set one
+pub(crate) fn get_default_max_session_age() -> Option<u64> {
+    std::env::var("ADDIE_MAX_SESSION_AGE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+}
+
+/// Whether a session has outlived the re-login floor
+///
+/// Age is measured from the original login, so it can't be extended by anything but a new login.
+/// Sessions saved before created_at existed are not subject to the floor.
+fn exceeds_max_session_age(session: &UserSession) -> bool {
+    session_deadline(session).is_some_and(|deadline| now_secs() > deadline)
+}
+
+/// Unix time after which the session must be re-authenticated, if one is known
+fn session_deadline(session: &UserSession) -> Option<u64> {
+    let max_age = session
+        .max_session_age
+        .or_else(get_default_max_session_age)?;
+    Some(session.created_at?.saturating_add(max_age))
+}
+
+/// Unix time the session stops working: the earlier of its token's expiry and the max-age
+/// deadline, whichever are known
+pub(crate) fn expiry_deadline(session: &UserSession) -> Option<u64> {
+    match (session.expires_at, session_deadline(session)) {
+        (Some(expires_at), Some(deadline)) => Some(expires_at.min(deadline)),
+        (expires_at, deadline) => expires_at.or(deadline),
+    }
+}
+
+/// Seconds until the stored session expires (see expiry_deadline)
+///
+/// None when there's no session or no known expiry; negative once already expired.
+/// Never refreshes or clears anything, so it's cheap enough to poll for a countdown.
+pub fn seconds_until_expiry() -> Result<Option<i64>, Box<dyn std::error::Error>> {
+    let deadline = get_session()?.as_ref().and_then(expiry_deadline);
+    // max_session_age comes straight from the callback URL, so don't trust it to fit
+    Ok(deadline.map(|deadline| {
+        i64::try_from(deadline)
+            .unwrap_or(i64::MAX)
+            .saturating_sub(now_secs() as i64)
+    }))
+}
+
+/// Get API base URL
+pub(crate) fn get_api_base_url() -> String {
+    #[cfg(test)]
+    if let Some(url) = crate::api::mock_api::base_url() {
+        return url;
+    }
+    std::env::var("ADDIE_API_URL").unwrap_or_else(|_| "https://agenticadvertising.org".to_string())
+}
+
+/// Identity providers the login endpoint can send the user straight to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginProvider {
+    Google,
+    Microsoft,
+    Apple,
+    GitHub,
+}
+
+impl LoginProvider {
+    const ALL: [LoginProvider; 4] = [
+        LoginProvider::Google,
+        LoginProvider::Microsoft,
+        LoginProvider::Apple,
+        LoginProvider::GitHub,
+    ];
+
+    /// Friendly name accepted by start_login (the inverse of FromStr)
+    fn id(&self) -> &'static str {
+        match self {
+            LoginProvider::Google => "google",
+            LoginProvider::Microsoft => "microsoft",
+            LoginProvider::Apple => "apple",
+            LoginProvider::GitHub => "github",
+        }
+    }
+
+    fn display_name(&self) -> &'static str {
+        match self {
+            LoginProvider::Google => "Google",
+            LoginProvider::Microsoft => "Microsoft",
+            LoginProvider::Apple => "Apple",
+            LoginProvider::GitHub => "GitHub",
+        }
+    }
+
+    /// Value the server expects in the `provider` query param
+    fn as_param(&self) -> &'static str {
+        match self {
+            LoginProvider::Google => "GoogleOAuth",
+            LoginProvider::Microsoft => "MicrosoftOAuth",
+            LoginProvider::Apple => "AppleOAuth",
+            LoginProvider::GitHub => "GitHubOAuth",
+        }
+    }
+}
+
+impl std::str::FromStr for LoginProvider {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "google" => Ok(LoginProvider::Google),
+            "microsoft" => Ok(LoginProvider::Microsoft),
+            "apple" => Ok(LoginProvider::Apple),
+            "github" => Ok(LoginProvider::GitHub),
+            _ => Err(format!("Unknown login provider: {}", name)),
+        }
+    }
+}
+
+/// Login providers for the sign-in screen
+///
+/// Asks the server (reusing its answer for a few minutes) and falls back to the
+/// providers built into the app if it can't be reached.
+pub async fn get_login_providers() -> Vec<LoginProviderInfo> {
+    let cached = with_login_providers(|cached| {
+        cached
+            .as_ref()
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < LOGIN_PROVIDERS_TTL)
+            .map(|(_, providers)| providers.clone())
+    });
+    if let Some(providers) = cached {
+        return providers;
+    }
+
+    match api::get_login_providers().await {
+        Ok(providers) => {
+            with_login_providers(|cached| {
+                *cached = Some((std::time::Instant::now(), providers.clone()))
+            });
+            providers
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to fetch login providers, using built-in list: {}",
+                e
+            );
+            LoginProvider::ALL
+                .iter()
+                .map(|provider| LoginProviderInfo {
+                    id: provider.id().to_string(),
+                    display_name: provider.display_name().to_string(),
+                    icon_url: None,
+                })
+                .collect()
+        }
+    }
+}
+
+/// OAuth `prompt` values the login page can be asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginPrompt {
+    /// Let the provider decide (no prompt param)
+    None,
+    /// Re-enter credentials even with a live provider session
+    Login,
+    /// Show the account chooser, for users auto-signed into the wrong SSO account
+    SelectAccount,
+    /// Ask for consent again
+    Consent,
+}
+
+impl LoginPrompt {
+    /// Value for the `prompt` query param, if one should be sent
+    fn as_param(&self) -> Option<&'static str> {
+        match self {
+            LoginPrompt::None => None,
+            LoginPrompt::Login => Some("login"),
+            LoginPrompt::SelectAccount => Some("select_account"),
+            LoginPrompt::Consent => Some("consent"),
+        }
+    }
+}
+
+impl std::str::FromStr for LoginPrompt {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Ok(LoginPrompt::None),
+            "login" => Ok(LoginPrompt::Login),
+            "select_account" => Ok(LoginPrompt::SelectAccount),
+            "consent" => Ok(LoginPrompt::Consent),
+            _ => Err(format!("Unknown login prompt: {}", name)),
+        }
+    }
+}
+
+/// Whether an SSO connection id looks like one WorkOS issues (`conn_` + alphanumerics)
+fn is_valid_connection_id(connection: &str) -> bool {
+    connection
+        .strip_prefix("conn_")
+        .is_some_and(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Build the login page URL for the given options
+fn build_login_url(
+    options: &LoginOptions,
+    state: &str,
+    code_challenge: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let device_token = crate::device::device_token();
+    let locale = crate::locale::get_login_locale();
+    compose_login_url(
+        &get_api_base_url(),
+        options,
+        state,
+        code_challenge,
+        device_token.as_deref(),
+        locale.as_deref(),
+    )
+}
+
+/// build_login_url without the reads from storage and the OS, so it can be tested
+fn compose_login_url(
+    api_base: &str,
+    options: &LoginOptions,
+    state: &str,
+    code_challenge: &str,
+    device_token: Option<&str>,
+    locale: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // The login URL will redirect to WorkOS, which will callback with an authorization code
+    // (or, on servers without code exchange, the sealed session) in the deep link
+    let mut login_url = format!(
+        "{}/auth/login?native=true&redirect_uri={}&state={}&code_challenge={}&code_challenge_method=S256",
+        api_base,
+        urlencoding::encode(&format!("{}://auth/callback", crate::deep_link_scheme())),
+        state,
+        code_challenge
+    );
+
+    if let Some(provider) = &options.provider {
+        let provider: LoginProvider = provider.parse()?;
+        login_url.push_str(&format!("&provider={}", provider.as_param()));
+    }
+
+    if let Some(connection) = &options.connection {
+        if !is_valid_connection_id(connection) {
+            return Err(format!("Invalid SSO connection id: {}", connection).into());
+        }
+        login_url.push_str(&format!("&connection={}", connection));
+    }
+
+    if let Some(prompt) = &options.prompt {
+        let prompt: LoginPrompt = prompt.parse()?;
+        if let Some(prompt) = prompt.as_param() {
+            login_url.push_str(&format!("&prompt={}", prompt));
+        }
+    }
+
+    // Not encoded, so a token that isn't a plain opaque value is left out instead
+    if let Some(device_token) = device_token.filter(|t| crate::device::is_valid_device_token(t)) {
+        login_url.push_str(&format!("&device_token={}", device_token));
+    }
+
+    if let Some(locale) = locale {
+        login_url.push_str(&format!("&ui_locale={}", urlencoding::encode(locale)));
+    }
+
+    Ok(login_url)
+}
+
+/// Start OAuth flow by opening browser to login page
+pub fn start_oauth_flow<R: Runtime>(
+    app: &AppHandle<R>,
+    options: &LoginOptions,
+    window_label: Option<&str>,
+) -> Result<(), AuthError> {
+    // Already signed in: tell the frontend instead of sending the user through the browser again
+    if !options.force {
+        if let Some(session) = get_valid_session(app).map_err(AuthError::storage)? {
+            log::info!("Already authenticated, not starting login");
+            transition(app, AuthState::authenticated(session));
+            return Ok(());
+        }
+    }
+
+    if let Some(return_to) = &options.return_to {
+        if !is_allowed_route(return_to) {
+            return Err(AuthError::InvalidReturnTo(return_to.clone()));
+        }
+    }
+
+    // The server echoes state back on the callback, which is how return_to survives the round trip
+    let state = generate_state();
+    // PKCE: only this app knows the verifier, so a callback intercepted by another app is useless
+    let code_verifier = generate_state();
+    let login_url = build_login_url(options, &state, &pkce_challenge(&code_verifier))
+        .map_err(|e| AuthError::InvalidLoginOption(e.to_string()))?;
+
+    {
+        let pending = app.state::<PendingLogins>();
+        let mut pending = pending.0.lock().unwrap();
+        if pending.len() >= MAX_PENDING_LOGINS {
+            pending.remove(0);
+        }
+        pending.push(PendingLogin {
+            state,
+            return_to: options.return_to.clone(),
+            window_label: window_label.map(str::to_string),
+            login_url: login_url.clone(),
+            started_at: now_secs(),
+            code_verifier: Some(code_verifier),
+        });
+        persist_pending_logins(&pending);
+    }
+
+    // A forced re-login keeps the current session, so an Authenticated phase stays put
+    let phase = *app.state::<AuthPhase>().0.lock().unwrap();
+    if phase != LoginPhase::Authenticated {
+        transition(app, AuthState::pending());
+    }
+
+    telemetry::record(app, Metric::LoginStarted);
+    open_url(app, &login_url).map_err(|e| AuthError::BrowserFailed(e.to_string()))
+}
+
+/// Random opaque value identifying one login attempt
+fn generate_state() -> String {
+    use base64::Engine;
+    use rand::RngCore;
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// S256 PKCE challenge for a verifier (RFC 7636)
+fn pkce_challenge(code_verifier: &str) -> String {
+    use base64::Engine;
+    use sha2::{Digest, Sha256};
+
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Whether auth callbacks must use the PKCE code exchange (ADDIE_REQUIRE_PKCE=1)
+///
+/// Off by default so servers that haven't enabled `/auth/token` can still send the session directly.
+pub(crate) fn is_pkce_required() -> bool {
+    std::env::var("ADDIE_REQUIRE_PKCE").is_ok_and(|v| v == "1")
+}
+
+/// Take the login attempt matching a callback's state, forgetting it
+fn take_pending_login<R: Runtime>(app: &AppHandle<R>, state: &str) -> Option<PendingLogin> {
+    let pending = app.state::<PendingLogins>();
+    let mut pending = pending.0.lock().unwrap();
+    // A stale deep link must not complete a login that has already timed out
+    let now = now_secs();
+    pending.retain(|login| now.saturating_sub(login.started_at) < PENDING_LOGIN_TTL_SECS);
+    let login = pending
+        .iter()
+        .position(|login| login.state == state)
+        .map(|index| pending.remove(index));
+    persist_pending_logins(&pending);
+    login
+}
+
+/// Authorize URL of the most recent login that hasn't completed or timed out
+fn latest_login_url<R: Runtime>(app: &AppHandle<R>) -> Option<String> {
+    let pending = app.state::<PendingLogins>();
+    let mut pending = pending.0.lock().unwrap();
+    let now = now_secs();
+    pending.retain(|login| now.saturating_sub(login.started_at) < PENDING_LOGIN_TTL_SECS);
+    persist_pending_logins(&pending);
+    pending.last().map(|login| login.login_url.clone())
+}
+
+/// Forget every started login, e.g. once one of them has completed
+fn clear_pending_logins<R: Runtime>(app: &AppHandle<R>) {
+    let pending = app.state::<PendingLogins>();
+    let mut pending = pending.0.lock().unwrap();
+    pending.clear();
+    persist_pending_logins(&pending);
+}
+
+/// Re-open the browser for the login in progress, keeping its state
+///
+/// For when the first launch failed (no default browser, sandboxed opener).
+pub fn retry_open_login<R: Runtime>(app: &AppHandle<R>) -> Result<(), Box<dyn std::error::Error>> {
+    let login_url = latest_login_url(app).ok_or("No login in progress")?;
+    open_url(app, &login_url)
+}
+
+/// Authorize URL of the login in progress, for the user to paste into a browser themselves
+pub fn copy_login_url<R: Runtime>(
+    app: &AppHandle<R>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(latest_login_url(app).ok_or("No login in progress")?)
+}
+
+/// Check everything that could make a login fail before the UI offers one
+pub async fn login_preflight<R: Runtime>(app: &AppHandle<R>) -> LoginPreflight {
+    // The only slow check; spawn it first so the rest run while it's in flight
+    let network = spawn_background(api::ping());
+
+    let mut checks = vec![
+        check_deep_link_scheme(app),
+        check_browser_available(),
+        check_api_base(&get_api_base_url()),
+    ];
+
+    let network = match network.await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    checks.push(match network {
+        Ok(()) => PreflightCheck::pass("network_reachable"),
+        Err(e) => PreflightCheck::fail(
+            "network_reachable",
+            format!(
+                "Can't reach {} ({}). Check your connection or proxy.",
+                get_api_base_url(),
+                e
+            ),
+        ),
+    });
+
+    LoginPreflight {
+        ready: checks.iter().all(|check| check.passed),
+        checks,
+    }
+}
+
+/// Push a synthetic callback through every deep link stage except saving the session
+///
+/// The URL is built here from a random marker and never reaches route_deep_link, so the
+/// self-test can't be used to sign anyone in.
+pub fn run_deeplink_selftest<R: Runtime>(app: &AppHandle<R>) -> DeepLinkSelfTest {
+    let marker = generate_state();
+    let url = format!(
+        "{}://auth/callback?sealed_session=selftest-{}&user_id=selftest&email=selftest%40invalid&state={}",
+        crate::deep_link_scheme(),
+        marker,
+        marker
+    );
+
+    let stage = |name: &'static str, passed: bool, hint: &str| {
+        if passed {
+            PreflightCheck::pass(name)
+        } else {
+            PreflightCheck::fail(name, hint)
+        }
+    };
+
+    let mut stages = vec![check_deep_link_scheme(app)];
+
+    let parsed = url::Url::parse(&url).ok();
+    stages.push(stage(
+        "url_parses",
+        parsed.is_some(),
+        "The synthetic callback URL didn't parse.",
+    ));
+    stages.push(stage(
+        "recognized_as_callback",
+        parsed.as_ref().is_some_and(is_auth_callback),
+        "The callback wasn't recognised as an auth link.",
+    ));
+    stages.push(stage(
+        "kept_in_batch",
+        coalesce_deep_links(std::slice::from_ref(&url)) == [url.clone()],
+        "The callback was dropped while batching deep links.",
+    ));
+
+    let callback = parse_callback(&url);
+    stages.push(stage(
+        "session_extracted",
+        matches!(&callback, Ok(Some(AuthCallback { grant: CallbackGrant::Session(session), state, .. }))
+            if session.user_id == "selftest" && state.as_deref() == Some(marker.as_str())),
+        "The session couldn't be read back out of the callback.",
+    ));
+
+    DeepLinkSelfTest {
+        passed: stages.iter().all(|stage| stage.passed),
+        stages,
+    }
+}
+
+/// Whether the OS will route addie:// links back to this app
+fn check_deep_link_scheme<R: Runtime>(app: &AppHandle<R>) -> PreflightCheck {
+    use tauri_plugin_deep_link::DeepLinkExt;
+
+    const NAME: &str = "scheme_registered";
+    if is_headless() {
+        return PreflightCheck::pass(NAME);
+    }
+
+    match app.deep_link().is_registered(crate::DEEP_LINK_SCHEME) {
+        Ok(true) => PreflightCheck::pass(NAME),
+        Ok(false) => PreflightCheck::fail(
+            NAME,
+            format!(
+                "The {}:// link handler isn't registered. Re-register it from settings or reinstall the app.",
+                crate::DEEP_LINK_SCHEME
+            ),
+        ),
+        // macOS and mobile register the scheme at install time and can't be queried
+        Err(_) => PreflightCheck::pass(NAME),
+    }
+}
+
+/// Whether there's something to open the login page with
+fn check_browser_available() -> PreflightCheck {
+    const NAME: &str = "browser_available";
+
+    // Linux desktops open URLs through xdg-open (or $BROWSER); other platforms always have a handler
+    let available = is_headless()
+        || !cfg!(target_os = "linux")
+        || std::env::var_os("BROWSER").is_some()
+        || std::env::var_os("PATH").is_some_and(|path| {
+            std::env::split_paths(&path).any(|dir| dir.join("xdg-open").is_file())
+        });
+
+    if available {
+        PreflightCheck::pass(NAME)
+    } else {
+        PreflightCheck::fail(
+            NAME,
+            "No browser launcher found. Install xdg-utils or set $BROWSER, or copy the login link instead.",
+        )
+    }
+}
+
+/// Whether the configured API base is a usable http(s) URL
+fn check_api_base(api_base: &str) -> PreflightCheck {
+    const NAME: &str = "api_base_valid";
+
+    match url::Url::parse(api_base) {
+        Ok(parsed) if matches!(parsed.scheme(), "https" | "http") && parsed.host().is_some() => {
+            PreflightCheck::pass(NAME)
+        }
+        _ => PreflightCheck::fail(
+            NAME,
+            format!("ADDIE_API_URL is not a valid http(s) URL: {}", api_base),
+        ),
+    }
+}
+
+/// Limit a display name's length without ever splitting a character
+///
+/// Counts chars rather than bytes so emoji, RTL text and combining accents come
+/// through intact; names within the limit are returned unchanged.
+fn cap_display_name(name: &str) -> String {
+    name.chars().take(MAX_NAME_CHARS).collect()
+}
+
+/// Whether a token_type is a plain auth scheme name, safe to put in a header
+fn is_valid_token_type(token_type: &str) -> bool {
+    !token_type.is_empty()
+        && token_type.len() <= 32
+        && token_type
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Whether a parsed deep link is <scheme>://auth<path>, for the scheme this build handles
+///
+/// The url crate lowercases the scheme but keeps the host of a custom scheme as
+/// given, and some OSes uppercase it (ADDIE://AUTH/...), so compare it ASCII
+/// case-insensitively. Only the custom scheme is accepted, never https links,
+/// so there's no unicode host that could pass for "auth".
+fn is_auth_link(parsed: &url::Url, path: &str) -> bool {
+    parsed.scheme() == crate::deep_link_scheme()
+        && parsed
+            .host_str()
+            .is_some_and(|host| host.eq_ignore_ascii_case("auth"))
+        && parsed.path() == path
+}
+
+/// Whether a parsed deep link is the OAuth callback
+fn is_auth_callback(parsed: &url::Url) -> bool {
+    is_auth_link(parsed, "/callback")
+}
+
+/// The single value of `key` in a server-sent auth link, if present
+///
+/// A second copy is rejected, as for callbacks, since it could be an appended value.
+fn link_param(parsed: &url::Url, key: &'static str) -> Result<Option<String>, AuthError> {
+    let mut values = parsed
+        .query_pairs()
+        .take(MAX_CALLBACK_PARAMS)
+        .filter(|(k, _)| k == key)
+        .map(|(_, value)| value.into_owned());
+    let value = values.next();
+    if values.next().is_some() {
+        return Err(AuthError::DuplicateParam(key.to_string()));
+    }
+    Ok(value)
+}
+
+/// The user a server-sent auth link (force-logout, profile-updated) is about
+fn link_user_id(parsed: &url::Url) -> Result<String, AuthError> {
+    match link_param(parsed, "user_id")? {
+        Some(user_id) if user_id.is_empty() => Err(AuthError::InvalidParam("user_id")),
+        Some(user_id) => Ok(user_id),
+        None => Err(AuthError::MissingParam("user_id")),
+    }
+}
+
+/// Handle `addie://auth/profile-updated?user_id=...` by re-fetching the profile in the background
+///
+/// Ignored unless user_id names the signed-in user.
+pub(crate) fn handle_profile_updated<R: Runtime>(
+    app: &AppHandle<R>,
+    parsed: &url::Url,
+) -> Result<(), AuthError> {
+    let user_id = link_user_id(parsed)?;
+
+    if get_session()
+        .map_err(AuthError::storage)?
+        .is_none_or(|session| session.user_id != user_id)
+    {
+        log::info!("Ignoring profile-updated for a user that isn't signed in");
+        return Ok(());
+    }
+
+    let app = app.clone();
+    spawn_background(async move {
+        if let Err(e) = fetch_profile(&app).await {
+            log::warn!("Failed to refresh profile: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+/// Refresh the stored name and email from the server, emitting auth-state-changed
+///
+/// The profile is only applied if the server still reports the same user.
+pub async fn fetch_profile<R: Runtime>(
+    app: &AppHandle<R>,
+) -> Result<Option<UserSession>, Box<dyn std::error::Error>> {
+    let session = get_valid_session(app)?;
+    let Some(session) = session else {
+        return Ok(None);
+    };
+
+    let me = api::get_me(Credentials::for_session(&session)).await?;
+    if me.id != session.user_id {
+        return Err("Server returned a different user than the stored session".into());
+    }
+
+    let updated = UserSession {
+        email: me.email,
+        first_name: me.first_name.as_deref().map(cap_display_name),
+        last_name: me.last_name.as_deref().map(cap_display_name),
+        ..session.clone()
+    };
+
+    if updated != session {
+        save_session_verified(&updated)?;
+        log::info!("Profile refreshed for user: {}", updated.user_id);
+    }
+    transition(app, AuthState::authenticated(updated.clone()));
+
+    Ok(Some(updated))
+}
+
+/// Handle `addie://auth/force-logout?user_id=...&reason=...` sent by the server after a security event
+///
+/// Only acts when user_id names the signed-in user, so a stray link can't log out someone else.
+/// An inactive stored account with that id is forgotten quietly, without touching the active one.
+pub(crate) fn handle_force_logout<R: Runtime>(
+    app: &AppHandle<R>,
+    parsed: &url::Url,
+) -> Result<(), AuthError> {
+    let user_id = link_user_id(parsed)?;
+    let reason = link_param(parsed, "reason")?;
+
+    let session = match get_session().map_err(AuthError::storage)? {
+        Some(session) if session.user_id == user_id => session,
+        _ => {
+            if remove_inactive_account(&user_id).map_err(AuthError::storage)? {
+                log::info!(
+                    "Server requested logout for inactive account {}, removed it",
+                    user_id
+                );
+                return Ok(());
+            }
+            log::info!("Ignoring force-logout for a user that isn't signed in");
+            return Ok(());
+        }
+    };
+
+    // Reason is shown by the UI, so only pass through simple codes
+    let reason = reason
+        .filter(|r| {
+            r.len() <= 64
+                && r.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+        .unwrap_or_else(|| "unspecified".to_string());
+
+    log::info!(
+        "Server requested logout for {} ({})",
+        session.user_id,
+        reason
+    );
+    force_logout_local(app, LogoutReason::ServerRequested, Some(reason))
+        .map_err(AuthError::storage)?;
+
+    Ok(())
+}
+
+/// Reduce a burst of deep links (e.g. replayed on mobile resume) to the ones worth handling
+///
+/// Duplicates are dropped and only the latest auth callback carrying a session (or a code to
+/// exchange for one) is kept, so the freshest login wins. Other links keep their original order.
+pub fn coalesce_deep_links(urls: &[String]) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+
+    // For each unique link: None if it isn't a callback, else whether it carries a session
+    let links: Vec<(&String, Option<bool>)> = urls
+        .iter()
+        .filter(|url| seen.insert(url.as_str()))
+        .map(|url| {
+            let callback = url::Url::parse(url).ok().filter(is_auth_callback);
+            let has_session = callback.map(|p| {
+                p.query_pairs()
+                    .any(|(k, _)| k == "sealed_session" || k == "code")
+            });
+            (url, has_session)
+        })
+        .collect();
+
+    // Prefer the last callback with a session; fall back to the last callback so its error surfaces
+    let chosen = links
+        .iter()
+        .rposition(|(_, cb)| *cb == Some(true))
+        .or_else(|| links.iter().rposition(|(_, cb)| cb.is_some()));
+
+    links
+        .into_iter()
+        .enumerate()
+        .filter(|(i, (_, cb))| cb.is_none() || Some(*i) == chosen)
+        .map(|(_, (url, _))| url.clone())
+        .collect()
+}
+
+/// Open the web dashboard in the system browser, signed in via a one-time ticket
+///
+/// The raw sealed session never leaves the app; if no ticket can be obtained the page
+/// is opened anyway and the user signs in on the web.
+pub async fn open_account_portal<R: Runtime>(
+    app: &AppHandle<R>,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !is_allowed_route(path) {
+        return Err(format!("Path not allowed for account portal: {}", path).into());
+    }
+
+    let mut portal_url = url::Url::parse(&format!("{}{}", get_api_base_url(), path))?;
+
+    let session = get_valid_session(app)?;
+    if let Some(session) = session {
+        match api::create_portal_ticket(Credentials::for_session(&session)).await {
+            Ok(ticket) => {
+                portal_url.query_pairs_mut().append_pair("ticket", &ticket);
+            }
+            Err(e) => log::warn!(
+                "Failed to get portal ticket, opening unauthenticated: {}",
+                e
+            ),
+        }
+    }
+
+    open_url(app, portal_url.as_str())
+}
+
+/// A parsed addie://auth/callback link
+#[derive(Debug)]
+pub struct AuthCallback {
+    pub grant: CallbackGrant,
+    /// OAuth state echoed back by the server, if any
+    pub state: Option<String>,
+    /// Trusted-device token to present on later logins, if the server issued one
+    /// (for a code grant it comes with the token exchange instead)
+    pub device_token: Option<String>,
+}
+
+/// What a callback carries to sign in with
+#[derive(Debug)]
+pub enum CallbackGrant {
+    /// PKCE authorization code, exchanged with the login's verifier at `/auth/token`
+    Code(String),
+    /// Session handed over directly, by servers that haven't enabled code exchange
+    Session(Box<UserSession>),
+}
+
+/// Parse an auth callback URL without touching storage or the app
+///
+/// Returns Ok(None) for well-formed links that aren't auth callbacks, so "ignored" is
+/// distinct from a malformed callback. Names are form-decoded (`+` and `%20` are spaces).
+/// Kept free of side effects so it can be fuzzed (see fuzz/).
+pub fn parse_callback(url: &str) -> Result<Option<AuthCallback>, AuthError> {
+    // Reject oversized links before parsing so junk can't exhaust memory
+    if url.len() > MAX_CALLBACK_URL_LEN {
+        log::warn!(
+            "Rejecting deep link: {} bytes exceeds limit of {}",
+            url.len(),
+            MAX_CALLBACK_URL_LEN
+        );
+        return Err(AuthError::CallbackTooLarge);
+    }
+
+    callback_from_url(&url::Url::parse(url)?)
+}
+
+/// parse_callback for a link that's already been parsed (and size-checked)
+fn callback_from_url(parsed: &url::Url) -> Result<Option<AuthCallback>, AuthError> {
+    // Check if this is an auth callback
+    if !is_auth_callback(parsed) {
+        return Ok(None); // Not an auth URL, ignore
+    }
+
+    // Count pairs lazily so a flood of params is rejected before we collect them
+    if parsed.query_pairs().take(MAX_CALLBACK_PARAMS + 1).count() > MAX_CALLBACK_PARAMS {
+        log::warn!(
+            "Rejecting auth callback: more than {} query params",
+            MAX_CALLBACK_PARAMS
+        );
+        return Err(AuthError::CallbackTooLarge);
+    }
+
+    // Collecting into a map would silently keep the last copy, so refuse to guess
+    let mut seen = std::collections::HashSet::new();
+    for (key, _) in parsed.query_pairs() {
+        if SINGLE_VALUE_PARAMS.contains(&key.as_ref()) && !seen.insert(key.clone()) {
+            log::warn!("Rejecting auth callback: duplicate {} param", key);
+            return Err(AuthError::DuplicateParam(key.to_string()));
+        }
+    }
+
+    let params: std::collections::HashMap<_, _> = parsed.query_pairs().collect();
+    let state = params.get("state").map(|v| v.to_string());
+
+    // Servers with code exchange send only a code; the session is fetched with our verifier
+    if let Some(code) = params.get("code") {
+        if !is_valid_auth_code(code) {
+            return Err(AuthError::InvalidParam("code"));
+        }
+        return Ok(Some(AuthCallback {
+            grant: CallbackGrant::Code(code.to_string()),
+            state,
+            device_token: None,
+        }));
+    }
+
+    // Otherwise the server sent the sealed session directly
+    let required = |name: &'static str| {
+        params
+            .get(name)
+            .map(|v| v.to_string())
+            .ok_or(AuthError::MissingParam(name))
+    };
+    let token = api::TokenResponse {
+        sealed_session: required("sealed_session")?,
+        user_id: required("user_id")?,
+        email: required("email")?,
+        first_name: params.get("first_name").map(|v| v.to_string()),
+        last_name: params.get("last_name").map(|v| v.to_string()),
+        max_session_age: params.get("max_session_age").and_then(|v| v.parse().ok()),
+        token_type: params.get("token_type").map(|v| v.to_string()),
+        scope: params.get("scope").map(|v| v.to_string()),
+        expires_at: params.get("expires_at").and_then(|v| v.parse().ok()),
+        refresh_token: params.get("refresh_token").map(|v| v.to_string()),
+        device_token: params.get("device_token").map(|v| v.to_string()),
+    };
+    let (session, device_token) = session_from_token(token)?;
+
+    Ok(Some(AuthCallback {
+        grant: CallbackGrant::Session(Box::new(session)),
+        state,
+        device_token,
+    }))
+}
+
+/// Authorization codes are opaque, but only ever URL-safe and reasonably short
+fn is_valid_auth_code(code: &str) -> bool {
+    !code.is_empty()
+        && code.len() <= MAX_AUTH_CODE_LEN
+        && code
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+}
+
+/// Validate a session from the callback or the code exchange, returning it with the device token
+///
+/// Both paths go through here so a session is held to the same limits however it arrived.
+pub(crate) fn session_from_token(
+    token: api::TokenResponse,
+) -> Result<(UserSession, Option<String>), AuthError> {
+    // `sealed_session=` with no value would otherwise be saved and fail every API call
+    for (name, value) in [
+        ("sealed_session", &token.sealed_session),
+        ("user_id", &token.user_id),
+        ("email", &token.email),
+    ] {
+        if value.trim().is_empty() {
+            return Err(AuthError::InvalidParam(name));
+        }
+    }
+
+    // Fail clearly here rather than with a confusing keychain/disk error on save
+    if token.sealed_session.len() > MAX_SEALED_SESSION_LEN {
+        log::warn!(
+            "Rejecting auth callback: sealed_session is {} bytes, limit is {}",
+            token.sealed_session.len(),
+            MAX_SEALED_SESSION_LEN
+        );
+        return Err(AuthError::TokenTooLarge);
+    }
+
+    let token_type = match token.token_type {
+        Some(token_type) if is_valid_token_type(&token_type) => token_type,
+        Some(_) => return Err(AuthError::InvalidParam("token_type")),
+        None => crate::DEFAULT_TOKEN_TYPE.to_string(),
+    };
+    let scopes = token
+        .scope
+        .map(|scope| scope.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default();
+    // A bad refresh token only means logging in again at expiry, so drop it rather than the login
+    let refresh_token = token
+        .refresh_token
+        .filter(|token| is_valid_refresh_token(token));
+
+    let session = UserSession {
+        sealed_session: token.sealed_session,
+        user_id: token.user_id,
+        email: token.email,
+        first_name: token.first_name.as_deref().map(cap_display_name),
+        last_name: token.last_name.as_deref().map(cap_display_name),
+        created_at: Some(now_secs()),
+        max_session_age: token.max_session_age,
+        token_type,
+        scopes,
+        schema_version: crate::SESSION_SCHEMA_VERSION,
+        expires_at: token.expires_at,
+        refresh_token,
+    };
+
+    // A malformed device token only costs an MFA prompt later, so drop it rather than the login
+    let device_token = token
+        .device_token
+        .filter(|token| crate::device::is_valid_device_token(token));
+
+    Ok((session, device_token))
+}
+
+/// Handle deep link callback from OAuth flow
+/// URL format: addie://auth/callback?code=xxx&state=xxx, or without code exchange
+/// addie://auth/callback?sealed_session=xxx&user_id=xxx&email=xxx&first_name=xxx&last_name=xxx&state=xxx
+///
+/// Reached through deep_link::route_deep_link, which has already parsed and size-checked it.
+pub(crate) fn handle_callback<R: Runtime>(
+    app: &AppHandle<R>,
+    parsed: &url::Url,
+) -> Result<(), AuthError> {
+    let AuthCallback {
+        grant,
+        state,
+        device_token,
+    } = match callback_from_url(parsed)? {
+        Some(callback) => callback,
+        None => return Ok(()),
+    };
+
+    // Only a callback for a login this app started may sign in; anything else could be
+    // another app injecting a session through our scheme. Each state works once.
+    let pending = match state
+        .as_deref()
+        .and_then(|state| take_pending_login(app, state))
+    {
+        Some(pending) => pending,
+        None => {
+            let error = match state {
+                None => AuthError::MissingState,
+                Some(_) => AuthError::StateMismatch,
+            };
+            return Err(reject_callback(app, error));
+        }
+    };
+
+    match grant {
+        CallbackGrant::Code(code) => {
+            let Some(code_verifier) = pending.code_verifier.clone() else {
+                return Err(reject_callback(app, AuthError::PkceUnavailable));
+            };
+
+            // The exchange is a network call, so finish the login in the background
+            let app = app.clone();
+            spawn_background(async move {
+                let exchanged = match api::exchange_code(&code, &code_verifier).await {
+                    Ok(token) => session_from_token(token),
+                    Err(e) => Err(e.into()),
+                };
+                let result = match exchanged {
+                    Ok((session, device_token)) => {
+                        finish_login(&app, &pending, session, device_token)
+                    }
+                    Err(e) => Err(reject_callback(&app, e)),
+                };
+                if let Err(e) = result {
+                    log::warn!("Login by code exchange failed: {}", e);
+                }
+            });
+            Ok(())
+        }
+        CallbackGrant::Session(_) if is_pkce_required() => {
+            Err(reject_callback(app, AuthError::PkceRequired))
+        }
+        CallbackGrant::Session(session) => {
+            log::info!("Server sent the session directly (no code exchange), accepting it");
+            finish_login(app, &pending, *session, device_token)
+        }
+    }
+}
+
+/// Report a callback that can't sign in, returning the error for the caller to pass on
+fn reject_callback<R: Runtime>(app: &AppHandle<R>, error: AuthError) -> AuthError {
+    log::warn!("Rejected auth callback: {}", error);
+    report_auth_error(app, error.to_string());
+    error
+}
+
+/// Save a session from a verified callback and tell the frontend the login succeeded
+fn finish_login<R: Runtime>(
+    app: &AppHandle<R>,
+    pending: &PendingLogin,
+    session: UserSession,
+    device_token: Option<String>,
+) -> Result<(), AuthError> {
+    // Store session securely, and only report success once it's durably readable
+    let session = match save_signed_in_session(&session) {
+        Ok(merge) => report_merge(app, merge, session),
+        Err(e) => {
+            log::error!("Failed to save session: {}", e);
+            report_auth_error(app, format!("Failed to save session: {}", e));
+            return Err(AuthError::storage(e));
+        }
+    };
+
+    log::info!("Auth callback received for user: {}", session.user_id);
+
+    if let Some(device_token) = device_token {
+        if let Err(e) = crate::device::save_device_token(&device_token) {
+            log::warn!("Failed to save device token: {}", e);
+        }
+    }
+
+    if is_storage_readonly() {
+        emit_logged(app, "storage-readonly", ());
+    }
+
+    // Signed in now, so no other started login's URL should be reusable
+    clear_pending_logins(app);
+
+    // Re-check return_to even though it was validated when the login started
+    let return_to = pending
+        .return_to
+        .clone()
+        .filter(|route| is_allowed_route(route));
+
+    // Bring window to foreground first (desktop only), so a login screen sitting behind
+    // the browser is visible when the events below move it forward
+    #[cfg(desktop)]
+    {
+        if let Err(e) = crate::window::bring_to_front(app, pending.window_label.as_deref()) {
+            log::warn!("Could not bring a window to the foreground: {}", e);
+        }
+    }
+
+    // Notify frontend of successful login
+    clear_auth_error(app);
+    telemetry::record(app, Metric::LoginSucceeded);
+    transition(app, AuthState::authenticated(session.clone()));
+    emit_auth_success(app, &session, return_to);
+
+    log::debug!("auth-success event emitted");
+
+    Ok(())
+}
+
+/// Save a session that just signed in, undoing only that account if it doesn't stick
+///
+/// An account already stored for the same email is merged with it rather than kept as a
+/// duplicate. Other accounts, and which one was active, are left as they were.
+fn save_signed_in_session(
+    session: &UserSession,
+) -> Result<Option<AccountMerge>, Box<dyn std::error::Error>> {
+    let before = {
+        let _guard = lock_store();
+        read_store()
+    };
+    let merge = before
+        .as_ref()
+        .ok()
+        .and_then(|store| store.duplicate_of(session))
+        .map(|existing| AccountMerge::between(existing, session));
+
+    let result = match &merge {
+        None => save_session_verified(session),
+        Some(merge) => save_session_verified(&merge.kept)
+            .and_then(|()| remove_inactive_account(&merge.removed_user_id).map(|_| ())),
+    };
+    // An unreadable store means save_session wrote nothing, so there's nothing to undo
+    if let (Err(_), Ok(before)) = (&result, &before) {
+        let mut user_ids = vec![session.user_id.as_str()];
+        user_ids.extend(merge.as_ref().map(|merge| merge.removed_user_id.as_str()));
+        if let Err(e) = undo_account_save(before, &user_ids) {
+            log::warn!("Could not roll back the failed save: {}", e);
+        }
+    }
+    result.map(|()| merge)
+}
+
+/// Emit account-merged for a login that was folded into another account
+///
+/// Returns the session that ended up signed in, which is the kept one.
+fn report_merge<R: Runtime>(
+    app: &AppHandle<R>,
+    merge: Option<AccountMerge>,
+    session: UserSession,
+) -> UserSession {
+    let Some(merge) = merge else {
+        return session;
+    };
+    log::info!(
+        "Merged account {} into {} (same email)",
+        merge.removed_user_id,
+        merge.kept.user_id
+    );
+    emit_logged(
+        app,
+        "account-merged",
+        AccountMergedPayload {
+            user_id: &merge.kept.user_id,
+            removed_user_id: &merge.removed_user_id,
+        },
+    );
+    merge.kept
+}
+
+/// Put accounts back as they were in `before` after a failed save of them
+///
+/// Their previous sessions are restored (or they're removed if new), as is the previously
+/// active account; every other account, including ones saved since, is left alone.
+fn undo_account_save(
+    before: &SessionStore,
+    user_ids: &[&str],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _guard = lock_store();
+    let mut store = read_store()?;
+    for &user_id in user_ids {
+        match before.accounts.get(user_id) {
+            Some(previous) => store.accounts.insert(user_id.to_string(), previous.clone()),
+            None => store.accounts.remove(user_id),
+        };
+    }
+    store.active = before
+        .active
+        .clone()
+        .filter(|id| store.accounts.contains_key(id));
+    save_store(&store)
+}
+
+/// Emit auth-error and remember it for last_auth_error
+///
+/// Messages never include the session token, only what went wrong.
+pub(crate) fn report_auth_error<R: Runtime>(app: &AppHandle<R>, message: String) {
+    let report = AuthErrorReport {
+        message,
+        occurred_at: now_secs(),
+    };
+    telemetry::record(app, Metric::LoginFailed);
+    let payload = AuthErrorPayload {
+        message: &report.message,
+        platform: BuildInfo::current(app),
+    };
+    let fallback = serde_json::json!({ "message": report.message });
+    emit_critical(app, "auth-error", payload, fallback);
+    // A failed re-login while still signed in is rejected here and leaves the session alone
+    transition(app, AuthState::failed(report.message.clone()));
+    *app.state::<LastAuthError>().0.lock().unwrap() = Some(report);
+}
+
+/// The most recent login failure, unless a login has succeeded since
+pub fn last_auth_error<R: Runtime>(app: &AppHandle<R>) -> Option<AuthErrorReport> {
+    app.state::<LastAuthError>().0.lock().unwrap().clone()
+}
+
+/// Forget the last login failure (also done by every successful login)
+pub fn clear_auth_error<R: Runtime>(app: &AppHandle<R>) {
+    *app.state::<LastAuthError>().0.lock().unwrap() = None;
+}
+
+/// Payload of the auth-error event
+#[derive(Clone, serde::Serialize)]
+struct AuthErrorPayload<'a> {
+    message: &'a str,
+    platform: BuildInfo,
+}
+
+/// Payload of the auth-success event
+#[derive(Clone, serde::Serialize)]
+struct AuthSuccessPayload<'a> {
+    user: AuthSuccessUser<'a>,
+    return_to: Option<String>,
+    platform: BuildInfo,
+}
+
+#[derive(Clone, serde::Serialize)]
+struct AuthSuccessUser<'a> {
+    id: &'a str,
+    email: &'a str,
+    first_name: Option<&'a str>,
+    last_name: Option<&'a str>,
+}
+
+/// Emit auth-success; falls back to just the user id so the UI still leaves the login screen
+fn emit_auth_success<R: Runtime>(
+    app: &AppHandle<R>,
+    session: &UserSession,
+    return_to: Option<String>,
+) {
+    let fallback = serde_json::json!({
+        "user": { "id": session.user_id },
+        "return_to": return_to,
+    });
+    let payload = AuthSuccessPayload {
+        user: AuthSuccessUser {
+            id: &session.user_id,
+            email: &session.email,
+            first_name: session.first_name.as_deref(),
+            last_name: session.last_name.as_deref(),
+        },
+        return_to,
+        platform: BuildInfo::current(app),
+    };
+    emit_critical(app, "auth-success", payload, fallback);
+}
+
+/// Make an authenticated API call for the frontend, so the webview never holds the token
+///
+/// `path` must resolve to the configured API base; anything that would leave it (absolute
+/// URLs, `//host`) is refused so this can't be pointed at other hosts. When the frontend
+/// passes a token handle it must still be live, which ties the call to the session it
+/// was issued for. `idempotency_key` is for repeating a write without applying it twice.
+pub async fn authenticated_request<R: Runtime>(
+    app: &AppHandle<R>,
+    handle: Option<&str>,
+    method: &str,
+    path: &str,
+    body: Option<&serde_json::Value>,
+    idempotency_key: Option<&str>,
+) -> Result<ApiResponse, ApiRequestError> {
+    if handle.is_some_and(|handle| !api::is_valid_token_handle(handle)) {
+        return Err(ApiRequestError::local(
+            "invalid_handle",
+            "Invalid or expired token handle",
+        ));
+    }
+    if idempotency_key.is_some_and(|key| !api::is_valid_idempotency_key(key)) {
+        return Err(ApiRequestError::local(
+            "invalid_idempotency_key",
+            "Invalid idempotency key",
+        ));
+    }
+    if !is_api_path(path) {
+        return Err(ApiRequestError::local(
+            "invalid_path",
+            format!("Not an API path: {}", path),
+        ));
+    }
+    let method = match method.to_ascii_uppercase().as_str() {
+        "GET" => reqwest::Method::GET,
+        "POST" => reqwest::Method::POST,
+        "PUT" => reqwest::Method::PUT,
+        "PATCH" => reqwest::Method::PATCH,
+        "DELETE" => reqwest::Method::DELETE,
+        _ => {
+            return Err(ApiRequestError::local(
+                "unsupported_method",
+                format!("Unsupported method: {}", method),
+            ))
+        }
+    };
+
+    let session = get_fresh_session(app)
+        .await
+        .map_err(|e| ApiRequestError::local(e.code(), format!("Failed to get session: {}", e)))?
+        .ok_or_else(|| ApiRequestError::local("not_signed_in", "Not signed in"))?;
+
+    api::request(
+        method,
+        path,
+        Credentials::for_session(&session),
+        body,
+        idempotency_key,
+    )
+    .await
+    .map_err(|e| ApiRequestError {
+        code: e.code(),
+        status: e.status(),
+        message: e.to_string(),
+        body: e.body().cloned(),
+    })
+}
+
+/// Whether `path` is a path on the API base that can't be bent into another origin
+fn is_api_path(path: &str) -> bool {
+    if !path.starts_with('/')
+        || path.starts_with("//")
+        || path.contains('\\')
+        || path.contains("://")
+    {
+        return false;
+    }
+    let base = get_api_base_url();
+    match (
+        url::Url::parse(&base),
+        url::Url::parse(&format!("{}{}", base, path)),
+    ) {
+        (Ok(base), Ok(url)) => url.origin() == base.origin(),
+        _ => false,
+    }
+}
+
+/// Whether pasting a session token for manual recovery is enabled
+pub(crate) fn is_session_import_enabled() -> bool {
+    std::env::var("ADDIE_ALLOW_SESSION_IMPORT").is_ok_and(|v| v == "1")
+}
+
+/// Sign in with a sealed session pasted by the user (support-guided recovery)
+///
+/// The token is only saved once the server confirms who it belongs to.
+pub async fn import_session_token<R: Runtime>(
+    app: &AppHandle<R>,
+    token: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !is_session_import_enabled() {
+        return Err("Session import is disabled".into());
+    }
+
+    let session = validate_imported_token(token).await?;
+    let session = match save_signed_in_session(&session) {
+        Ok(merge) => report_merge(app, merge, session),
+        Err(e) => {
+            report_auth_error(app, format!("Failed to save session: {}", e));
+            return Err(format!("Failed to save session: {}", e).into());
+        }
+    };
+
+    log::info!("Imported session for user: {}", session.user_id);
+    clear_auth_error(app);
+    telemetry::record(app, Metric::LoginSucceeded);
+    transition(app, AuthState::authenticated(session.clone()));
+    emit_auth_success(app, &session, None);
+
+    Ok(())
+}
+
+/// Check a pasted session token and build the session it belongs to
+///
+/// Rejects malformed and (for JWTs) expired tokens before asking the server; the user
+/// comes from `/auth/me`, never from the token.
+async fn validate_imported_token(token: &str) -> Result<UserSession, Box<dyn std::error::Error>> {
+    let token = token.trim();
+    if token.is_empty() {
+        return Err("Invalid session token".into());
+    }
+    if token.len() > MAX_SEALED_SESSION_LEN {
+        return Err("Session token too large".into());
+    }
+    if token.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("Invalid session token".into());
+    }
+
+    // Only JWT-shaped tokens carry an expiry we can check locally
+    let exp = decode_displayable_claims(token).and_then(|claims| claims.get("exp")?.as_u64());
+    if exp.is_some_and(|exp| exp <= now_secs()) {
+        return Err("Session token has expired".into());
+    }
+
+    let me = api::get_me(Credentials::bearer(token))
+        .await
+        .map_err(|e| format!("Server rejected session token: {}", e))?;
+
+    Ok(UserSession {
+        sealed_session: token.to_string(),
+        user_id: me.id,
+        email: me.email,
+        first_name: me.first_name.as_deref().map(cap_display_name),
+        last_name: me.last_name.as_deref().map(cap_display_name),
+        created_at: Some(now_secs()),
+        max_session_age: None,
+        token_type: crate::DEFAULT_TOKEN_TYPE.to_string(),
+        scopes: Vec::new(),
+        schema_version: crate::SESSION_SCHEMA_VERSION,
+        expires_at: exp,
+        refresh_token: None,
+    })
+}
+
+/// Check the stored session against the server's view of it
+///
+/// A session the server reports as expired or revoked is logged out locally.
+pub async fn verify_session<R: Runtime>(
+    app: &AppHandle<R>,
+) -> Result<SessionVerification, Box<dyn std::error::Error>> {
+    let session = match get_session()? {
+        Some(session) => session,
+        None => return Ok(SessionVerification::default()),
+    };
+
+    let me = api::get_me(Credentials::for_session(&session)).await;
+    let status = verification_status(&me, &session.user_id);
+    if status.expired {
+        force_logout_local(app, LogoutReason::Expired, None)?;
+    } else if status.revoked {
+        force_logout_local(app, LogoutReason::Revoked, None)?;
+    }
+    match me {
+        Ok(_) if !status.server_user_matches_local => {
+            log::warn!("Server returned a different user than the stored session");
+        }
+        // The server doesn't know the endpoint, which says nothing about the session
+        Err(ApiError::EndpointUnavailable { path }) => {
+            log::warn!("Session verification endpoint unavailable: {}", path);
+            emit_logged(
+                app,
+                "server-incompatible",
+                serde_json::json!({ "path": path }),
+            );
+        }
+        Err(e) if status.network_error => log::warn!("Session verification failed: {}", e),
+        _ => {}
+    }
+
+    Ok(status)
+}
+
+/// What a `/auth/me` answer says about the stored session of `local_user_id`
+///
+/// A 401 invalid_token means the session expired; any other 401 or a 403 means it was
+/// revoked. A different user than the local one means the token is stale.
+fn verification_status(
+    me: &Result<api::MeResponse, ApiError>,
+    local_user_id: &str,
+) -> SessionVerification {
+    let mut status = SessionVerification::default();
+    match me {
+        Ok(me) => {
+            status.server_user_matches_local = me.id == local_user_id;
+            status.valid = status.server_user_matches_local;
+        }
+        Err(ApiError::Unauthorized { code }) if code.as_deref() == Some("invalid_token") => {
+            status.expired = true;
+        }
+        Err(ApiError::Unauthorized { .. }) | Err(ApiError::Forbidden { .. }) => {
+            status.revoked = true;
+        }
+        Err(ApiError::EndpointUnavailable { .. }) => status.server_incompatible = true,
+        Err(_) => status.network_error = true,
+    }
+    status
+}
+
+/// Resolve and create the app's data directory; must run in setup before anything is stored
+///
+/// Files an older version kept in $HOME are moved across under the same names and
+/// formats, so existing users stay signed in after upgrading. If there's no usable data
+/// directory the app still starts, with the session kept in memory for this run.
+pub fn init_data_dir<R: Runtime>(app: &AppHandle<R>) {
+    let dir = match app.path().app_data_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            log::error!(
+                "No app data directory ({}), keeping the session in memory only",
+                e
+            );
+            set_storage_readonly();
+            return;
+        }
+    };
+    match std::fs::create_dir_all(&dir) {
+        Ok(()) => migrate_legacy_data_files(&dir),
+        // Reads of anything already there may still work; writes will fail and say why
+        Err(e) => {
+            log::error!(
+                "Could not create data directory {:?} ({}), keeping the session in memory only",
+                dir,
+                e
+            );
+            set_storage_readonly();
+        }
+    }
+    let _ = DATA_DIR.set(dir);
+}
+
+/// Move per-install files from $HOME (where versions before the app-data dir kept them)
+fn migrate_legacy_data_files(dir: &std::path::Path) {
+    let Some(home) = std::env::var_os("HOME").map(std::path::PathBuf::from) else {
+        return;
+    };
+    if home == dir {
+        return;
+    }
+
+    for name in LEGACY_DATA_FILES {
+        let (from, to) = (home.join(name), dir.join(name));
+        if !from.exists() || to.exists() {
+            continue;
+        }
+        match std::fs::rename(&from, &to) {
+            Ok(()) => log::info!("Moved {} into the app data directory", name),
+            Err(e) => log::warn!("Could not move {} into the app data directory: {}", name, e),
+        }
+    }
+}
+
+/// Path of a file in the app's data directory, or NotFound if there is none
+pub(crate) fn data_file_path(file_name: &str) -> std::io::Result<std::path::PathBuf> {
+    #[cfg(test)]
+    if let Some(dir) = temp_data_dir::get() {
+        return Ok(dir.join(file_name));
+    }
+    DATA_DIR
+        .get()
+        .map(|dir| dir.join(file_name))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no app data directory"))
+}
+
+/// Get session file path
+fn get_session_file_path() -> std::io::Result<std::path::PathBuf> {
+    data_file_path(".addie-session.json")
+}
+
+/// Remember how a storage operation went; the error text is about I/O, never the session
+fn record_storage_outcome<T>(
+    slot: &Mutex<Option<StorageOutcome>>,
+    result: &Result<T, Box<dyn std::error::Error>>,
+) {
+    *slot.lock().unwrap() = Some(StorageOutcome {
+        ok: result.is_ok(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+        at: now_secs(),
+    });
+}
+
+/// Save the session as the active account, in the keychain or (if unavailable) the session file
+///
+/// Other signed-in accounts are kept; a session for an account already stored replaces it.
+/// Expects the store lock to be held; save_session_verified takes it.
+fn save_session(session: &UserSession) -> Result<(), Box<dyn std::error::Error>> {
+    // Starting a new store here would overwrite every other account; an invalid store was
+    // already quarantined by read_store, so this only fails while storage is unreadable
+    let mut store =
+        read_store().map_err(|e| format!("Could not read session store to save into: {}", e))?;
+    store.upsert_active(session.clone());
+    save_store(&store)
+}
+
+fn save_store(store: &SessionStore) -> Result<(), Box<dyn std::error::Error>> {
+    // A label goes with its account
+    let result = if store
+        .labels
+        .keys()
+        .all(|id| store.accounts.contains_key(id))
+    {
+        write_store(store)
+    } else {
+        let mut store = store.clone();
+        let accounts = &store.accounts;
+        store.labels.retain(|id, _| accounts.contains_key(id));
+        write_store(&store)
+    };
+    record_storage_outcome(last_session_write(), &result);
+    result
+}
+
+fn keychain_entry() -> keyring::Result<Entry> {
+    Entry::new(KEYRING_SERVICE, KEYRING_USER)
+}
+
+/// Attempts made at a keychain call before giving up on a locked or busy keychain
+const KEYCHAIN_ATTEMPTS: u32 = 3;
+
+/// Wait before the first keychain retry; doubles with each attempt
+const KEYCHAIN_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// Why a keychain call failed, as far as it matters for what we do next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeychainFailure {
+    /// Locked, or not allowed to prompt right now; the session is there once it's unlocked
+    Locked,
+    /// Busy or timed out, e.g. just after wake; usually gone on the next try
+    Transient,
+    /// No usable keychain at all, so the session file is the store
+    Unavailable,
+}
+
+impl KeychainFailure {
+    fn of(error: &keyring::Error) -> Self {
+        let text = match error {
+            keyring::Error::NoStorageAccess(e) | keyring::Error::PlatformFailure(e) => {
+                e.to_string().to_lowercase()
+            }
+            _ => return KeychainFailure::Unavailable,
+        };
+        // macOS reports a locked keychain as errSecInteractionNotAllowed (-25308)
+        if text.contains("lock")
+            || text.contains("interaction is not allowed")
+            || text.contains("-25308")
+        {
+            KeychainFailure::Locked
+        } else if ["busy", "timed out", "timeout", "temporarily", "try again"]
+            .iter()
+            .any(|marker| text.contains(marker))
+        {
+            KeychainFailure::Transient
+        } else {
+            KeychainFailure::Unavailable
+        }
+    }
+}
+
+/// The session is in a keychain that's locked; reported as `keychain-locked`, never as a logout
+#[derive(Debug)]
+pub(crate) struct KeychainLocked;
+
+impl std::fmt::Display for KeychainLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The keychain is locked")
+    }
+}
+
+impl std::error::Error for KeychainLocked {}
+
+/// Run a keychain call, retrying with backoff while the keychain is locked or busy
+fn retry_keychain<T>(mut call: impl FnMut() -> keyring::Result<T>) -> keyring::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match call() {
+            Err(e)
+                if attempt < KEYCHAIN_ATTEMPTS
+                    && KeychainFailure::of(&e) != KeychainFailure::Unavailable =>
+            {
+                log::warn!(
+                    "Keychain call failed ({}), retrying (attempt {} of {})",
+                    e,
+                    attempt,
+                    KEYCHAIN_ATTEMPTS
+                );
+                std::thread::sleep(KEYCHAIN_RETRY_DELAY * 2u32.pow(attempt - 1));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn keychain_get_password() -> keyring::Result<String> {
+    retry_keychain(keychain_get_password_once)
+}
+
+fn keychain_get_password_once() -> keyring::Result<String> {
+    #[cfg(test)]
+    if let Some(result) = fake_keychain::get_password() {
+        return result;
+    }
+    keychain_entry()?.get_password()
+}
+
+fn keychain_set_password(json: &str) -> keyring::Result<()> {
+    retry_keychain(|| keychain_entry()?.set_password(json))
+}
+
+fn write_store(store: &SessionStore) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(test)]
+    if let Some(result) = fake_store::write(store) {
+        return result;
+    }
+    if uses_memory_store() {
+        with_memory_store(|memory| *memory = Some(store.clone()));
+        return Ok(());
+    }
+
+    let json = serde_json::to_string(store)?;
+    match keychain_set_password(&json) {
+        Ok(()) => {
+            log::debug!("Session saved to keychain");
+            *last_session_store().lock().unwrap() = Some("keychain");
+            remove_plaintext_session_file();
+            return Ok(());
+        }
+        // A file written now would be shadowed by whatever the keychain holds once unlocked
+        Err(e) if KeychainFailure::of(&e) == KeychainFailure::Locked => {
+            log::warn!("Keychain write failed, keychain is locked: {}", e);
+            return Err(KeychainLocked.into());
+        }
+        // e.g. unsigned debug builds on macOS, or no Secret Service on Linux
+        Err(e) => {
+            log::warn!(
+                "Keychain write failed ({}), falling back to session file",
+                e
+            );
+            // An older session left in the keychain would shadow the file on the next read
+            let _ = keychain_entry().and_then(|entry| entry.delete_credential());
+        }
+    }
+
+    let path = get_session_file_path()?;
+    if let Err(e) = write_private_file(&path, json.as_bytes()) {
+        return keep_in_memory_if_unwritable(e, store);
+    }
+    log::info!("Session saved to file (keychain fallback): {:?}", path);
+    *last_session_store().lock().unwrap() = Some("file");
+    Ok(())
+}
+
+/// Handle a failed session file write: a read-only data dir moves the session to memory
+fn keep_in_memory_if_unwritable(
+    e: std::io::Error,
+    store: &SessionStore,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match e.kind() {
+        // Locked-down images: keep the user logged in for this run rather than failing login
+        std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::ReadOnlyFilesystem => {
+            log::warn!(
+                "Session file not writable ({}), keeping session in memory only",
+                e
+            );
+            set_storage_readonly();
+            with_memory_store(|memory| *memory = Some(store.clone()));
+            Ok(())
+        }
+        std::io::ErrorKind::StorageFull => Err("Disk full, could not save session".into()),
+        _ => Err(e.into()),
+    }
+}
+
+/// Write a file only the current user can read, replacing `path` in one step
+///
+/// On Unix the data goes to a temp file created 0600 in the same directory, which is then
+/// renamed over `path`, so the final path never exists with looser permissions. On Windows
+/// the app data dir already sits in the user's profile, whose ACL excludes other users, so
+/// this is a plain write-and-rename there.
+pub(crate) fn write_private_file(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".tmp-{}", std::process::id()));
+    let tmp = path.with_file_name(tmp_name);
+
+    // A leftover temp file would keep its old permissions, so always create a fresh one
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let result = options
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Delete the session file once the keychain holds the session, so no plaintext copy lingers
+fn remove_plaintext_session_file() {
+    let Ok(path) = get_session_file_path() else {
+        return;
+    };
+    if path.exists() {
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                log::info!("Removed plaintext session file now that the keychain holds the session")
+            }
+            Err(e) => log::warn!("Could not remove plaintext session file {:?}: {}", path, e),
+        }
+    }
+}
+
+/// Save a session and read it back to confirm the write is durable
+///
+/// Both happen under one hold of the store lock, so a concurrent account switch can't
+/// land in between and make a good write look lost.
+fn save_session_verified(session: &UserSession) -> Result<(), Box<dyn std::error::Error>> {
+    let _guard = lock_store();
+    save_session(session)?;
+    verify_active_session(session)
+}
+
+/// Check that `session` reads back as the active one; expects the store lock to be held
+fn verify_active_session(session: &UserSession) -> Result<(), Box<dyn std::error::Error>> {
+    let stored = read_store().map(|store| store.active_session());
+    record_storage_outcome(last_session_read(), &stored);
+    match stored? {
+        Some(stored) if stored == *session => Ok(()),
+        Some(_) => Err("Stored session does not match what was written".into()),
+        None => Err("Session missing after write".into()),
+    }
+}
+
+/// Store a refreshed session, unless the account stopped holding the one it came from
+///
+/// Checked and written under one hold of the store lock, so a logout, account switch or
+/// new login while the refresh was in flight is never undone. `generation` is
+/// api::current_generation from before the refresh; a logout or switch since bumped it,
+/// even one whose account came back with the same session. Returns whether it was saved.
+fn save_refreshed_session(
+    refreshed: &UserSession,
+    refreshed_from: &str,
+    generation: u64,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let _guard = lock_store();
+    if api::current_generation() != generation {
+        return Ok(false);
+    }
+    let still_active = read_store()?.active_session().is_some_and(|stored| {
+        stored.user_id == refreshed.user_id && stored.sealed_session == refreshed_from
+    });
+    if !still_active {
+        return Ok(false);
+    }
+    save_session(refreshed)?;
+    verify_active_session(refreshed)?;
+    Ok(true)
+}
+
+/// Get the active account's session from the keychain, falling back to the session file
+pub fn get_session() -> Result<Option<UserSession>, Box<dyn std::error::Error>> {
+    let _guard = lock_store();
+    let result = read_store().map(|store| store.active_session());
+    record_storage_outcome(last_session_read(), &result);
+    result
+}
+
+fn read_store() -> Result<SessionStore, Box<dyn std::error::Error>> {
+    #[cfg(test)]
+    if let Some(result) = fake_store::read() {
+        return result;
+    }
+    if uses_memory_store() {
+        return Ok(with_memory_store(|memory| {
+            memory.clone().unwrap_or_default()
+        }));
+    }
+
+    let keychain_reachable = match keychain_get_password() {
+        Ok(json) => {
+            let store = match SessionStore::parse(json.as_bytes()) {
+                Ok(store) => store,
+                Err(e) => {
+                    return quarantine_session(json.as_bytes(), &e.to_string(), true, || {
+                        Ok(keychain_entry()?.delete_credential()?)
+                    })
+                }
+            };
+            log::debug!("Session loaded from keychain");
+            *last_session_store().lock().unwrap() = Some("keychain");
+            return Ok(store);
+        }
+        Err(keyring::Error::NoEntry) => true,
+        // The session is most likely in there: reading the file instead would look like a logout
+        Err(e) => match KeychainFailure::of(&e) {
+            KeychainFailure::Locked => {
+                log::warn!("Keychain read failed, keychain is locked: {}", e);
+                return Err(KeychainLocked.into());
+            }
+            KeychainFailure::Transient => {
+                return Err(format!("Keychain still unavailable after retries: {}", e).into())
+            }
+            KeychainFailure::Unavailable => {
+                log::warn!("Keychain read failed ({}), falling back to session file", e);
+                false
+            }
+        },
+    };
+
+    let path = get_session_file_path()?;
+    if path.exists() {
+        let bytes = std::fs::read(&path)?;
+        let store = match SessionStore::parse(&bytes) {
+            Ok(store) => store,
+            Err(e) => {
+                return quarantine_session(&bytes, &e.to_string(), false, || {
+                    Ok(std::fs::remove_file(&path)?)
+                })
+            }
+        };
+        log::info!(
+            "Session loaded from file (keychain {})",
+            if keychain_reachable {
+                "empty"
+            } else {
+                "unavailable"
+            }
+        );
+        *last_session_store().lock().unwrap() = Some("file");
+
+        // Sessions saved before the keychain was used: move them there now, in the current layout
+        if keychain_reachable {
+            let json = serde_json::to_string(&store)?;
+            match keychain_set_password(&json) {
+                Ok(()) => {
+                    log::info!("Moved session from file to keychain");
+                    *last_session_store().lock().unwrap() = Some("keychain");
+                    remove_plaintext_session_file();
+                }
+                Err(e) => log::warn!("Could not move session to keychain, keeping file: {}", e),
+            }
+        }
+        return Ok(store);
+    }
+    Ok(SessionStore::default())
+}
+
+/// Quarantine a stored session that failed its integrity check, leaving us logged out
+///
+/// `remove` deletes it from the store it came from. If it can't be quarantined it stays
+/// put and the read error is returned, so nothing is ever lost silently.
+fn quarantine_session(
+    bytes: &[u8],
+    reason: &str,
+    from_keychain: bool,
+    remove: impl FnOnce() -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<SessionStore, Box<dyn std::error::Error>> {
+    // Keychain contents hold every account's secrets; only their size and hash leave it
+    let id = quarantine::quarantine(bytes, reason, !from_keychain).map_err(|e| {
+        format!(
+            "Stored session is invalid ({}) and could not be quarantined: {}",
+            reason, e
+        )
+    })?;
+    remove()?;
+    log::error!(
+        "Stored session failed integrity check ({}), quarantined as {}",
+        reason,
+        id
+    );
+    // read_store has no app to emit with; get_valid_session reports the logout
+    replace_unreported_quarantine(Some(id));
+    Ok(SessionStore::default())
+}
+
+/// Every account with a stored session, whether or not it's the active one
+pub fn list_accounts() -> Result<Vec<UserInfo>, Box<dyn std::error::Error>> {
+    let _guard = lock_store();
+    let mut store = read_store()?;
+    Ok(store
+        .accounts
+        .into_values()
+        .filter_map(|session| {
+            let label = store.labels.remove(&session.user_id).unwrap_or_default();
+            AuthState::authenticated(session).user.map(|user| UserInfo {
+                label: label.label,
+                color: label.color,
+                ..user
+            })
+        })
+        .collect())
+}
+
+/// Add imported sessions to the account store in one write, returning their users
+///
+/// An account already stored gets the imported session. The active account stays as it
+/// is; if none is active, the first imported one becomes active and the UI is told.
+pub(crate) fn add_imported_accounts<R: Runtime>(
+    app: &AppHandle<R>,
+    sessions: Vec<UserSession>,
+) -> Result<Vec<UserInfo>, AuthError> {
+    let activated = {
+        let _guard = lock_store();
+        let mut store = read_store().map_err(AuthError::storage)?;
+        let activated = match &store.active {
+            Some(_) => None,
+            None => sessions.first().cloned(),
+        };
+        for session in &sessions {
+            store
+                .accounts
+                .insert(session.user_id.clone(), session.clone());
+        }
+        if let Some(session) = &activated {
+            store.active = Some(session.user_id.clone());
+        }
+        save_store(&store).map_err(AuthError::storage)?;
+        activated
+    };
+
+    if let Some(session) = activated {
+        transition(app, AuthState::authenticated(session));
+    }
+    Ok(sessions
+        .into_iter()
+        .filter_map(|session| AuthState::authenticated(session).user)
+        .collect())
+}
+
+/// Longest account label, in characters
+const MAX_ACCOUNT_LABEL_CHARS: usize = 32;
+
+/// A picker label, trimmed; blank clears it
+fn account_label(label: Option<String>) -> Result<Option<String>, AuthError> {
+    let Some(label) = label.map(|label| label.trim().to_string()) else {
+        return Ok(None);
+    };
+    if label.chars().count() > MAX_ACCOUNT_LABEL_CHARS || label.chars().any(char::is_control) {
+        return Err(AuthError::InvalidParam("label"));
+    }
+    Ok(Some(label).filter(|label| !label.is_empty()))
+}
+
+/// A picker color as `#rgb` or `#rrggbb`, lowercased
+fn account_color(color: Option<String>) -> Result<Option<String>, AuthError> {
+    let Some(color) = color else {
+        return Ok(None);
+    };
+    let is_hex = color.strip_prefix('#').is_some_and(|hex| {
+        matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit())
+    });
+    if !is_hex {
+        return Err(AuthError::InvalidParam("color"));
+    }
+    Ok(Some(color.to_ascii_lowercase()))
+}
+
+/// Set how a stored account is shown in the account picker; None clears a field
+///
+/// Stored with the accounts and dropped when the account is removed.
+pub fn set_account_label(
+    user_id: &str,
+    label: Option<String>,
+    color: Option<String>,
+) -> Result<UserInfo, AuthError> {
+    let label = AccountLabel {
+        label: account_label(label)?,
+        color: account_color(color)?,
+    };
+
+    let _guard = lock_store();
+    let mut store = read_store().map_err(AuthError::storage)?;
+    let Some(session) = store.accounts.get(user_id).cloned() else {
+        return Err(AuthError::UnknownAccount(user_id.to_string()));
+    };
+    if label == AccountLabel::default() {
+        store.labels.remove(user_id);
+    } else {
+        store.labels.insert(user_id.to_string(), label.clone());
+    }
+    save_store(&store).map_err(AuthError::storage)?;
+
+    let user = AuthState::authenticated(session)
+        .user
+        .ok_or_else(|| AuthError::UnknownAccount(user_id.to_string()))?;
+    Ok(UserInfo {
+        label: label.label,
+        color: label.color,
+        ..user
+    })
+}
+
+/// Make another stored account the active one and emit auth-state-changed for it
+///
+/// Calls still in flight were made as the previous account, so they're cancelled.
+pub fn switch_account<R: Runtime>(
+    app: &AppHandle<R>,
+    user_id: &str,
+) -> Result<AuthState, AuthError> {
+    {
+        let _guard = lock_store();
+        let mut store = read_store().map_err(AuthError::storage)?;
+        if !store.accounts.contains_key(user_id) {
+            return Err(AuthError::UnknownAccount(user_id.to_string()));
+        }
+
+        if store.active.as_deref() != Some(user_id) {
+            api::cancel_in_flight();
+            store.active = Some(user_id.to_string());
+            save_store(&store).map_err(AuthError::storage)?;
+            log::info!("Switched active account to {}", user_id);
+        }
+    }
+
+    // Applies max age and expiry to the account we switched to
+    let state = match get_valid_session(app).map_err(AuthError::storage)? {
+        Some(session) => AuthState::authenticated(session),
+        None => signed_out_state(app),
+    };
+    transition(app, state.clone());
+    Ok(state)
+}
+
+/// Forget a stored account that isn't the active one; returns whether it was stored
+fn remove_inactive_account(user_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let _guard = lock_store();
+    let mut store = read_store()?;
+    if store.active.as_deref() == Some(user_id) || store.accounts.remove(user_id).is_none() {
+        return Ok(false);
+    }
+    save_store(&store)?;
+    Ok(true)
+}
+
+/// Whether a stored session can't be used again without a new login
+fn is_dead_session(session: &UserSession) -> bool {
+    exceeds_max_session_age(session)
+        || (is_token_expired(session) && session.refresh_token.is_none())
+}
+
+/// Forget stored accounts that can't be used without logging in again; returns their ids
+///
+/// The active account is left to get_valid_session, which logs it out with a reason. Safe
+/// to call at any time: nothing is written unless an account is removed.
+pub fn prune_accounts<R: Runtime>(app: &AppHandle<R>) -> Result<Vec<String>, AuthError> {
+    let removed = {
+        let _guard = lock_store();
+        let mut store = read_store().map_err(AuthError::storage)?;
+        let removed: Vec<String> = store
+            .accounts
+            .iter()
+            .filter(|(id, session)| store.active.as_ref() != Some(*id) && is_dead_session(session))
+            .map(|(id, _)| id.clone())
+            .collect();
+        if removed.is_empty() {
+            return Ok(removed);
+        }
+        for id in &removed {
+            store.accounts.remove(id);
+        }
+        save_store(&store).map_err(AuthError::storage)?;
+        removed
+    };
+
+    log::info!("Pruned {} account(s) that need a new login", removed.len());
+    emit_logged(app, "accounts-pruned", &removed);
+    Ok(removed)
+}
+
+/// Report on where sessions are stored and whether that storage is working
+///
+/// Only probes and outcomes; nothing read from the session itself is included.
+pub fn storage_health() -> StorageHealth {
+    // NoEntry means we could ask the keychain and it answered
+    let keychain_reachable = !is_headless()
+        && matches!(
+            keychain_get_password_once(),
+            Ok(_) | Err(keyring::Error::NoEntry)
+        );
+
+    let backend = if is_headless() {
+        "memory"
+    } else if is_storage_readonly() {
+        "memory_readonly_fallback"
+    } else {
+        let last_store = *last_session_store().lock().unwrap();
+        last_store.unwrap_or(if keychain_reachable {
+            "keychain"
+        } else {
+            "file"
+        })
+    };
+
+    StorageHealth {
+        backend,
+        keychain_reachable,
+        data_dir_writable: !is_headless() && is_data_dir_writable(),
+        // The OS keychain encrypts at rest; the fallback file is plain JSON
+        encrypted: backend == "keychain",
+        last_write: last_session_write().lock().unwrap().clone(),
+        last_read: last_session_read().lock().unwrap().clone(),
+    }
+}
+
+/// Whether a file can be created in the data directory right now
+fn is_data_dir_writable() -> bool {
+    let Ok(probe) = data_file_path(".addie-write-probe") else {
+        return false;
+    };
+    let writable = std::fs::write(&probe, b"").is_ok();
+    let _ = std::fs::remove_file(&probe);
+    writable
+}
+
+/// Decoded token claims, keyed by claim name
+pub type Claims = serde_json::Map<String, serde_json::Value>;
+
+/// JWT claims that are safe to show in diagnostics
+const DISPLAYABLE_CLAIMS: &[&str] = &["iss", "sub", "aud", "exp", "iat", "nbf", "org_id", "role"];
+
+/// Decode the non-secret claims of a JWT-shaped token without verifying it
+///
+/// Returns None for opaque tokens. Only claims in DISPLAYABLE_CLAIMS are kept.
+fn decode_displayable_claims(token: &str) -> Option<Claims> {
+    use base64::Engine;
+
+    let mut parts = token.split('.');
+    let (Some(_header), Some(payload), Some(_signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+
+    let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let serde_json::Value::Object(claims) = serde_json::from_slice(&bytes).ok()? else {
+        return None;
+    };
+
+    Some(
+        claims
+            .into_iter()
+            .filter(|(name, _)| DISPLAYABLE_CLAIMS.contains(&name.as_str()))
+            .collect(),
+    )
+}
+
+/// Non-secret claims of the stored session token, if it is a JWT
+pub fn session_claims() -> Result<Option<Claims>, Box<dyn std::error::Error>> {
+    Ok(get_session()?.and_then(|session| decode_displayable_claims(&session.sealed_session)))
+}
+
+/// Bring a session saved by an older version up to the current layout and re-save it
+///
+/// Missing fields were already defaulted by serde (Bearer token type, no scopes). A session
+/// without the identity we need is logged out instead. Returns None in that case.
+fn migrate_session<R: Runtime>(
+    app: &AppHandle<R>,
+    session: UserSession,
+) -> Result<Option<UserSession>, Box<dyn std::error::Error>> {
+    let from = session.schema_version;
+
+    if session.sealed_session.is_empty() || session.user_id.is_empty() {
+        log::info!(
+            "Stored session (schema {}) can't be migrated, forcing re-login",
+            from
+        );
+        force_logout_local(app, LogoutReason::SessionOutdated, None)?;
+        return Ok(None);
+    }
+
+    let migrated = UserSession {
+        schema_version: crate::SESSION_SCHEMA_VERSION,
+        ..session
+    };
+    save_session_verified(&migrated)?;
+
+    log::info!(
+        "Migrated stored session from schema {} to {}",
+        from,
+        migrated.schema_version
+    );
+    emit_logged(
+        app,
+        "session-migrated",
+        serde_json::json!({
+            "from": from,
+            "to": migrated.schema_version,
+        }),
+    );
+
+    Ok(Some(migrated))
+}
+
+/// Re-read the session from the store and emit auth-state-changed with what it holds
+///
+/// The session file is read on every access, so there's no cache to drop; this is for a
+/// UI that suspects its own state is stale. In memory-store mode memory is the store.
+pub fn reload_session_from_store<R: Runtime>(
+    app: &AppHandle<R>,
+) -> Result<AuthState, Box<dyn std::error::Error>> {
+    let state = match get_valid_session(app)? {
+        Some(session) => AuthState::authenticated(session),
+        None => signed_out_state(app),
+    };
+    transition(app, state.clone());
+    Ok(state)
+}
+
+/// Get the stored session, logging out if it has exceeded the maximum session age
+pub fn get_valid_session<R: Runtime>(
+    app: &AppHandle<R>,
+) -> Result<Option<UserSession>, Box<dyn std::error::Error>> {
+    let session = match get_session() {
+        Err(e) if e.is::<KeychainLocked>() => {
+            emit_logged(app, "keychain-locked", ());
+            return Err(e);
+        }
+        result => result?,
+    };
+    // The store was unreadable and set aside: that's a logout, so tell the frontend why
+    let quarantined = replace_unreported_quarantine(None);
+    if let Some(id) = quarantined {
+        force_logout_local(
+            app,
+            LogoutReason::Corrupted,
+            Some(format!("Quarantined as {}", id)),
+        )?;
+        return Ok(None);
+    }
+
+    let session = match session {
+        Some(session) if session.schema_version < crate::SESSION_SCHEMA_VERSION => {
+            match migrate_session(app, session)? {
+                Some(session) => session,
+                None => return Ok(None),
+            }
+        }
+        Some(session) => session,
+        None => return Ok(None),
+    };
+
+    if exceeds_max_session_age(&session) {
+        log::info!("Session exceeded maximum age, forcing re-login");
+        force_logout_local(app, LogoutReason::MaxSessionAge, None)?;
+        return Ok(None);
+    }
+
+    // Expired with nothing to refresh it with: report signed out rather than a dead session
+    if is_token_expired(&session) && session.refresh_token.is_none() {
+        log::info!("Session token expired, forcing re-login");
+        force_logout_local(app, LogoutReason::Expired, None)?;
+        return Ok(None);
+    }
+
+    Ok(Some(session))
+}
+
+/// Whether a refresh token is a plain opaque value we're willing to store
+fn is_valid_refresh_token(token: &str) -> bool {
+    !token.is_empty()
+        && token.len() <= MAX_REFRESH_TOKEN_LEN
+        && !token.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Whether the sealed session itself has expired (separate from the max-age floor)
+fn is_token_expired(session: &UserSession) -> bool {
+    session
+        .expires_at
+        .is_some_and(|expires_at| now_secs() >= expires_at)
+}
+
+/// Whether the session can and should be refreshed before its token is used
+fn needs_refresh(session: &UserSession) -> bool {
+    session.refresh_token.is_some()
+        && session
+            .expires_at
+            .is_some_and(|expires_at| now_secs().saturating_add(REFRESH_MARGIN_SECS) >= expires_at)
+}
+
+/// Held while refreshing: rotated refresh tokens are single-use, so refreshes mustn't race
+static REFRESH_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Token the frontend authenticates with: the sealed session, or with `handles` an opaque
+/// handle for authenticated_request, so the raw token never leaves the backend
+pub async fn session_token<R: Runtime>(
+    app: &AppHandle<R>,
+    handles: bool,
+) -> Result<Option<String>, AuthError> {
+    // Refreshed first when it's about to expire, so callers never get a token that's dying
+    Ok(get_fresh_session(app).await?.map(|session| {
+        if handles {
+            api::token_handle()
+        } else {
+            session.sealed_session
+        }
+    }))
+}
+
+/// The valid session, refreshed first if it expires within REFRESH_MARGIN_SECS
+///
+/// If the refresh fails for any reason but the server refusing it, the current session
+/// is still returned while its token hasn't actually expired.
+pub async fn get_fresh_session<R: Runtime>(
+    app: &AppHandle<R>,
+) -> Result<Option<UserSession>, AuthError> {
+    let session = match get_valid_session(app).map_err(AuthError::storage)? {
+        Some(session) if needs_refresh(&session) => session,
+        session => return Ok(session),
+    };
+
+    match refresh(app, false).await {
+        Ok(refreshed) => Ok(refreshed),
+        Err(e) if !is_token_expired(&session) => {
+            log::warn!("Session refresh failed, using current token: {}", e);
+            // Re-read rather than reuse our copy: a logout may be what cancelled the refresh
+            get_valid_session(app).map_err(AuthError::storage)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Exchange the refresh token for a new sealed session now, emitting auth-refreshed
+///
+/// Ok(None) when signed out, including when the server rejects the refresh token.
+pub async fn refresh_session<R: Runtime>(
+    app: &AppHandle<R>,
+) -> Result<Option<UserSession>, AuthError> {
+    refresh(app, true).await
+}
+
+async fn refresh<R: Runtime>(
+    app: &AppHandle<R>,
+    force: bool,
+) -> Result<Option<UserSession>, AuthError> {
+    let _guard = REFRESH_LOCK.lock().await;
+
+    // Re-read under the lock: whoever held it may have just refreshed
+    let session = match get_valid_session(app).map_err(AuthError::storage)? {
+        Some(session) if force || needs_refresh(&session) => session,
+        session => return Ok(session),
+    };
+    let refresh_token = session
+        .refresh_token
+        .clone()
+        .ok_or(AuthError::NoRefreshToken)?;
+
+    let generation = api::current_generation();
+    let response =
+        match api::refresh_session(Credentials::for_session(&session), &refresh_token).await {
+            Ok(response) => response,
+            Err(ApiError::Unauthorized { code }) => {
+                log::info!("Refresh token rejected, forcing re-login");
+                force_logout_local(app, LogoutReason::Expired, code).map_err(AuthError::storage)?;
+                return Ok(None);
+            }
+            // An older server without refresh: the session is still good until it expires
+            Err(ApiError::EndpointUnavailable { path }) => {
+                log::warn!("Session refresh endpoint unavailable: {}", path);
+                emit_logged(
+                    app,
+                    "server-incompatible",
+                    serde_json::json!({ "path": path }),
+                );
+                return Err(ApiError::EndpointUnavailable { path }.into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+    let refreshed_from = session.sealed_session.clone();
+    let refreshed = refreshed_session(session, response)?;
+    // The server may have rotated the refresh token, but until the new one reads back from
+    // the store the refresh failed: the stored session, old refresh token and all, is kept
+    let saved = save_refreshed_session(&refreshed, &refreshed_from, generation).map_err(|e| {
+        log::warn!("Refreshed session not saved, keeping the stored one: {}", e);
+        AuthError::storage(e)
+    })?;
+    // Logged out (or switched account) while the call was in flight: don't bring it back
+    if !saved {
+        log::info!("Account changed during refresh, discarding the refreshed session");
+        return get_valid_session(app).map_err(AuthError::storage);
+    }
+
+    log::info!("Session refreshed for user: {}", refreshed.user_id);
+    emit_logged(
+        app,
+        "auth-refreshed",
+        serde_json::json!({
+            "user_id": refreshed.user_id,
+            "expires_at": refreshed.expires_at,
+        }),
+    );
+    session_timer::reschedule(app);
+    Ok(Some(refreshed))
+}
+
+/// The session a successful /auth/refresh response replaces `session` with
+fn refreshed_session(
+    session: UserSession,
+    response: api::RefreshResponse,
+) -> Result<UserSession, AuthError> {
+    if response.sealed_session.is_empty() {
+        return Err(AuthError::MissingParam("sealed_session"));
+    }
+    if response.sealed_session.len() > MAX_SEALED_SESSION_LEN {
+        return Err(AuthError::TokenTooLarge);
+    }
+    Ok(UserSession {
+        sealed_session: response.sealed_session,
+        expires_at: response.expires_at,
+        // Servers that don't rotate keep the same refresh token valid
+        refresh_token: response
+            .refresh_token
+            .filter(|token| is_valid_refresh_token(token))
+            .or(session.refresh_token.clone()),
+        ..session
+    })
+}
+
+/// Accounts refresh_all_accounts checks at once
+const ACCOUNT_CHECK_CONCURRENCY: usize = 4;
+
+/// How long refresh_all_accounts waits on any one account
+const ACCOUNT_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// What the server said about one stored account
+enum AccountCheck {
+    Valid,
+    Refreshed(Box<UserSession>),
+    Dead(LogoutReason),
+    Unknown(String),
+}
+
+/// Check every stored account with the server, refreshing those close to expiring
+///
+/// A few accounts are checked at a time, each with its own timeout, so one slow account
+/// doesn't hold up the rest. Dead accounts are signed out: the active one is logged out
+/// with the reason, others are forgotten. Emits accounts-refreshed with the results.
+pub async fn refresh_all_accounts<R: Runtime>(
+    app: &AppHandle<R>,
+) -> Result<Vec<AccountStatus>, AuthError> {
+    // Rotated refresh tokens are single-use, so no other refresh may run alongside these
+    let _refreshing = REFRESH_LOCK.lock().await;
+    let sessions: Vec<UserSession> = {
+        let _guard = lock_store();
+        read_store()
+            .map_err(AuthError::storage)?
+            .accounts
+            .into_values()
+            .collect()
+    };
+
+    let permits = tokio::sync::Semaphore::new(ACCOUNT_CHECK_CONCURRENCY);
+    let checks = sessions
+        .iter()
+        .map(|session| async {
+            let _permit = permits.acquire().await;
+            tokio::time::timeout(ACCOUNT_CHECK_TIMEOUT, check_account(session))
+                .await
+                .unwrap_or_else(|_| AccountCheck::Unknown("Timed out".to_string()))
+        })
+        .collect();
+    let checks = join_all(checks).await;
+
+    let statuses: Vec<AccountStatus> = sessions
+        .iter()
+        .zip(checks)
+        .map(|(session, check)| AccountStatus {
+            user_id: session.user_id.clone(),
+            health: apply_account_check(app, session, check),
+        })
+        .collect();
+    emit_logged(app, "accounts-refreshed", &statuses);
+    Ok(statuses)
+}
+
+/// Ask the server about one account: a refresh if it's close to expiring, else /auth/me
+async fn check_account(session: &UserSession) -> AccountCheck {
+    let credentials = Credentials::for_session(session);
+    if let Some(refresh_token) = session
+        .refresh_token
+        .as_deref()
+        .filter(|_| needs_refresh(session))
+    {
+        return match api::refresh_session(credentials, refresh_token).await {
+            Ok(response) => match refreshed_session(session.clone(), response) {
+                Ok(refreshed) => AccountCheck::Refreshed(Box::new(refreshed)),
+                Err(e) => AccountCheck::Unknown(e.to_string()),
+            },
+            Err(ApiError::Unauthorized { .. }) => AccountCheck::Dead(LogoutReason::Expired),
+            Err(e) => AccountCheck::Unknown(e.to_string()),
+        };
+    }
+
+    let me = api::get_me(credentials).await;
+    let status = verification_status(&me, &session.user_id);
+    if status.valid {
+        AccountCheck::Valid
+    } else if status.expired {
+        AccountCheck::Dead(LogoutReason::Expired)
+    } else if status.revoked {
+        AccountCheck::Dead(LogoutReason::Revoked)
+    } else {
+        AccountCheck::Unknown(match me {
+            Ok(_) => "Server returned a different user".to_string(),
+            Err(e) => e.to_string(),
+        })
+    }
+}
+
+/// Store what check_account found out about `session`, returning the account's health
+fn apply_account_check<R: Runtime>(
+    app: &AppHandle<R>,
+    session: &UserSession,
+    check: AccountCheck,
+) -> AccountHealth {
+    let applied = match check {
+        AccountCheck::Valid => Ok(AccountHealth::Valid),
+        AccountCheck::Refreshed(refreshed) => {
+            update_stored_session(&refreshed, &session.sealed_session).map(|saved| {
+                if saved {
+                    AccountHealth::Refreshed
+                } else {
+                    AccountHealth::Unknown
+                }
+            })
+        }
+        AccountCheck::Dead(reason) => remove_dead_account(app, session, reason),
+        AccountCheck::Unknown(error) => {
+            log::warn!("Could not check account {}: {}", session.user_id, error);
+            Ok(AccountHealth::Unknown)
+        }
+    };
+    applied.unwrap_or_else(|e| {
+        log::warn!("Could not update account {}: {}", session.user_id, e);
+        AccountHealth::Unknown
+    })
+}
+
+/// Sign out an account the server no longer accepts, unless it has a new session since
+fn remove_dead_account<R: Runtime>(
+    app: &AppHandle<R>,
+    session: &UserSession,
+    reason: LogoutReason,
+) -> Result<AccountHealth, Box<dyn std::error::Error>> {
+    if remove_inactive_account(&session.user_id)? {
+        log::info!("Forgot account {} ({:?})", session.user_id, reason);
+        return Ok(AccountHealth::Dead);
+    }
+    let still_active = get_session()?.is_some_and(|stored| stored == *session);
+    if !still_active {
+        return Ok(AccountHealth::Unknown);
+    }
+    force_logout_local(app, reason, None)?;
+    Ok(AccountHealth::Dead)
+}
+
+/// Replace one stored account's session with its refreshed one, active or not
+///
+/// Skipped, returning false, if the account no longer holds the session it came from.
+/// Like save_refreshed_session, only done once the refreshed session reads back.
+fn update_stored_session(
+    refreshed: &UserSession,
+    refreshed_from: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let _guard = lock_store();
+    let mut store = read_store()?;
+    match store.accounts.get_mut(&refreshed.user_id) {
+        Some(stored) if stored.sealed_session == refreshed_from => *stored = refreshed.clone(),
+        _ => return Ok(false),
+    }
+    save_store(&store)?;
+    match read_store()?.accounts.get(&refreshed.user_id) {
+        Some(stored) if stored == refreshed => Ok(true),
+        _ => Err("Refreshed session missing after write".into()),
+    }
+}
+
+/// Run futures concurrently on the current task and collect their outputs in order
+///
+/// Staying on this task keeps the calls under the caller's thread-local state, which is
+/// what the unit-test seams rely on.
+async fn join_all<F: std::future::Future>(futures: Vec<F>) -> Vec<F::Output> {
+    let mut futures: Vec<_> = futures.into_iter().map(Box::pin).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    std::future::poll_fn(|cx| {
+        let mut pending = false;
+        for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+            if output.is_none() {
+                match future.as_mut().poll(cx) {
+                    std::task::Poll::Ready(value) => *output = Some(value),
+                    std::task::Poll::Pending => pending = true,
+                }
+            }
+        }
+        if pending {
+            std::task::Poll::Pending
+        } else {
+            std::task::Poll::Ready(())
+        }
+    })
+    .await;
+    outputs.into_iter().flatten().collect()
+}
+
+/// Drop the local session and emit `logged-out` with the reason
+///
+/// Every path that signs the user out goes through here, so the UI reacts to a single
+/// event. Returns the id of the user that was signed in, if any.
+pub fn force_logout_local<R: Runtime>(
+    app: &AppHandle<R>,
+    reason: LogoutReason,
+    detail: Option<String>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let (user_id, cleared) = end_local_session(app, reason, detail, false);
+    if !cleared.local_cleared() {
+        return Err(cleared.errors.join("; ").into());
+    }
+    Ok(user_id)
+}
+
+/// User-requested logout of the active account (or, with `all`, every stored account):
+/// clear locally, then revoke server-side
+///
+/// The local clear comes first so an offline logout is immediate; revocation is tried
+/// once per account with a short timeout, and a failure is reported in the outcome.
+pub async fn logout<R: Runtime>(app: &AppHandle<R>, all: bool) -> LogoutOutcome {
+    let mut errors = Vec::new();
+    let sessions: Vec<UserSession> = {
+        let _guard = lock_store();
+        match read_store() {
+            Ok(store) if all => store.accounts.into_values().collect(),
+            Ok(store) => store.active_session().into_iter().collect(),
+            Err(e) => {
+                errors.push(format!("Could not read session: {}", e));
+                Vec::new()
+            }
+        }
+    };
+
+    // Logging out twice is fine: the second finds nothing and says so
+    let already_logged_out = sessions.is_empty() && errors.is_empty();
+    let (_, cleared) = end_local_session(app, LogoutReason::UserRequested, None, all);
+    errors.extend(cleared.errors.iter().cloned());
+
+    let mut server_revoked = !sessions.is_empty();
+    for session in &sessions {
+        if let Err(e) = api::revoke_session(Credentials::for_session(session)).await {
+            server_revoked = false;
+            errors.push(format!(
+                "Server revocation failed for {}: {}",
+                session.user_id, e
+            ));
+        }
+    }
+
+    LogoutOutcome {
+        local_cleared: cleared.local_cleared(),
+        keychain_cleared: cleared.keychain_cleared,
+        file_cleared: cleared.file_cleared,
+        server_revoked,
+        already_logged_out,
+        errors,
+    }
+}
+
+/// Payload of the logged-out event
+#[derive(Clone, serde::Serialize)]
+struct LoggedOutPayload<'a> {
+    reason: LogoutReason,
+    user_id: Option<&'a str>,
+    detail: Option<&'a str>,
+    platform: BuildInfo,
+}
+
+/// Payload of the auth-logout event: who was logged out, or that every account was
+#[derive(Clone, serde::Serialize)]
+struct AuthLogoutPayload<'a> {
+    user_id: Option<&'a str>,
+    all: bool,
+}
+
+/// Clear the session from this device and announce it, returning who was logged out
+///
+/// logged-out is only emitted once the session is really gone locally. Other stored accounts
+/// are kept unless `all` is set.
+fn end_local_session<R: Runtime>(
+    app: &AppHandle<R>,
+    reason: LogoutReason,
+    detail: Option<String>,
+    all: bool,
+) -> (Option<String>, ClearedStorage) {
+    let user_id = get_session().ok().flatten().map(|session| session.user_id);
+    // Before clearing, so no refresh started now or already in flight can save over it
+    session_timer::stop(app);
+    api::cancel_in_flight();
+    let cleared = {
+        let _guard = lock_store();
+        if all {
+            clear_all_session_storage()
+        } else {
+            clear_session_storage()
+        }
+    };
+    if !cleared.local_cleared() {
+        return (user_id, cleared);
+    }
+
+    log::info!("Logged out ({:?})", reason);
+    telemetry::record(app, Metric::LoggedOut);
+    transition(app, AuthState::unauthenticated());
+    let fallback = serde_json::json!({ "reason": reason, "user_id": user_id });
+    let payload = LoggedOutPayload {
+        reason,
+        user_id: user_id.as_deref(),
+        detail: detail.as_deref(),
+        platform: BuildInfo::current(app),
+    };
+    emit_critical(app, "logged-out", payload, fallback);
+    // Every window listens for this, as for auth-success, so none keeps showing the old account
+    emit_logged(
+        app,
+        "auth-logout",
+        AuthLogoutPayload {
+            user_id: user_id.as_deref(),
+            all,
+        },
+    );
+
+    (user_id, cleared)
+}
+
+/// Which session stores clear_session_storage emptied
+struct ClearedStorage {
+    keychain_cleared: bool,
+    file_cleared: bool,
+    errors: Vec<String>,
+}
+
+impl ClearedStorage {
+    /// The session can't come back on this device: the keychain, the primary store, must be
+    /// cleared too (a read-only file is tolerated, as on save)
+    fn local_cleared(&self) -> bool {
+        self.keychain_cleared && (self.file_cleared || is_storage_readonly())
+    }
+}
+
+/// Remove the active account, keeping any others (which stay signed in but inactive)
+fn clear_session_storage() -> ClearedStorage {
+    let mut store = match read_store() {
+        Ok(store) => store,
+        // Can't tell what else is stored, and the session must go either way
+        Err(_) => return clear_all_session_storage(),
+    };
+    if let Some(active) = store.active.take() {
+        store.accounts.remove(&active);
+    }
+    if store.accounts.is_empty() {
+        return clear_all_session_storage();
+    }
+
+    match save_store(&store) {
+        Ok(()) => ClearedStorage {
+            keychain_cleared: true,
+            file_cleared: true,
+            errors: Vec::new(),
+        },
+        Err(e) => ClearedStorage {
+            keychain_cleared: false,
+            file_cleared: false,
+            errors: vec![format!("Could not remove the active account: {}", e)],
+        },
+    }
+}
+
+/// Remove every stored account
+fn clear_all_session_storage() -> ClearedStorage {
+    with_memory_store(|memory| *memory = None);
+    let mut cleared = ClearedStorage {
+        keychain_cleared: true,
+        file_cleared: true,
+        errors: Vec::new(),
+    };
+    #[cfg(test)]
+    if fake_store::clear().is_some() {
+        return cleared;
+    }
+    if is_headless() {
+        return cleared;
+    }
+
+    // Try keychain
+    let last_store = *last_session_store().lock().unwrap();
+    match keychain_entry().and_then(|entry| entry.delete_credential()) {
+        Ok(()) | Err(keyring::Error::NoEntry) => {}
+        // No keychain on this machine and the session was kept in the file: nothing to clear there
+        Err(e @ (keyring::Error::NoStorageAccess(_) | keyring::Error::PlatformFailure(_)))
+            if last_store != Some("keychain") =>
+        {
+            log::warn!(
+                "Keychain unavailable while clearing, session was in the file: {}",
+                e
+            );
+        }
+        Err(e) => {
+            cleared.keychain_cleared = false;
+            cleared
+                .errors
+                .push(format!("Could not clear keychain: {}", e));
+        }
+    }
+
+    // Also clear file (best effort when the data dir is read-only); already gone is fine,
+    // so logging out twice never fails
+    match get_session_file_path().and_then(std::fs::remove_file) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            if is_storage_readonly() {
+                log::warn!(
+                    "Could not remove session file from read-only storage: {}",
+                    e
+                );
+            }
+            cleared.file_cleared = false;
+            cleared
+                .errors
+                .push(format!("Could not remove session file: {}", e));
+        }
+    }
+
+    cleared
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::mock_api;
+
+    /// An auth callback URL for our scheme with the given query
+    fn callback_url(query: &str) -> String {
+        format!("{}://auth/callback?{}", crate::DEEP_LINK_SCHEME, query)
+    }
+
+    const VALID_QUERY: &str =
+        "sealed_session=sealed&user_id=user_1&email=a%40example.com&state=abc";
+
+    fn parsed_session(query: &str) -> UserSession {
+        match parse_callback(&callback_url(query)) {
+            Ok(Some(AuthCallback {
+                grant: CallbackGrant::Session(session),
+                ..
+            })) => *session,
+            other => panic!("expected a session, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_a_direct_session_callback() {
+        let session = parsed_session(VALID_QUERY);
+        assert_eq!(session.sealed_session, "sealed");
+        assert_eq!(session.user_id, "user_1");
+        assert_eq!(session.email, "a@example.com");
+        assert_eq!(session.token_type, crate::DEFAULT_TOKEN_TYPE);
+    }
+
+    #[test]
+    fn decodes_plus_and_percent_encoding() {
+        let session = parsed_session(
+            "sealed_session=sealed&user%5Fid=user_1&email=a%2Bb%40example.com&first_name=Mary+Ann&last_name=O%27Brien%20Smith",
+        );
+        assert_eq!(session.user_id, "user_1");
+        assert_eq!(session.email, "a+b@example.com");
+        assert_eq!(session.first_name.as_deref(), Some("Mary Ann"));
+        assert_eq!(session.last_name.as_deref(), Some("O'Brien Smith"));
+    }
+
+    #[test]
+    fn rejects_each_missing_required_param() {
+        for missing in ["sealed_session", "user_id", "email"] {
+            let query: Vec<&str> = VALID_QUERY
+                .split('&')
+                .filter(|pair| !pair.starts_with(&format!("{}=", missing)))
+                .collect();
+            let result = parse_callback(&callback_url(&query.join("&")));
+            assert!(
+                matches!(result, Err(AuthError::MissingParam(name)) if name == missing),
+                "missing {}: {:?}",
+                missing,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_each_empty_required_param() {
+        for empty in ["sealed_session", "user_id", "email"] {
+            let query = VALID_QUERY.replace(
+                &format!("{}=", empty),
+                &format!("{}=&ignored_{}=", empty, empty),
+            );
+            let result = parse_callback(&callback_url(&query));
+            assert!(
+                matches!(result, Err(AuthError::InvalidParam(name)) if name == empty),
+                "empty {}: {:?}",
+                empty,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_links_that_are_not_auth_callbacks() {
+        let scheme = crate::DEEP_LINK_SCHEME;
+        for url in [
+            format!("{}://campaign/123", scheme),
+            format!("{}://auth/force-logout?user_id=user_1", scheme),
+            format!("https://example.com/auth/callback?{}", VALID_QUERY),
+        ] {
+            assert!(matches!(parse_callback(&url), Ok(None)), "{}", url);
+        }
+    }
+
+    #[test]
+    fn rejects_unparseable_urls() {
+        assert!(matches!(
+            parse_callback("not a url"),
+            Err(AuthError::InvalidCallbackUrl(_))
+        ));
+    }
+
+    #[test]
+    fn parses_a_code_callback() {
+        let callback = parse_callback(&callback_url("code=abc-123_x.y~z&state=s"))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(callback.grant, CallbackGrant::Code(code) if code == "abc-123_x.y~z"));
+        assert_eq!(callback.state.as_deref(), Some("s"));
+        assert!(matches!(
+            parse_callback(&callback_url("code=&state=s")),
+            Err(AuthError::InvalidParam("code"))
+        ));
+    }
+
+    /// VALID_QUERY padded with a junk param so the whole URL is exactly `len` bytes
+    fn padded_callback(len: usize) -> String {
+        let url = callback_url(&format!("{}&pad=", VALID_QUERY));
+        format!("{}{}", url, "x".repeat(len - url.len()))
+    }
+
+    #[test]
+    fn accepts_a_callback_just_under_the_length_limit() {
+        let url = padded_callback(MAX_CALLBACK_URL_LEN);
+        assert_eq!(url.len(), MAX_CALLBACK_URL_LEN);
+        assert!(matches!(parse_callback(&url), Ok(Some(_))));
+    }
+
+    #[test]
+    fn rejects_a_callback_just_over_the_length_limit() {
+        let url = padded_callback(MAX_CALLBACK_URL_LEN + 1);
+        assert!(matches!(
+            parse_callback(&url),
+            Err(AuthError::CallbackTooLarge)
+        ));
+    }
+
+    /// VALID_QUERY (4 params) plus junk params up to `count` in total
+    fn callback_with_params(count: usize) -> String {
+        let junk: Vec<String> = (4..count).map(|i| format!("p{}=v", i)).collect();
+        callback_url(&format!("{}&{}", VALID_QUERY, junk.join("&")))
+    }
+
+    #[test]
+    fn accepts_the_maximum_number_of_params() {
+        let session = match parse_callback(&callback_with_params(MAX_CALLBACK_PARAMS)) {
+            Ok(Some(AuthCallback {
+                grant: CallbackGrant::Session(session),
+                ..
+            })) => session,
+            other => panic!("expected a session, got {:?}", other),
+        };
+        assert_eq!(session.user_id, "user_1");
+    }
+
+    #[test]
+    fn rejects_one_param_over_the_maximum() {
+        let result = parse_callback(&callback_with_params(MAX_CALLBACK_PARAMS + 1));
+        assert!(matches!(result, Err(AuthError::CallbackTooLarge)));
+    }
+
+    #[test]
+    fn rejects_an_oversized_sealed_session() {
+        let token = "s".repeat(MAX_SEALED_SESSION_LEN + 1);
+        let query = VALID_QUERY.replace(
+            "sealed_session=sealed",
+            &format!("sealed_session={}", token),
+        );
+        assert!(matches!(
+            parse_callback(&callback_url(&query)),
+            Err(AuthError::TokenTooLarge)
+        ));
+    }
+
+    #[test]
+    fn coalesces_a_burst_to_the_latest_login_and_keeps_other_links() {
+        let scheme = crate::DEEP_LINK_SCHEME;
+        let first = callback_url("sealed_session=old&user_id=u&email=e&state=1");
+        let other = format!("{}://campaign/42", scheme);
+        let latest = callback_url("sealed_session=new&user_id=u&email=e&state=2");
+        let burst = [first.clone(), other.clone(), latest.clone(), other.clone()];
+
+        assert_eq!(coalesce_deep_links(&burst), vec![other, latest]);
+    }
+
+    #[test]
+    fn coalescing_prefers_a_callback_with_a_session_or_code() {
+        let with_code = callback_url("code=abc&state=1");
+        let without = callback_url("error=access_denied&state=2");
+
+        assert_eq!(
+            coalesce_deep_links(&[with_code.clone(), without.clone()]),
+            vec![with_code]
+        );
+        // With no usable callback the last one is kept so its error surfaces
+        assert_eq!(
+            coalesce_deep_links(std::slice::from_ref(&without)),
+            vec![without]
+        );
+    }
+
+    fn login_url_with(options: &LoginOptions) -> Result<String, Box<dyn std::error::Error>> {
+        compose_login_url(
+            "https://api.example.com",
+            options,
+            "st",
+            "challenge",
+            None,
+            None,
+        )
+    }
+
+    fn login_params(url: &str) -> std::collections::HashMap<String, String> {
+        url::Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    }
+
+    #[test]
+    fn login_url_carries_state_pkce_and_redirect() {
+        let url = login_url_with(&LoginOptions::default()).unwrap();
+        assert!(url.starts_with("https://api.example.com/auth/login?"));
+
+        let params = login_params(&url);
+        assert_eq!(params["state"], "st");
+        assert_eq!(params["code_challenge"], "challenge");
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(
+            params["redirect_uri"],
+            format!("{}://auth/callback", crate::DEEP_LINK_SCHEME)
+        );
+        assert!(!params.contains_key("provider"));
+    }
+
+    #[test]
+    fn login_url_maps_each_provider() {
+        for (name, param) in [
+            ("google", "GoogleOAuth"),
+            ("Microsoft", "MicrosoftOAuth"),
+            ("apple", "AppleOAuth"),
+            ("GITHUB", "GitHubOAuth"),
+        ] {
+            let options = LoginOptions {
+                provider: Some(name.to_string()),
+                ..Default::default()
+            };
+            assert_eq!(
+                login_params(&login_url_with(&options).unwrap())["provider"],
+                param,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn login_url_rejects_an_unknown_provider() {
+        let options = LoginOptions {
+            provider: Some("myspace".to_string()),
+            ..Default::default()
+        };
+        assert!(login_url_with(&options).is_err());
+    }
+
+    #[test]
+    fn login_url_validates_the_sso_connection() {
+        let options = LoginOptions {
+            connection: Some("conn_01ABC".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            login_params(&login_url_with(&options).unwrap())["connection"],
+            "conn_01ABC"
+        );
+
+        for bad in ["conn_", "01ABC", "conn_01&provider=x"] {
+            let options = LoginOptions {
+                connection: Some(bad.to_string()),
+                ..Default::default()
+            };
+            assert!(login_url_with(&options).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn verified_save_succeeds_when_read_back_matches() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = parsed_session(VALID_QUERY);
+
+        save_session_verified(&session).unwrap();
+        assert_eq!(get_session().unwrap(), Some(session));
+    }
+
+    #[test]
+    fn verified_save_fails_when_write_is_not_kept() {
+        let _store = fake_store::inject(fake_store::FakeStore::dropping_writes());
+
+        let err = save_session_verified(&parsed_session(VALID_QUERY)).unwrap_err();
+        assert_eq!(err.to_string(), "Session missing after write");
+    }
+
+    #[test]
+    fn verified_save_fails_when_read_back_differs() {
+        let _store = fake_store::inject(fake_store::FakeStore::dropping_writes());
+        let stale = parsed_session(VALID_QUERY);
+        fake_store::set_accounts(Some(&stale), &[]);
+
+        let fresh = UserSession {
+            sealed_session: "fresh".to_string(),
+            ..stale
+        };
+        let err = save_session_verified(&fresh).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Stored session does not match what was written"
+        );
+    }
+
+    #[test]
+    fn rereading_the_store_reflects_an_external_change() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let ours = parsed_session(VALID_QUERY);
+        save_session(&ours).unwrap();
+        assert_eq!(get_session().unwrap(), Some(ours.clone()));
+
+        // Another instance signs in as someone else, then signs everyone out
+        let theirs = UserSession {
+            user_id: "user_2".to_string(),
+            ..ours.clone()
+        };
+        fake_store::set_accounts(Some(&theirs), &[ours]);
+        assert_eq!(get_session().unwrap(), Some(theirs));
+
+        fake_store::set_accounts(None, &[]);
+        assert_eq!(get_session().unwrap(), None);
+    }
+
+    #[test]
+    fn accepts_an_uppercased_callback_link() {
+        let url = format!(
+            "{}://AUTH/callback?{}",
+            crate::DEEP_LINK_SCHEME.to_ascii_uppercase(),
+            VALID_QUERY
+        );
+        assert!(matches!(parse_callback(&url), Ok(Some(_))), "{}", url);
+    }
+
+    #[test]
+    fn ignores_a_lookalike_auth_host() {
+        // Cyrillic "а" in place of the Latin "a"
+        let url = format!(
+            "{}://\u{430}uth/callback?{}",
+            crate::DEEP_LINK_SCHEME,
+            VALID_QUERY
+        );
+        assert!(matches!(parse_callback(&url), Ok(None)), "{}", url);
+    }
+
+    #[test]
+    fn lookalike_https_host_is_not_trusted() {
+        let host_of = |url: &str| {
+            url::Url::parse(url)
+                .unwrap()
+                .host_str()
+                .unwrap()
+                .to_string()
+        };
+
+        assert!(is_trusted_link_host(&host_of(
+            "https://docs.agenticadvertising.org/help"
+        )));
+        // The url crate turns the unicode host into punycode, which matches no trusted domain
+        let lookalike = host_of("https://\u{430}genticadvertising.org/help");
+        assert!(lookalike.starts_with("xn--"), "{}", lookalike);
+        assert!(!is_trusted_link_host(&lookalike));
+        assert!(!is_trusted_link_host("agenticadvertising.org.example.com"));
+    }
+
+    #[test]
+    fn external_links_must_be_http() {
+        let docs = "https://docs.agenticadvertising.org/privacy";
+        assert_eq!(check_external_url(docs, true).unwrap().as_str(), docs);
+        // Links typed into the UI may go anywhere on the web
+        assert!(check_external_url("https://example.com/", false).is_ok());
+
+        for url in [
+            "javascript:alert(1)",
+            "file:///etc/passwd",
+            "addie://auth/callback",
+            "ftp://example.com/",
+        ] {
+            let err = check_external_url(url, false).unwrap_err();
+            assert!(
+                err.to_string().contains("must be http(s)"),
+                "{}: {}",
+                url,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn server_supplied_links_need_a_trusted_host() {
+        let err = check_external_url("https://evil.example/", true).unwrap_err();
+        assert!(err.to_string().contains("untrusted host"), "{}", err);
+        assert!(check_external_url("https://agenticadvertising.org@evil.example/", false).is_err());
+    }
+
+    #[test]
+    fn login_url_carries_the_locale() {
+        let url = compose_login_url(
+            "https://api.example.com",
+            &LoginOptions::default(),
+            "st",
+            "challenge",
+            None,
+            Some("pt-BR"),
+        )
+        .unwrap();
+        assert_eq!(login_params(&url)["ui_locale"], "pt-BR");
+        assert!(
+            !login_params(&login_url_with(&LoginOptions::default()).unwrap())
+                .contains_key("ui_locale")
+        );
+    }
+
+    #[test]
+    fn login_url_maps_each_prompt() {
+        for (prompt, param) in [
+            ("none", None),
+            ("login", Some("login")),
+            ("select_account", Some("select_account")),
+            ("Consent", Some("consent")),
+        ] {
+            let options = LoginOptions {
+                prompt: Some(prompt.to_string()),
+                ..Default::default()
+            };
+            let params = login_params(&login_url_with(&options).unwrap());
+            assert_eq!(
+                params.get("prompt").map(String::as_str),
+                param,
+                "{}",
+                prompt
+            );
+        }
+
+        let options = LoginOptions {
+            prompt: Some("always".to_string()),
+            ..Default::default()
+        };
+        assert!(login_url_with(&options)
+            .unwrap_err()
+            .to_string()
+            .contains("Unknown login prompt"));
+    }
+
+    #[test]
+    fn rejects_a_doubled_security_param() {
+        for key in ["sealed_session", "state", "user_id"] {
+            let url = callback_url(&format!("{}&{}=injected", VALID_QUERY, key));
+            assert!(
+                matches!(parse_callback(&url), Err(AuthError::DuplicateParam(ref k)) if k == key),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn legal_transitions_move_the_phase() {
+        let phase = AuthPhase(Mutex::new(LoginPhase::SignedOut));
+        for next in [
+            LoginPhase::LoginPending,
+            LoginPhase::Failed,
+            LoginPhase::LoginPending,
+            LoginPhase::Authenticated,
+            LoginPhase::Authenticated,
+            LoginPhase::SignedOut,
+        ] {
+            assert!(phase.advance(next), "-> {:?}", next);
+            assert_eq!(*phase.0.lock().unwrap(), next);
+        }
+    }
+
+    #[test]
+    fn illegal_transitions_leave_the_phase_alone() {
+        let phase = AuthPhase(Mutex::new(LoginPhase::Authenticated));
+        for next in [LoginPhase::LoginPending, LoginPhase::Failed] {
+            assert!(!phase.advance(next), "-> {:?}", next);
+            assert_eq!(*phase.0.lock().unwrap(), LoginPhase::Authenticated);
+        }
+    }
+
+    #[test]
+    fn a_callback_may_arrive_without_a_pending_login() {
+        for from in [LoginPhase::SignedOut, LoginPhase::Failed] {
+            assert!(
+                from.can_transition_to(LoginPhase::Authenticated),
+                "{:?}",
+                from
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_files_are_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("addie-private-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session.json");
+        // A file left with looser permissions is replaced, not written through
+        std::fs::write(&path, b"old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private_file(&path, b"{}").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let contents = std::fs::read(&path).unwrap();
+        let leftovers = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(contents, b"{}");
+        assert_eq!(leftovers, 1, "temp file left behind");
+    }
+
+    #[test]
+    fn save_fails_rather_than_overwrite_an_unreadable_store() {
+        let _store = fake_store::inject(fake_store::FakeStore::failing_reads());
+
+        let err = save_session(&parsed_session(VALID_QUERY)).unwrap_err();
+        assert!(err.to_string().contains("keychain is locked"), "{}", err);
+    }
+
+    #[test]
+    fn save_keeps_other_accounts() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let first = parsed_session(VALID_QUERY);
+        let second = UserSession {
+            user_id: "user_2".to_string(),
+            ..first.clone()
+        };
+
+        save_session(&first).unwrap();
+        save_session(&second).unwrap();
+        let ids: Vec<String> = list_accounts()
+            .unwrap()
+            .into_iter()
+            .map(|account| account.id)
+            .collect();
+        assert_eq!(ids, ["user_1", "user_2"]);
+        assert_eq!(get_session().unwrap(), Some(second));
+    }
+
+    #[test]
+    fn expiry_is_the_earlier_of_token_expiry_and_max_age() {
+        let session = UserSession {
+            created_at: Some(1_000),
+            max_session_age: Some(500),
+            expires_at: None,
+            ..parsed_session(VALID_QUERY)
+        };
+        assert_eq!(expiry_deadline(&session), Some(1_500));
+
+        let token_first = UserSession {
+            expires_at: Some(1_200),
+            ..session.clone()
+        };
+        assert_eq!(expiry_deadline(&token_first), Some(1_200));
+
+        let max_age_first = UserSession {
+            expires_at: Some(9_000),
+            ..session.clone()
+        };
+        assert_eq!(expiry_deadline(&max_age_first), Some(1_500));
+
+        let token_only = UserSession {
+            created_at: None,
+            expires_at: Some(1_200),
+            ..session
+        };
+        assert_eq!(expiry_deadline(&token_only), Some(1_200));
+    }
+
+    #[test]
+    fn without_a_data_dir_files_are_unavailable_rather_than_a_panic() {
+        // Unit tests never run init_data_dir
+        let err = data_file_path(".addie-preferences.json").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(!is_data_dir_writable());
+        assert_eq!(crate::device::device_token(), None);
+        assert_eq!(crate::locale::get_override(), None);
+    }
+
+    #[test]
+    fn server_links_report_bad_user_id_as_param_errors() {
+        let link = force_logout_link;
+
+        assert_eq!(
+            link_user_id(&link("user_id=user_1&reason=x")).unwrap(),
+            "user_1"
+        );
+        assert!(matches!(
+            link_user_id(&link("reason=x")),
+            Err(AuthError::MissingParam("user_id"))
+        ));
+        assert!(matches!(
+            link_user_id(&link("user_id=")),
+            Err(AuthError::InvalidParam("user_id"))
+        ));
+        assert!(matches!(
+            link_user_id(&link("user_id=user_1&user_id=user_2")),
+            Err(AuthError::DuplicateParam(ref key)) if key == "user_id"
+        ));
+        assert!(matches!(
+            link_param(&link("user_id=u&reason=a&reason=b"), "reason"),
+            Err(AuthError::DuplicateParam(_))
+        ));
+    }
+
+    #[test]
+    fn undoing_a_failed_save_only_touches_that_account() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let active = parsed_session(VALID_QUERY);
+        let other = UserSession {
+            user_id: "user_2".to_string(),
+            ..active.clone()
+        };
+        fake_store::set_accounts(Some(&active), std::slice::from_ref(&other));
+        let before = read_store().unwrap();
+
+        // The new login was written, and another account was saved meanwhile
+        let added = UserSession {
+            user_id: "user_3".to_string(),
+            ..active.clone()
+        };
+        let newer = UserSession {
+            user_id: "user_4".to_string(),
+            ..active.clone()
+        };
+        fake_store::set_accounts(
+            Some(&added),
+            &[active.clone(), other.clone(), newer.clone()],
+        );
+
+        undo_account_save(&before, &["user_3"]).unwrap();
+        let store = read_store().unwrap();
+        assert_eq!(store.active.as_deref(), Some("user_1"));
+        assert_eq!(
+            store.accounts.keys().collect::<Vec<_>>(),
+            ["user_1", "user_2", "user_4"]
+        );
+    }
+
+    #[test]
+    fn undoing_a_failed_re_login_restores_the_previous_session() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let previous = parsed_session(VALID_QUERY);
+        fake_store::set_accounts(Some(&previous), &[]);
+        let before = read_store().unwrap();
+
+        let replaced = UserSession {
+            sealed_session: "new".to_string(),
+            ..previous.clone()
+        };
+        fake_store::set_accounts(Some(&replaced), &[]);
+
+        undo_account_save(&before, &["user_1"]).unwrap();
+        assert_eq!(get_session().unwrap(), Some(previous));
+    }
+
+    fn me(id: &str) -> Result<api::MeResponse, ApiError> {
+        Ok(api::MeResponse {
+            id: id.to_string(),
+            email: "a@example.com".to_string(),
+            first_name: None,
+            last_name: None,
+        })
+    }
+
+    #[test]
+    fn verification_of_a_valid_session() {
+        let status = verification_status(&me("user_1"), "user_1");
+        assert!(status.valid && status.server_user_matches_local);
+        assert!(!status.expired && !status.revoked && !status.network_error);
+    }
+
+    #[test]
+    fn verification_of_an_expired_session() {
+        let expired = Err(ApiError::Unauthorized {
+            code: Some("invalid_token".to_string()),
+        });
+        let status = verification_status(&expired, "user_1");
+        assert!(status.expired && !status.revoked && !status.valid);
+    }
+
+    #[test]
+    fn verification_of_a_revoked_session() {
+        for revoked in [
+            ApiError::Unauthorized {
+                code: Some("session_revoked".to_string()),
+            },
+            ApiError::Unauthorized { code: None },
+            ApiError::Forbidden { body: None },
+        ] {
+            let status = verification_status(&Err(revoked), "user_1");
+            assert!(status.revoked && !status.expired && !status.valid);
+        }
+    }
+
+    #[test]
+    fn verification_flags_a_different_server_user() {
+        let status = verification_status(&me("user_2"), "user_1");
+        assert!(!status.server_user_matches_local && !status.valid);
+        assert!(!status.revoked && !status.expired);
+    }
+
+    #[test]
+    fn verification_keeps_the_session_on_network_and_endpoint_errors() {
+        let status = verification_status(&Err(ApiError::Offline), "user_1");
+        assert!(status.network_error && !status.expired && !status.revoked);
+
+        let missing = Err(ApiError::EndpointUnavailable {
+            path: "/auth/me".to_string(),
+        });
+        let status = verification_status(&missing, "user_1");
+        assert!(status.server_incompatible && !status.network_error);
+    }
+
+    #[test]
+    fn callback_without_state_is_rejected_and_reported() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let errors = test_app::record(&app, "auth-error");
+
+        let url = url::Url::parse(&callback_url(
+            "sealed_session=sealed&user_id=user_1&email=a%40example.com",
+        ))
+        .unwrap();
+        let result = handle_callback(app.handle(), &url);
+
+        assert!(matches!(result, Err(AuthError::MissingState)));
+        assert_eq!(get_session().unwrap(), None);
+        assert_eq!(errors.payloads().len(), 1);
+        assert!(last_auth_error(app.handle()).is_some());
+    }
+
+    const ME_JSON: &str =
+        r#"{"id":"user_9","email":"nine@example.com","first_name":"Nine","last_name":null}"#;
+
+    fn jwt(claims: serde_json::Value) -> String {
+        use base64::Engine;
+
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        format!(
+            "{}.{}.c2lnbmF0dXJl",
+            b64.encode(r#"{"alg":"RS256","typ":"JWT"}"#),
+            b64.encode(claims.to_string())
+        )
+    }
+
+    fn jwt_expiring_at(exp: u64) -> String {
+        jwt(serde_json::json!({ "exp": exp }))
+    }
+
+    #[tokio::test]
+    async fn imported_token_is_confirmed_by_the_server() {
+        let api = mock_api::serve(vec![Some((200, ME_JSON))]);
+
+        let session = validate_imported_token(" opaque-token ").await.unwrap();
+        assert_eq!(session.user_id, "user_9");
+        assert_eq!(session.email, "nine@example.com");
+        assert_eq!(session.sealed_session, "opaque-token");
+        let received = api.received();
+        assert_eq!(received[0].path, "/auth/me");
+        assert_eq!(
+            received[0].header("Authorization"),
+            Some("Bearer opaque-token")
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_imported_token_is_rejected_without_asking_the_server() {
+        let api = mock_api::serve(vec![Some((200, ME_JSON))]);
+
+        let err = validate_imported_token(&jwt_expiring_at(now_secs() - 60))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Session token has expired");
+        assert!(api.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn garbage_imported_token_is_rejected() {
+        let api = mock_api::serve(vec![Some((401, r#"{"error":"invalid_token"}"#))]);
+
+        for token in ["", "   ", "two words", "nul\u{0}byte"] {
+            assert!(validate_imported_token(token).await.is_err(), "{:?}", token);
+        }
+        let too_long = "x".repeat(MAX_SEALED_SESSION_LEN + 1);
+        assert!(validate_imported_token(&too_long).await.is_err());
+        assert!(api.received().is_empty());
+
+        let err = validate_imported_token("garbage").await.unwrap_err();
+        assert!(err.to_string().starts_with("Server rejected"), "{}", err);
+    }
+
+    #[test]
+    fn failed_save_of_a_new_login_keeps_the_other_accounts() {
+        let _store = fake_store::inject(fake_store::FakeStore::unreadable_after_write());
+        let active = parsed_session(VALID_QUERY);
+        let other = UserSession {
+            user_id: "user_2".to_string(),
+            ..active.clone()
+        };
+        fake_store::set_accounts(Some(&active), std::slice::from_ref(&other));
+
+        let new = UserSession {
+            user_id: "user_3".to_string(),
+            ..active.clone()
+        };
+        assert!(save_signed_in_session(&new).is_err());
+
+        let store = read_store().unwrap();
+        assert_eq!(store.active.as_deref(), Some("user_1"));
+        assert_eq!(
+            store.accounts.keys().collect::<Vec<_>>(),
+            ["user_1", "user_2"]
+        );
+    }
+
+    fn keychain_error(message: &str) -> keyring::Error {
+        keyring::Error::PlatformFailure(Box::new(std::io::Error::other(message.to_string())))
+    }
+
+    #[test]
+    fn keychain_failures_are_classified() {
+        let locked = keychain_error("User interaction is not allowed. (-25308)");
+        assert_eq!(KeychainFailure::of(&locked), KeychainFailure::Locked);
+        let locked =
+            keyring::Error::NoStorageAccess(Box::new(std::io::Error::other("object locked")));
+        assert_eq!(KeychainFailure::of(&locked), KeychainFailure::Locked);
+        let busy = keychain_error("Operation timed out");
+        assert_eq!(KeychainFailure::of(&busy), KeychainFailure::Transient);
+        let missing = keychain_error("No such keychain");
+        assert_eq!(KeychainFailure::of(&missing), KeychainFailure::Unavailable);
+        assert_eq!(
+            KeychainFailure::of(&keyring::Error::BadEncoding(vec![0xff])),
+            KeychainFailure::Unavailable
+        );
+    }
+
+    #[test]
+    fn session_loads_when_the_keychain_recovers_after_two_failures() {
+        // Parsed once: the retries can outlast the second its created_at is stamped with
+        let session = parsed_session(VALID_QUERY);
+        let mut store = SessionStore::default();
+        store.upsert_active(session.clone());
+        let _keychain = fake_keychain::script(vec![
+            Err(keychain_error("Resource busy")),
+            Err(keychain_error("User interaction is not allowed.")),
+            Ok(serde_json::to_string(&store).unwrap()),
+        ]);
+
+        assert_eq!(get_session().unwrap(), Some(session));
+        assert_eq!(fake_keychain::remaining(), 0);
+    }
+
+    #[test]
+    fn locked_keychain_is_reported_without_logging_out() {
+        let dir = temp_data_dir::create();
+        let mut store = SessionStore::default();
+        store.upsert_active(parsed_session(VALID_QUERY));
+        let file = dir.path().join(".addie-session.json");
+        std::fs::write(&file, serde_json::to_vec(&store).unwrap()).unwrap();
+        // Startup's phase restore reads the keychain too
+        let locked = (0..2 * KEYCHAIN_ATTEMPTS)
+            .map(|_| Err(keychain_error("User interaction is not allowed. (-25308)")))
+            .collect();
+        let _keychain = fake_keychain::script(locked);
+        let app = test_app::create();
+        let reported = test_app::record(&app, "keychain-locked");
+        let logged_out = test_app::record(&app, "logged-out");
+
+        let read = get_valid_session(app.handle());
+
+        assert!(read.as_ref().is_err_and(|e| e.is::<KeychainLocked>()));
+        assert_eq!(fake_keychain::remaining(), 0);
+        assert_eq!(reported.payloads().len(), 1);
+        assert!(logged_out.payloads().is_empty());
+        let state = AuthState::from_session_read(read, || panic!("not signed out"));
+        assert_eq!(state.status, AuthStatus::Unknown);
+        assert_eq!(
+            std::fs::read(&file).unwrap(),
+            serde_json::to_vec(&store).unwrap()
+        );
+    }
+
+    /// The VALID_QUERY account signed in again under a reissued id
+    fn reissued(expires_at: Option<u64>) -> UserSession {
+        UserSession {
+            user_id: "user_9".to_string(),
+            email: " A@Example.com".to_string(),
+            expires_at,
+            ..parsed_session(VALID_QUERY)
+        }
+    }
+
+    #[test]
+    fn login_under_a_reissued_id_merges_the_accounts() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let existing = UserSession {
+            expires_at: Some(now_secs() + 60),
+            ..parsed_session(VALID_QUERY)
+        };
+        let other = UserSession {
+            user_id: "user_2".to_string(),
+            email: "b@example.com".to_string(),
+            ..existing.clone()
+        };
+        fake_store::set_accounts(Some(&existing), std::slice::from_ref(&other));
+
+        let new = reissued(Some(now_secs() + 3600));
+        let merge = save_signed_in_session(&new).unwrap().unwrap();
+
+        assert_eq!(merge.kept, new);
+        assert_eq!(merge.removed_user_id, "user_1");
+        let store = read_store().unwrap();
+        assert_eq!(store.active.as_deref(), Some("user_9"));
+        assert_eq!(
+            store.accounts.keys().collect::<Vec<_>>(),
+            ["user_2", "user_9"]
+        );
+    }
+
+    #[test]
+    fn merge_keeps_the_session_that_expires_later() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let existing = parsed_session(VALID_QUERY);
+        fake_store::set_accounts(Some(&existing), &[]);
+
+        // No expiry at all outlasts any expiry
+        let merge = save_signed_in_session(&reissued(Some(now_secs() + 3600)))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(merge.kept, existing);
+        assert_eq!(merge.removed_user_id, "user_9");
+        assert_eq!(get_session().unwrap(), Some(existing));
+        assert_eq!(read_store().unwrap().accounts.len(), 1);
+    }
+
+    #[test]
+    fn merged_login_emits_account_merged() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let merged = test_app::record(&app, "account-merged");
+        let new = reissued(None);
+        let merge = AccountMerge::between(&parsed_session(VALID_QUERY), &new);
+
+        assert_eq!(report_merge(app.handle(), Some(merge), new.clone()), new);
+        assert_eq!(
+            merged.payloads(),
+            [serde_json::json!({ "user_id": "user_9", "removed_user_id": "user_1" })]
+        );
+        assert_eq!(report_merge(app.handle(), None, new.clone()), new);
+        assert_eq!(merged.payloads().len(), 1);
+    }
+
+    #[test]
+    fn pruning_removes_only_dead_inactive_accounts() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let pruned = test_app::record(&app, "accounts-pruned");
+        let account = |user_id: &str, session: UserSession| UserSession {
+            user_id: user_id.to_string(),
+            ..session
+        };
+        let base = parsed_session(VALID_QUERY);
+        let expired = UserSession {
+            expires_at: Some(now_secs() - 60),
+            ..base.clone()
+        };
+        // The active account is dead too, but get_valid_session logs that one out
+        let active = account("user_1", expired.clone());
+        fake_store::set_accounts(
+            Some(&active),
+            &[
+                account("expired", expired.clone()),
+                account(
+                    "refreshable",
+                    UserSession {
+                        refresh_token: Some("refresh".to_string()),
+                        ..expired
+                    },
+                ),
+                account(
+                    "too_old",
+                    UserSession {
+                        created_at: Some(now_secs() - 7200),
+                        max_session_age: Some(3600),
+                        ..base.clone()
+                    },
+                ),
+                account("valid", base),
+            ],
+        );
+
+        assert_eq!(
+            prune_accounts(app.handle()).unwrap(),
+            ["expired", "too_old"]
+        );
+        assert_eq!(
+            read_store().unwrap().accounts.keys().collect::<Vec<_>>(),
+            ["refreshable", "user_1", "valid"]
+        );
+        assert_eq!(
+            pruned.payloads(),
+            [serde_json::json!(["expired", "too_old"])]
+        );
+
+        assert!(prune_accounts(app.handle()).unwrap().is_empty());
+        assert_eq!(pruned.payloads().len(), 1);
+    }
+
+    #[test]
+    fn concurrent_switches_and_refreshes_keep_the_store_consistent() {
+        const ROUNDS: usize = 20;
+        const REFRESHED: &str =
+            r#"{"sealed_session":"refreshed","expires_at":null,"refresh_token":"rotated"}"#;
+
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let refreshable = |user_id: &str| UserSession {
+            user_id: user_id.to_string(),
+            email: format!("{}@example.com", user_id),
+            refresh_token: Some("refresh".to_string()),
+            ..parsed_session(VALID_QUERY)
+        };
+        fake_store::set_accounts(Some(&refreshable("user_1")), &[refreshable("user_2")]);
+        let app = test_app::create();
+        let store = fake_store::shared();
+
+        let refreshers: Vec<_> = (0..2)
+            .map(|_| {
+                let (app, store) = (app.handle().clone(), store.clone());
+                std::thread::spawn(move || {
+                    let _store = store.inject();
+                    let _api = mock_api::serve(vec![Some((200, REFRESHED)); ROUNDS]);
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .unwrap();
+                    for _ in 0..ROUNDS {
+                        let _ = runtime.block_on(refresh_session(&app));
+                    }
+                })
+            })
+            .collect();
+
+        // Only switch_account changes the active account, so it must still be the one this
+        // thread switched to, however the refreshes land in between
+        let switcher = {
+            let (app, store) = (app.handle().clone(), store.clone());
+            std::thread::spawn(move || {
+                let _store = store.inject();
+                for round in 0..ROUNDS {
+                    let target = if round % 2 == 0 { "user_2" } else { "user_1" };
+                    switch_account(&app, target).unwrap();
+                    for _ in 0..10 {
+                        std::thread::yield_now();
+                        assert_eq!(get_session().unwrap().unwrap().user_id, target);
+                    }
+                }
+            })
+        };
+
+        switcher.join().unwrap();
+        for refresher in refreshers {
+            refresher.join().unwrap();
+        }
+        let store = read_store().unwrap();
+        assert_eq!(store.active.as_deref(), Some("user_1"));
+        assert_eq!(store.accounts.len(), 2);
+        for (user_id, session) in &store.accounts {
+            assert_eq!(&session.user_id, user_id);
+            assert_eq!(session.email, format!("{}@example.com", user_id));
+        }
+    }
+
+    /// Answers /auth/me and /auth/refresh for sessions whose token is `tok_<user id>`
+    fn account_api(connections: usize) -> mock_api::MockApi {
+        mock_api::serve_with(connections, |request| {
+            let user_id = request
+                .header("Authorization")?
+                .strip_prefix("Bearer tok_")?;
+            let me = format!(
+                r#"{{"id":"{}","email":"{}@example.com","first_name":null,"last_name":null}}"#,
+                user_id, user_id
+            );
+            Some(match (request.path.as_str(), user_id) {
+                ("/auth/refresh", _) => (
+                    200,
+                    r#"{"sealed_session":"tok_new","expires_at":null,"refresh_token":null}"#
+                        .to_string(),
+                ),
+                (_, "expired") => (401, r#"{"error":"invalid_token"}"#.to_string()),
+                (_, "old_server") => (404, "{}".to_string()),
+                _ => (200, me),
+            })
+        })
+    }
+
+    fn stored_account(user_id: &str) -> UserSession {
+        UserSession {
+            user_id: user_id.to_string(),
+            email: format!("{}@example.com", user_id),
+            sealed_session: format!("tok_{}", user_id),
+            ..parsed_session(VALID_QUERY)
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshing_all_accounts_reports_each_one() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let expiring = UserSession {
+            expires_at: Some(now_secs() + 10),
+            refresh_token: Some("refresh".to_string()),
+            ..stored_account("expiring")
+        };
+        fake_store::set_accounts(
+            Some(&stored_account("user_1")),
+            &[
+                expiring,
+                stored_account("expired"),
+                stored_account("old_server"),
+            ],
+        );
+        let app = test_app::create();
+        let refreshed = test_app::record(&app, "accounts-refreshed");
+        let _api = account_api(4);
+
+        let statuses = refresh_all_accounts(app.handle()).await.unwrap();
+
+        let health = |user_id: &str| {
+            statuses
+                .iter()
+                .find(|status| status.user_id == user_id)
+                .map(|status| status.health)
+        };
+        assert_eq!(statuses.len(), 4);
+        assert_eq!(health("user_1"), Some(AccountHealth::Valid));
+        assert_eq!(health("expiring"), Some(AccountHealth::Refreshed));
+        assert_eq!(health("expired"), Some(AccountHealth::Dead));
+        assert_eq!(health("old_server"), Some(AccountHealth::Unknown));
+
+        let store = read_store().unwrap();
+        assert_eq!(store.active.as_deref(), Some("user_1"));
+        assert_eq!(
+            store.accounts.keys().collect::<Vec<_>>(),
+            ["expiring", "old_server", "user_1"]
+        );
+        assert_eq!(store.accounts["expiring"].sealed_session, "tok_new");
+        assert_eq!(refreshed.payloads().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refreshing_all_accounts_logs_out_a_dead_active_account() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(
+            Some(&stored_account("expired")),
+            &[stored_account("user_2")],
+        );
+        let app = test_app::create();
+        let logged_out = test_app::record(&app, "logged-out");
+        let _api = account_api(2);
+
+        let statuses = refresh_all_accounts(app.handle()).await.unwrap();
+
+        assert!(statuses.iter().all(|status| match status.user_id.as_str() {
+            "expired" => status.health == AccountHealth::Dead,
+            _ => status.health == AccountHealth::Valid,
+        }));
+        assert_eq!(get_session().unwrap(), None);
+        assert!(read_store().unwrap().accounts.contains_key("user_2"));
+        assert_eq!(logged_out.payloads()[0]["reason"], "expired");
+    }
+
+    #[test]
+    fn account_labels_are_listed_and_kept_across_logins() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[stored_account("user_2")]);
+
+        let user = set_account_label(
+            "user_2",
+            Some("  Agency ".to_string()),
+            Some("#1A2B3C".to_string()),
+        )
+        .unwrap();
+        assert_eq!(user.label.as_deref(), Some("Agency"));
+        assert_eq!(user.color.as_deref(), Some("#1a2b3c"));
+
+        // A new login for the account replaces its session but not its label
+        {
+            let _guard = lock_store();
+            save_session(&stored_account("user_2")).unwrap();
+        }
+        let accounts = list_accounts().unwrap();
+        let labels: Vec<_> = accounts
+            .iter()
+            .map(|user| {
+                (
+                    user.id.as_str(),
+                    user.label.as_deref(),
+                    user.color.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                ("user_1", None, None),
+                ("user_2", Some("Agency"), Some("#1a2b3c"))
+            ]
+        );
+
+        let cleared = set_account_label("user_2", None, None).unwrap();
+        assert_eq!((cleared.label, cleared.color), (None, None));
+        assert!(read_store().unwrap().labels.is_empty());
+    }
+
+    #[test]
+    fn invalid_account_labels_are_rejected() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+
+        for color in ["1a2b3c", "#12", "#12345g", "red"] {
+            let err = set_account_label("user_1", None, Some(color.to_string())).unwrap_err();
+            assert!(matches!(err, AuthError::InvalidParam("color")), "{}", color);
+        }
+        for label in [
+            "x".repeat(MAX_ACCOUNT_LABEL_CHARS + 1),
+            "tab\there".to_string(),
+        ] {
+            let err = set_account_label("user_1", Some(label), None).unwrap_err();
+            assert!(matches!(err, AuthError::InvalidParam("label")));
+        }
+        let err = set_account_label("nobody", Some("Work".to_string()), None).unwrap_err();
+        assert!(matches!(err, AuthError::UnknownAccount(_)));
+        assert!(read_store().unwrap().labels.is_empty());
+    }
+
+    #[test]
+    fn removing_an_account_drops_its_label() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[stored_account("user_2")]);
+        set_account_label("user_2", Some("Agency".to_string()), None).unwrap();
+
+        assert!(remove_inactive_account("user_2").unwrap());
+
+        assert!(read_store().unwrap().labels.is_empty());
+    }
+
+    const ROTATED_REFRESH: &str =
+        r#"{"sealed_session":"tok_new","expires_at":null,"refresh_token":"refresh_2"}"#;
+
+    #[tokio::test]
+    async fn unsaved_refresh_keeps_the_old_refresh_token() {
+        // Writes are lost, as if the app died between using the token and saving its successor
+        let _store = fake_store::inject(fake_store::FakeStore::dropping_writes());
+        let session = UserSession {
+            expires_at: Some(now_secs() + 3600),
+            refresh_token: Some("refresh_1".to_string()),
+            ..stored_account("user_1")
+        };
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let refreshed = test_app::record(&app, "auth-refreshed");
+        let api = mock_api::serve(vec![Some((200, ROTATED_REFRESH))]);
+
+        let err = refresh_session(app.handle()).await.unwrap_err();
+
+        assert!(matches!(err, AuthError::StorageFailed(_)));
+        assert!(api.received()[0].body.contains("refresh_1"));
+        assert_eq!(get_session().unwrap(), Some(session));
+        assert!(refreshed.payloads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsaved_account_refresh_is_not_reported_as_refreshed() {
+        let _store = fake_store::inject(fake_store::FakeStore::dropping_writes());
+        let expiring = UserSession {
+            expires_at: Some(now_secs() + 10),
+            refresh_token: Some("refresh".to_string()),
+            ..stored_account("expiring")
+        };
+        fake_store::set_accounts(
+            Some(&stored_account("user_1")),
+            std::slice::from_ref(&expiring),
+        );
+        let app = test_app::create();
+        let _api = account_api(2);
+
+        let statuses = refresh_all_accounts(app.handle()).await.unwrap();
+
+        assert!(statuses
+            .iter()
+            .all(|status| status.health != AccountHealth::Refreshed));
+        assert_eq!(read_store().unwrap().accounts["expiring"], expiring);
+    }
+
+    #[tokio::test]
+    async fn refresh_answered_after_logout_does_not_bring_the_session_back() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = UserSession {
+            expires_at: Some(now_secs() + 3600),
+            refresh_token: Some("refresh_1".to_string()),
+            ..stored_account("user_1")
+        };
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let refreshed = test_app::record(&app, "auth-refreshed");
+        // The server holds its answer until the test has logged out
+        let (started, request_seen) = std::sync::mpsc::channel();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let _api = mock_api::serve_with(1, move |_| {
+            let _ = started.send(());
+            let _ = released.recv();
+            Some((200, ROTATED_REFRESH.to_string()))
+        });
+
+        let log_out = async {
+            while request_seen.try_recv().is_err() {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+            force_logout_local(app.handle(), LogoutReason::UserRequested, None).unwrap();
+            release.send(()).unwrap();
+        };
+        let (result, ()) = tokio::join!(refresh_session(app.handle()), log_out);
+
+        assert!(!matches!(result, Ok(Some(_))));
+        assert_eq!(get_session().unwrap(), None);
+        assert!(refreshed.payloads().is_empty());
+    }
+
+    #[test]
+    fn refresh_saved_after_a_logout_is_discarded() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = stored_account("user_1");
+        fake_store::set_accounts(Some(&session), &[]);
+        let generation = api::current_generation();
+        let refreshed = UserSession {
+            sealed_session: "tok_new".to_string(),
+            ..session.clone()
+        };
+
+        // Logged out, then signed in again with the very same session
+        api::cancel_in_flight();
+        assert!(!save_refreshed_session(&refreshed, &session.sealed_session, generation).unwrap());
+
+        assert_eq!(get_session().unwrap(), Some(session));
+    }
+
+    /// Start a login and return what it left pending
+    fn start_login(app: &tauri::App<tauri::test::MockRuntime>) -> PendingLogin {
+        start_oauth_flow(app.handle(), &LoginOptions::default(), None).unwrap();
+        let pending = app.state::<PendingLogins>();
+        let pending = pending.0.lock().unwrap();
+        assert_eq!(pending.len(), 1);
+        pending[0].clone()
+    }
+
+    #[test]
+    fn callback_state_must_match_a_pending_login_and_works_once() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let errors = test_app::record(&app, "auth-error");
+        let login = start_login(&app);
+        let callback = |state: &str| {
+            let query = format!(
+                "sealed_session=sealed&user_id=user_1&email=a%40example.com&state={}",
+                urlencoding::encode(state)
+            );
+            url::Url::parse(&callback_url(&query)).unwrap()
+        };
+        let pending_count = || app.state::<PendingLogins>().0.lock().unwrap().len();
+
+        // A forged state is refused without cancelling the real login
+        let result = handle_callback(app.handle(), &callback("forged"));
+        assert!(matches!(result, Err(AuthError::StateMismatch)));
+        assert_eq!(get_session().unwrap(), None);
+        assert_eq!(pending_count(), 1);
+
+        handle_callback(app.handle(), &callback(&login.state)).unwrap();
+        assert_eq!(get_session().unwrap().unwrap().user_id, "user_1");
+        assert_eq!(pending_count(), 0);
+
+        // Replaying the same link finds nothing pending
+        fake_store::set_accounts(None, &[]);
+        let result = handle_callback(app.handle(), &callback(&login.state));
+        assert!(matches!(result, Err(AuthError::StateMismatch)));
+        assert_eq!(get_session().unwrap(), None);
+        assert_eq!(errors.payloads().len(), 2);
+    }
+
+    #[test]
+    fn login_url_challenge_is_the_stored_verifiers_s256() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+
+        let login = start_login(&app);
+
+        let params = login_params(&login.login_url);
+        let verifier = login.code_verifier.as_deref().unwrap();
+        assert_eq!(params["state"], login.state);
+        assert_eq!(params["code_challenge"], pkce_challenge(verifier));
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_ne!(verifier, login.state);
+        // RFC 7636 appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    /// user_1 with a refresh token, due for refresh (within REFRESH_MARGIN_SECS of expiry)
+    fn refreshable_account() -> UserSession {
+        UserSession {
+            expires_at: Some(now_secs() + REFRESH_MARGIN_SECS / 2),
+            refresh_token: Some("refresh_1".to_string()),
+            ..stored_account("user_1")
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_refreshes_use_the_refresh_token_once() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&refreshable_account()), &[]);
+        let app = test_app::create();
+        let api = mock_api::serve_with(2, |_| Some((200, ROTATED_REFRESH.to_string())));
+
+        let (first, second) = tokio::join!(
+            get_fresh_session(app.handle()),
+            get_fresh_session(app.handle())
+        );
+
+        // The second waited for the first and found the session already refreshed
+        for session in [first.unwrap().unwrap(), second.unwrap().unwrap()] {
+            assert_eq!(session.sealed_session, "tok_new");
+            assert_eq!(session.refresh_token.as_deref(), Some("refresh_2"));
+        }
+        assert_eq!(api.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refresh_is_dropped_if_another_account_became_active_meanwhile() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = refreshable_account();
+        fake_store::set_accounts(Some(&session), &[stored_account("user_2")]);
+        let app = test_app::create();
+        let refreshed = test_app::record(&app, "auth-refreshed");
+        // Another process switches accounts while the server is answering
+        let shared = fake_store::shared();
+        let (user_1, user_2) = (session.clone(), stored_account("user_2"));
+        let _api = mock_api::serve_with(1, move |_| {
+            let _store = shared.inject();
+            fake_store::set_accounts(Some(&user_2), std::slice::from_ref(&user_1));
+            Some((200, ROTATED_REFRESH.to_string()))
+        });
+
+        let result = refresh_session(app.handle()).await.unwrap();
+
+        assert_eq!(result.unwrap().user_id, "user_2");
+        assert_eq!(read_store().unwrap().accounts["user_1"], session);
+        assert!(refreshed.payloads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refresh_endpoint_missing_keeps_the_current_session() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = refreshable_account();
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let incompatible = test_app::record(&app, "server-incompatible");
+        let _api = mock_api::serve(vec![Some((404, "{}"))]);
+
+        // Not expired yet, so the token is still handed out
+        assert_eq!(
+            get_fresh_session(app.handle()).await.unwrap(),
+            Some(session)
+        );
+        assert_eq!(
+            incompatible.payloads(),
+            [serde_json::json!({ "path": "/auth/refresh" })]
+        );
+    }
+
+    #[tokio::test]
+    async fn forced_refresh_without_a_refresh_token_says_so() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+
+        let err = refresh_session(app.handle()).await.unwrap_err();
+
+        assert!(matches!(err, AuthError::NoRefreshToken));
+        assert_eq!(err.code(), "no_refresh_token");
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_the_session_after_clearing_it() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let api = mock_api::serve(vec![Some((200, "{}"))]);
+
+        let outcome = logout(app.handle(), false).await;
+
+        assert!(outcome.local_cleared && outcome.keychain_cleared && outcome.file_cleared);
+        assert!(outcome.server_revoked);
+        assert!(outcome.errors.is_empty());
+        let received = api.received();
+        assert_eq!(received[0].path, "/auth/logout");
+        assert_eq!(
+            received[0].header("Authorization"),
+            Some("Bearer tok_user_1")
+        );
+        assert_eq!(get_session().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn failed_revocation_still_clears_the_session() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let logged_out = test_app::record(&app, "logged-out");
+        let _api = mock_api::serve(vec![None]);
+
+        let outcome = logout(app.handle(), false).await;
+
+        assert!(outcome.local_cleared);
+        assert!(!outcome.server_revoked);
+        assert_eq!(outcome.errors.len(), 1);
+        assert!(outcome.errors[0].starts_with("Server revocation failed for user_1"));
+        assert_eq!(get_session().unwrap(), None);
+        assert_eq!(logged_out.payloads()[0]["reason"], "user_requested");
+    }
+
+    #[tokio::test]
+    async fn second_logout_finds_nothing_and_does_not_fail() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let broadcast = test_app::record(&app, "auth-logout");
+        let api = mock_api::serve(vec![Some((200, "{}"))]);
+
+        let first = logout(app.handle(), false).await;
+        let second = logout(app.handle(), false).await;
+
+        assert!(!first.already_logged_out);
+        assert!(second.already_logged_out);
+        assert!(second.local_cleared && !second.server_revoked);
+        assert!(second.errors.is_empty());
+        // Only the first had a session to revoke
+        assert_eq!(api.received().len(), 1);
+        assert_eq!(
+            broadcast.payloads()[0],
+            serde_json::json!({ "user_id": "user_1", "all": false })
+        );
+    }
+
+    /// A session as schema 1 wrote it: a bare object, before token types, scopes and expiry
+    const V1_SESSION: &str = r#"{
+        "sealed_session": "sealed",
+        "user_id": "user_1",
+        "email": "a@example.com",
+        "first_name": "Ada",
+        "last_name": null,
+        "created_at": 1700000000,
+        "schema_version": 1
+    }"#;
+
+    #[test]
+    fn v1_session_loads_with_defaults_and_is_saved_at_the_current_schema() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let v1 = SessionStore::parse(V1_SESSION.as_bytes())
+            .unwrap()
+            .active_session()
+            .unwrap();
+        assert_eq!(v1.token_type, crate::DEFAULT_TOKEN_TYPE);
+        assert!(v1.scopes.is_empty());
+        assert_eq!((v1.expires_at, v1.refresh_token.as_deref()), (None, None));
+        fake_store::set_accounts(Some(&v1), &[]);
+        let app = test_app::create();
+        let migrated = test_app::record(&app, "session-migrated");
+
+        let session = get_valid_session(app.handle()).unwrap().unwrap();
+
+        assert_eq!(session.schema_version, crate::SESSION_SCHEMA_VERSION);
+        assert_eq!(session.first_name.as_deref(), Some("Ada"));
+        assert_eq!(session.created_at, Some(1_700_000_000));
+        assert_eq!(get_session().unwrap(), Some(session));
+        assert_eq!(
+            migrated.payloads(),
+            [serde_json::json!({ "from": 1, "to": crate::SESSION_SCHEMA_VERSION })]
+        );
+    }
+
+    #[test]
+    fn v1_session_without_an_identity_forces_a_new_login() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let v1 = UserSession {
+            user_id: String::new(),
+            ..SessionStore::parse(V1_SESSION.as_bytes())
+                .unwrap()
+                .active_session()
+                .unwrap()
+        };
+        fake_store::set_accounts(Some(&v1), &[]);
+        let app = test_app::create();
+        let logged_out = test_app::record(&app, "logged-out");
+
+        assert_eq!(get_valid_session(app.handle()).unwrap(), None);
+
+        assert_eq!(get_session().unwrap(), None);
+        assert_eq!(logged_out.payloads()[0]["reason"], "session_outdated");
+    }
+
+    #[test]
+    fn login_url_carries_a_valid_device_token_only() {
+        let with_token = |token| {
+            compose_login_url(
+                "https://api.example.com",
+                &LoginOptions::default(),
+                "st",
+                "challenge",
+                Some(token),
+                None,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(login_params(&with_token("dev_1"))["device_token"], "dev_1");
+        let url = with_token("dev&prompt=none");
+        assert!(!login_params(&url).contains_key("device_token"));
+        assert!(!login_params(&url).contains_key("prompt"));
+    }
+
+    #[test]
+    fn next_login_sends_the_saved_device_token() {
+        let _dir = temp_data_dir::create();
+        let without = build_login_url(&LoginOptions::default(), "st", "challenge").unwrap();
+        assert!(!login_params(&without).contains_key("device_token"));
+
+        crate::device::save_device_token("dev_1").unwrap();
+        let url = build_login_url(&LoginOptions::default(), "st", "challenge").unwrap();
+        assert_eq!(login_params(&url)["device_token"], "dev_1");
+
+        crate::device::forget_device().unwrap();
+        let url = build_login_url(&LoginOptions::default(), "st", "challenge").unwrap();
+        assert!(!login_params(&url).contains_key("device_token"));
+    }
+
+    fn force_logout_link(query: &str) -> url::Url {
+        url::Url::parse(&format!(
+            "{}://auth/force-logout?{}",
+            crate::DEEP_LINK_SCHEME,
+            query
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn force_logout_for_the_current_user_ends_the_session() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let logged_out = test_app::record(&app, "logged-out");
+
+        handle_force_logout(
+            app.handle(),
+            &force_logout_link("user_id=user_1&reason=password_changed"),
+        )
+        .unwrap();
+
+        assert_eq!(get_session().unwrap(), None);
+        assert!(list_accounts().unwrap().is_empty());
+        let payloads = logged_out.payloads();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0]["reason"], "server_requested");
+        assert_eq!(payloads[0]["detail"], "password_changed");
+    }
+
+    #[test]
+    fn force_logout_for_another_user_only_removes_that_account() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[stored_account("user_2")]);
+        let app = test_app::create();
+        let logged_out = test_app::record(&app, "logged-out");
+
+        handle_force_logout(app.handle(), &force_logout_link("user_id=user_2")).unwrap();
+
+        assert_eq!(get_session().unwrap().unwrap().user_id, "user_1");
+        let accounts = list_accounts().unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].id, "user_1");
+        assert!(logged_out.payloads().is_empty());
+
+        // A user with no stored account at all is ignored
+        handle_force_logout(app.handle(), &force_logout_link("user_id=user_9")).unwrap();
+        assert_eq!(get_session().unwrap().unwrap().user_id, "user_1");
+        assert!(logged_out.payloads().is_empty());
+    }
+
+    #[test]
+    fn failed_login_save_keeps_the_previously_active_account() {
+        let _dir = temp_data_dir::create();
+        // The keychain locks as the new login is written, so it can't be read back
+        let _store = fake_store::inject(fake_store::FakeStore::unreadable_after_write());
+        let app = test_app::create();
+        let login = start_login(&app);
+        let successes = test_app::record(&app, "auth-success");
+        let errors = test_app::record(&app, "auth-error");
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[stored_account("user_2")]);
+
+        let err = finish_login(app.handle(), &login, stored_account("user_3"), None).unwrap_err();
+
+        assert!(matches!(err, AuthError::StorageFailed(_)));
+        let store = read_store().unwrap();
+        assert_eq!(store.active.as_deref(), Some("user_1"));
+        assert_eq!(
+            store.accounts.keys().collect::<Vec<_>>(),
+            ["user_1", "user_2"]
+        );
+        assert!(successes.payloads().is_empty());
+        assert_eq!(errors.payloads().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn each_logout_trigger_emits_logged_out_with_its_reason() {
+        let dir = temp_data_dir::create();
+        let _keychain = fake_keychain::script(Vec::new());
+        let expired = UserSession {
+            expires_at: Some(now_secs() - 1),
+            ..stored_account("user_1")
+        };
+        let too_old = UserSession {
+            created_at: Some(1),
+            max_session_age: Some(60),
+            ..stored_account("user_1")
+        };
+
+        for (session, server_reply, reason) in [
+            (expired, None, "expired"),
+            (too_old, None, "max_session_age"),
+            (
+                stored_account("user_1"),
+                Some(r#"{"error":"invalid_token"}"#),
+                "expired",
+            ),
+            (
+                stored_account("user_1"),
+                Some(r#"{"error":"session_revoked"}"#),
+                "revoked",
+            ),
+        ] {
+            let _store = fake_store::inject(fake_store::FakeStore::default());
+            fake_store::set_accounts(Some(&session), &[]);
+            let app = test_app::create();
+            let logged_out = test_app::record(&app, "logged-out");
+
+            match server_reply {
+                Some(body) => {
+                    let _api = mock_api::serve(vec![Some((401, body))]);
+                    verify_session(app.handle()).await.unwrap();
+                }
+                None => assert_eq!(get_valid_session(app.handle()).unwrap(), None),
+            }
+
+            let payloads = logged_out.payloads();
+            assert_eq!(payloads.len(), 1, "{}", reason);
+            assert_eq!(payloads[0]["reason"], reason);
+            assert_eq!(get_session().unwrap(), None, "{}", reason);
+        }
+
+        // A session file that fails its integrity check is quarantined and reported once
+        std::fs::write(dir.path().join(".addie-session.json"), "{not json").unwrap();
+        let app = test_app::create();
+        let logged_out = test_app::record(&app, "logged-out");
+        assert_eq!(get_valid_session(app.handle()).unwrap(), None);
+        assert_eq!(get_valid_session(app.handle()).unwrap(), None);
+        let payloads = logged_out.payloads();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0]["reason"], "corrupted");
+    }
+
+    const UNICODE_NAMES: [&str; 4] = ["👩🏽‍💻 Zoë", "مريم", "Jose\u{301} Nin\u{303}o", "שָׁלוֹם 🇮🇱"];
+
+    #[test]
+    fn display_names_are_capped_on_char_boundaries() {
+        for name in UNICODE_NAMES {
+            assert_eq!(cap_display_name(name), name);
+        }
+
+        let long = "👩🏽‍💻".repeat(MAX_NAME_CHARS);
+        let capped = cap_display_name(&long);
+        assert_eq!(capped.chars().count(), MAX_NAME_CHARS);
+        assert!(long.starts_with(&capped));
+    }
+
+    #[test]
+    fn unicode_names_are_stored_and_emitted_as_given() {
+        for name in UNICODE_NAMES {
+            let _dir = temp_data_dir::create();
+            let _store = fake_store::inject(fake_store::FakeStore::default());
+            let app = test_app::create();
+            let successes = test_app::record(&app, "auth-success");
+            let login = start_login(&app);
+            let query = format!(
+                "sealed_session=sealed&user_id=user_1&email=a%40example.com&first_name={}&last_name={}&state={}",
+                urlencoding::encode(name),
+                urlencoding::encode(name),
+                login.state
+            );
+
+            handle_callback(
+                app.handle(),
+                &url::Url::parse(&callback_url(&query)).unwrap(),
+            )
+            .unwrap();
+
+            let stored = get_session().unwrap().unwrap();
+            assert_eq!(stored.first_name.as_deref(), Some(name));
+            assert_eq!(stored.last_name.as_deref(), Some(name));
+            let user = &successes.payloads()[0]["user"];
+            assert_eq!(user["first_name"], name);
+            assert_eq!(user["last_name"], name);
+        }
+    }
+
+    #[tokio::test]
+    async fn session_older_than_max_age_is_rejected_despite_a_good_refresh_token() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let too_old = UserSession {
+            created_at: Some(now_secs() - 7200),
+            max_session_age: Some(3600),
+            expires_at: Some(now_secs() + 60),
+            ..refreshable_account()
+        };
+        fake_store::set_accounts(Some(&too_old), &[]);
+        let app = test_app::create();
+        let logged_out = test_app::record(&app, "logged-out");
+        let api = mock_api::serve(Vec::new());
+
+        assert_eq!(get_fresh_session(app.handle()).await.unwrap(), None);
+
+        assert!(api.received().is_empty());
+        assert_eq!(get_session().unwrap(), None);
+        assert_eq!(logged_out.payloads()[0]["reason"], "max_session_age");
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_the_original_login_time() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = UserSession {
+            created_at: Some(now_secs() - 600),
+            ..refreshable_account()
+        };
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let _api = mock_api::serve(vec![Some((200, ROTATED_REFRESH))]);
+
+        refresh_session(app.handle()).await.unwrap();
+
+        let refreshed = get_session().unwrap().unwrap();
+        assert_eq!(refreshed.refresh_token.as_deref(), Some("refresh_2"));
+        assert_eq!(refreshed.created_at, session.created_at);
+    }
+
+    #[tokio::test]
+    async fn account_portal_opens_an_allowed_path_with_a_ticket() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let api = mock_api::serve(vec![Some((200, r#"{"ticket":"tkt_1"}"#))]);
+
+        open_account_portal(app.handle(), "/account/billing")
+            .await
+            .unwrap();
+
+        let received = api.received();
+        assert_eq!(received[0].path, "/auth/portal-ticket");
+        let opened = url::Url::parse(&last_opened_url().unwrap()).unwrap();
+        assert_eq!(opened.path(), "/account/billing");
+        assert_eq!(login_params(opened.as_str())["ticket"], "tkt_1");
+        // Only the ticket goes in the URL, never the session itself
+        assert!(!opened.as_str().contains("tok_user_1"));
+    }
+
+    #[tokio::test]
+    async fn account_portal_refuses_paths_off_the_allowlist() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let api = mock_api::serve(Vec::new());
+
+        for path in [
+            "https://evil.example/account",
+            "//evil.example/account",
+            "/admin",
+            "account",
+        ] {
+            let err = open_account_portal(app.handle(), path).await.unwrap_err();
+            assert!(err.to_string().contains("not allowed"), "{}", path);
+        }
+
+        assert!(api.received().is_empty());
+        assert_eq!(last_opened_url(), None);
+    }
+
+    #[tokio::test]
+    async fn account_portal_opens_without_a_ticket_when_none_is_issued() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let _api = mock_api::serve(vec![Some((500, "{}"))]);
+
+        open_account_portal(app.handle(), "/account").await.unwrap();
+
+        let opened = last_opened_url().unwrap();
+        assert!(opened.ends_with("/account"), "{}", opened);
+        assert!(!login_params(&opened).contains_key("ticket"));
+    }
+
+    #[test]
+    fn seconds_until_expiry_for_future_past_and_unknown_expiry() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        assert_eq!(seconds_until_expiry().unwrap(), None);
+
+        let expiring = |expires_at| UserSession {
+            expires_at,
+            created_at: None,
+            ..stored_account("user_1")
+        };
+        fake_store::set_accounts(Some(&expiring(None)), &[]);
+        assert_eq!(seconds_until_expiry().unwrap(), None);
+
+        fake_store::set_accounts(Some(&expiring(Some(now_secs() + 300))), &[]);
+        let remaining = seconds_until_expiry().unwrap().unwrap();
+        assert!((299..=300).contains(&remaining), "{}", remaining);
+
+        let expired = expiring(Some(now_secs() - 60));
+        fake_store::set_accounts(Some(&expired), &[]);
+        let remaining = seconds_until_expiry().unwrap().unwrap();
+        assert!((-61..=-60).contains(&remaining), "{}", remaining);
+        // Only reports: the expired session is neither refreshed nor cleared
+        assert_eq!(get_session().unwrap(), Some(expired));
+
+        let unbounded = UserSession {
+            created_at: Some(now_secs()),
+            max_session_age: Some(u64::MAX),
+            ..expiring(None)
+        };
+        fake_store::set_accounts(Some(&unbounded), &[]);
+        // A deadline past i64::MAX saturates rather than wrapping negative
+        let remaining = seconds_until_expiry().unwrap().unwrap();
+        assert!(
+            remaining > i64::MAX - now_secs() as i64 - 2,
+            "{}",
+            remaining
+        );
+    }
+
+    #[test]
+    fn jwt_claims_are_decoded_without_the_sensitive_ones() {
+        let token = jwt(serde_json::json!({
+            "iss": "https://auth.example.com",
+            "sub": "user_1",
+            "aud": ["addie"],
+            "exp": 1_700_003_600,
+            "org_id": "org_1",
+            "email": "a@example.com",
+            "sid": "session_secret",
+        }));
+
+        let claims = decode_displayable_claims(&token).unwrap();
+        assert_eq!(
+            claims.keys().collect::<Vec<_>>(),
+            ["aud", "exp", "iss", "org_id", "sub"]
+        );
+        assert_eq!(claims["sub"], "user_1");
+        assert_eq!(claims["exp"], 1_700_003_600);
+    }
+
+    #[test]
+    fn opaque_tokens_have_no_claims() {
+        for token in [
+            "Fe26.2**opaque-sealed-session",
+            "sealed",
+            "a.b",
+            "a.not base64!.c",
+            "a.b.c.d",
+            // Decodes, but to a JSON string rather than an object
+            &format!(
+                "a.{}.c",
+                base64::Engine::encode(
+                    &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+                    r#""sub""#
+                )
+            ),
+        ] {
+            assert_eq!(decode_displayable_claims(token), None, "{}", token);
+        }
+    }
+
+    #[test]
+    fn session_claims_reads_the_stored_token() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        assert_eq!(session_claims().unwrap(), None);
+
+        let session = UserSession {
+            sealed_session: jwt(serde_json::json!({ "sub": "user_1", "email": "a@example.com" })),
+            ..stored_account("user_1")
+        };
+        fake_store::set_accounts(Some(&session), &[]);
+        let claims = session_claims().unwrap().unwrap();
+        assert_eq!(
+            serde_json::Value::Object(claims),
+            serde_json::json!({ "sub": "user_1" })
+        );
+
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        assert_eq!(session_claims().unwrap(), None);
+    }
+
+    #[test]
+    fn internal_return_to_comes_back_in_auth_success() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let successes = test_app::record(&app, "auth-success");
+        let options = LoginOptions {
+            return_to: Some("/chat/42?tab=files".to_string()),
+            ..Default::default()
+        };
+
+        start_oauth_flow(app.handle(), &options, None).unwrap();
+        let login = app.state::<PendingLogins>().0.lock().unwrap()[0].clone();
+        // Only the opaque state goes to the server, not the route
+        assert!(!login.login_url.contains("chat"), "{}", login.login_url);
+        let query = format!(
+            "sealed_session=sealed&user_id=user_1&email=a%40example.com&state={}",
+            login.state
+        );
+        handle_callback(
+            app.handle(),
+            &url::Url::parse(&callback_url(&query)).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(successes.payloads()[0]["return_to"], "/chat/42?tab=files");
+    }
+
+    #[test]
+    fn external_return_to_is_refused_before_the_browser_opens() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+
+        for return_to in ["https://evil.example/", "//evil.example", "/admin"] {
+            let options = LoginOptions {
+                return_to: Some(return_to.to_string()),
+                ..Default::default()
+            };
+            let err = start_oauth_flow(app.handle(), &options, None).unwrap_err();
+            assert!(
+                matches!(err, AuthError::InvalidReturnTo(ref route) if route == return_to),
+                "{}",
+                return_to
+            );
+        }
+
+        assert_eq!(last_opened_url(), None);
+        assert!(app.state::<PendingLogins>().0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn start_login_while_signed_in_skips_the_browser_unless_forced() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let states = test_app::record(&app, "auth-state-changed");
+
+        start_oauth_flow(app.handle(), &LoginOptions::default(), None).unwrap();
+
+        assert_eq!(last_opened_url(), None);
+        assert!(app.state::<PendingLogins>().0.lock().unwrap().is_empty());
+        assert_eq!(states.payloads()[0]["status"], "authenticated");
+        assert_eq!(states.payloads()[0]["user"]["id"], "user_1");
+
+        let forced = LoginOptions {
+            force: true,
+            ..Default::default()
+        };
+        start_oauth_flow(app.handle(), &forced, None).unwrap();
+
+        assert!(last_opened_url().is_some());
+        assert_eq!(app.state::<PendingLogins>().0.lock().unwrap().len(), 1);
+        // Still signed in while the step-up login is under way
+        assert_eq!(get_session().unwrap().unwrap().user_id, "user_1");
+    }
+
+    #[test]
+    fn unwritable_data_dir_keeps_the_session_in_memory() {
+        let _dir = temp_data_dir::create();
+        let mut store = SessionStore::default();
+        store.upsert_active(stored_account("user_1"));
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+
+        keep_in_memory_if_unwritable(denied, &store).unwrap();
+
+        assert!(is_storage_readonly());
+        assert_eq!(get_session().unwrap(), Some(stored_account("user_1")));
+
+        // Later logins this run are kept in memory too, and the UI is told they won't persist
+        let app = test_app::create();
+        let readonly = test_app::record(&app, "storage-readonly");
+        let forced = LoginOptions {
+            force: true,
+            ..Default::default()
+        };
+        start_oauth_flow(app.handle(), &forced, None).unwrap();
+        let login = app.state::<PendingLogins>().0.lock().unwrap()[0].clone();
+        let query = format!(
+            "sealed_session=tok_user_2&user_id=user_2&email=b%40example.com&state={}",
+            login.state
+        );
+        handle_callback(
+            app.handle(),
+            &url::Url::parse(&callback_url(&query)).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(get_session().unwrap().unwrap().user_id, "user_2");
+        assert_eq!(list_accounts().unwrap().len(), 2);
+        assert_eq!(readonly.payloads().len(), 1);
+    }
+
+    #[test]
+    fn disk_full_and_other_write_errors_are_not_a_read_only_dir() {
+        let store = SessionStore::default();
+
+        let full = std::io::Error::from(std::io::ErrorKind::StorageFull);
+        let err = keep_in_memory_if_unwritable(full, &store).unwrap_err();
+        assert_eq!(err.to_string(), "Disk full, could not save session");
+
+        let other = std::io::Error::other("device went away");
+        let err = keep_in_memory_if_unwritable(other, &store).unwrap_err();
+        assert_eq!(err.to_string(), "device went away");
+
+        assert!(!is_storage_readonly());
+    }
+
+    #[test]
+    fn retrying_a_login_reopens_the_same_url_and_state() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let login = start_login(&app);
+        open_external(app.handle(), "https://agenticadvertising.org/help", false).unwrap();
+
+        retry_open_login(app.handle()).unwrap();
+
+        assert_eq!(last_opened_url().as_deref(), Some(login.login_url.as_str()));
+        assert_eq!(copy_login_url(app.handle()).unwrap(), login.login_url);
+        assert_eq!(
+            login_params(&copy_login_url(app.handle()).unwrap())["state"],
+            login.state
+        );
+        // The retried login still completes with its original state
+        let query = format!(
+            "sealed_session=sealed&user_id=user_1&email=a%40example.com&state={}",
+            login.state
+        );
+        handle_callback(
+            app.handle(),
+            &url::Url::parse(&callback_url(&query)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(get_session().unwrap().unwrap().user_id, "user_1");
+    }
+
+    #[test]
+    fn completed_or_timed_out_login_cant_be_reopened() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let login = start_login(&app);
+        let query = format!(
+            "sealed_session=sealed&user_id=user_1&email=a%40example.com&state={}",
+            login.state
+        );
+        handle_callback(
+            app.handle(),
+            &url::Url::parse(&callback_url(&query)).unwrap(),
+        )
+        .unwrap();
+
+        assert!(retry_open_login(app.handle()).is_err());
+        assert!(copy_login_url(app.handle()).is_err());
+
+        let forced = LoginOptions {
+            force: true,
+            ..Default::default()
+        };
+        start_oauth_flow(app.handle(), &forced, None).unwrap();
+        app.state::<PendingLogins>().0.lock().unwrap()[0].started_at =
+            now_secs() - PENDING_LOGIN_TTL_SECS;
+
+        let err = copy_login_url(app.handle()).unwrap_err();
+        assert_eq!(err.to_string(), "No login in progress");
+    }
+
+    #[tokio::test]
+    async fn non_bearer_token_type_and_scopes_reach_the_session_and_header() {
+        let session = parsed_session(&format!(
+            "{}&token_type=DPoP&scope=read%20%20write+offline_access",
+            VALID_QUERY
+        ));
+        assert_eq!(session.token_type, "DPoP");
+        assert_eq!(session.scopes, ["read", "write", "offline_access"]);
+        assert_eq!(
+            AuthState::authenticated(session.clone()).scopes,
+            ["read", "write", "offline_access"]
+        );
+
+        let api = mock_api::serve(vec![Some((200, ME_JSON))]);
+        api::get_me(Credentials::for_session(&session))
+            .await
+            .unwrap();
+        assert_eq!(
+            api.received()[0].header("authorization"),
+            Some("DPoP sealed")
+        );
+    }
+
+    #[tokio::test]
+    async fn callback_without_token_type_or_scope_keeps_the_legacy_defaults() {
+        let session = parsed_session(VALID_QUERY);
+        assert_eq!(session.token_type, "Bearer");
+        assert!(session.scopes.is_empty());
+
+        let api = mock_api::serve(vec![Some((200, ME_JSON))]);
+        api::get_me(Credentials::for_session(&session))
+            .await
+            .unwrap();
+        assert_eq!(
+            api.received()[0].header("authorization"),
+            Some("Bearer sealed")
+        );
+    }
+
+    #[test]
+    fn token_type_that_isnt_a_scheme_name_is_rejected() {
+        for token_type in ["Bearer%20x", "Bea%0Drer", ""] {
+            let result = parse_callback(&callback_url(&format!(
+                "{}&token_type={}",
+                VALID_QUERY, token_type
+            )));
+            assert!(
+                matches!(result, Err(AuthError::InvalidParam("token_type"))),
+                "{:?}: {:?}",
+                token_type,
+                result
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn preflight_is_ready_when_every_check_passes() {
+        let _headless = headless::simulate();
+        let api = mock_api::serve(vec![Some((200, ""))]);
+        let app = test_app::create();
+
+        let report = login_preflight(app.handle()).await;
+
+        assert!(report.ready);
+        let names: Vec<_> = report.checks.iter().map(|check| check.name).collect();
+        assert_eq!(
+            names,
+            [
+                "scheme_registered",
+                "browser_available",
+                "api_base_valid",
+                "network_reachable"
+            ]
+        );
+        assert!(report.checks.iter().all(|check| check.hint.is_none()));
+        assert_eq!(api.received()[0].method, "HEAD");
+    }
+
+    #[tokio::test]
+    async fn unreachable_api_fails_only_the_network_check() {
+        let _headless = headless::simulate();
+        let _api = mock_api::serve(vec![None]);
+        let app = test_app::create();
+
+        let report = login_preflight(app.handle()).await;
+
+        assert!(!report.ready);
+        let failed: Vec<_> = report.checks.iter().filter(|check| !check.passed).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].name, "network_reachable");
+        let hint = failed[0].hint.as_deref().unwrap();
+        assert!(hint.contains(&get_api_base_url()), "{}", hint);
+    }
+
+    #[tokio::test]
+    async fn offline_network_fails_the_preflight() {
+        let _headless = headless::simulate();
+        let api = mock_api::serve(vec![Some((200, ""))]);
+        api::set_network_simulation(api::NetworkSimulation::Offline).unwrap();
+        let app = test_app::create();
+
+        let report = login_preflight(app.handle()).await;
+        api::set_network_simulation(api::NetworkSimulation::Normal).unwrap();
+
+        assert!(!report.ready);
+        assert!(!report.checks[3].passed);
+        assert!(api.received().is_empty());
+    }
+
+    #[test]
+    fn api_base_must_be_an_http_url_with_a_host() {
+        for api_base in ["https://agenticadvertising.org", "http://127.0.0.1:8080"] {
+            assert!(check_api_base(api_base).passed, "{}", api_base);
+        }
+        for api_base in [
+            "ftp://agenticadvertising.org",
+            "not a url",
+            "file:///tmp",
+            "",
+        ] {
+            let check = check_api_base(api_base);
+            assert!(!check.passed, "{}", api_base);
+            assert!(check.hint.unwrap().contains("ADDIE_API_URL"));
+        }
+    }
+
+    const PROVIDERS_JSON: &str = r#"{"providers":[{"id":"okta","display_name":"Okta","icon_url":"https://agenticadvertising.org/okta.svg"}]}"#;
+
+    #[tokio::test]
+    async fn login_providers_come_from_the_server() {
+        let api = mock_api::serve(vec![Some((200, PROVIDERS_JSON))]);
+
+        let providers = get_login_providers().await;
+
+        assert_eq!(api.received()[0].path, "/auth/providers");
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].id, "okta");
+        assert_eq!(providers[0].display_name, "Okta");
+        assert_eq!(
+            providers[0].icon_url.as_deref(),
+            Some("https://agenticadvertising.org/okta.svg")
+        );
+    }
+
+    #[tokio::test]
+    async fn login_providers_are_cached_until_the_ttl_passes() {
+        let api = mock_api::serve(vec![Some((200, PROVIDERS_JSON)); 2]);
+
+        get_login_providers().await;
+        let cached = get_login_providers().await;
+        assert_eq!(cached[0].id, "okta");
+        assert_eq!(api.received().len(), 1);
+
+        with_login_providers(|cached| {
+            let (fetched_at, _) = cached.as_mut().unwrap();
+            *fetched_at -= LOGIN_PROVIDERS_TTL;
+        });
+        get_login_providers().await;
+        // Expired, so the server was asked again (received() drains what it returns)
+        assert_eq!(api.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn login_providers_fall_back_to_the_built_in_list() {
+        let _api = mock_api::serve(vec![Some((500, "{}"))]);
+
+        let providers = get_login_providers().await;
+
+        let ids: Vec<_> = providers
+            .iter()
+            .map(|provider| provider.id.as_str())
+            .collect();
+        assert_eq!(ids, ["google", "microsoft", "apple", "github"]);
+        // A failed fetch isn't cached, so the next call asks the server again
+        with_login_providers(|cached| assert!(cached.is_none()));
+    }
+
+    fn profile_updated_link(query: &str) -> url::Url {
+        url::Url::parse(&format!(
+            "{}://auth/profile-updated?{}",
+            crate::DEEP_LINK_SCHEME,
+            query
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn profile_updated_link_refreshes_the_stored_profile() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let api = mock_api::serve(vec![Some((
+            200,
+            r#"{"id":"user_1","email":"renamed@example.com","first_name":"Renamed","last_name":"User"}"#,
+        ))]);
+        let app = test_app::create();
+        let changed = test_app::record(&app, "auth-state-changed");
+
+        handle_profile_updated(app.handle(), &profile_updated_link("user_id=user_1")).unwrap();
+        for _ in 0..200 {
+            if !changed.payloads().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let received = api.received();
+        assert_eq!(received[0].path, "/auth/me");
+        assert_eq!(
+            received[0].header("Authorization"),
+            Some("Bearer tok_user_1")
+        );
+        let session = get_session().unwrap().unwrap();
+        assert_eq!(session.email, "renamed@example.com");
+        assert_eq!(session.first_name.as_deref(), Some("Renamed"));
+        assert_eq!(session.last_name.as_deref(), Some("User"));
+        assert_eq!(changed.payloads().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn profile_updated_link_for_another_user_is_ignored() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[stored_account("user_2")]);
+        let api = mock_api::serve(vec![Some((200, ME_JSON))]);
+        let app = test_app::create();
+        let changed = test_app::record(&app, "auth-state-changed");
+
+        handle_profile_updated(app.handle(), &profile_updated_link("user_id=user_2")).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert!(api.received().is_empty());
+        assert!(changed.payloads().is_empty());
+        let session = get_session().unwrap().unwrap();
+        assert_eq!(
+            (session.user_id.as_str(), session.email.as_str()),
+            ("user_1", "user_1@example.com")
+        );
+
+        let err =
+            handle_profile_updated(app.handle(), &profile_updated_link("user_id=")).unwrap_err();
+        assert!(matches!(err, AuthError::InvalidParam("user_id")));
+    }
+
+    #[test]
+    fn overridden_scheme_is_used_for_the_redirect_and_the_callback() {
+        let _scheme = crate::scheme_override::set("acme");
+
+        let redirect_uri = login_params(&login_url_with(&LoginOptions::default()).unwrap())
+            ["redirect_uri"]
+            .clone();
+        assert_eq!(redirect_uri, "acme://auth/callback");
+        let callback = format!("{}?{}", redirect_uri, VALID_QUERY);
+        assert!(
+            matches!(parse_callback(&callback), Ok(Some(_))),
+            "{}",
+            callback
+        );
+
+        // The default scheme is just another app's links in this build
+        let default = format!("addie://auth/callback?{}", VALID_QUERY);
+        assert!(matches!(parse_callback(&default), Ok(None)));
+
+        let _headless = headless::simulate();
+        let app = test_app::create();
+        assert!(run_deeplink_selftest(app.handle()).passed);
+    }
+
+    #[test]
+    fn last_auth_error_is_kept_until_cleared_or_a_login_succeeds() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let errors = test_app::record(&app, "auth-error");
+        let login = start_login(&app);
+        let callback = |state: &str| {
+            url::Url::parse(&callback_url(&format!(
+                "sealed_session=sealed&user_id=user_1&email=a%40example.com&state={}",
+                state
+            )))
+            .unwrap()
+        };
+
+        let before = now_secs();
+        handle_callback(app.handle(), &callback("forged")).unwrap_err();
+        let report = last_auth_error(app.handle()).unwrap();
+        assert_eq!(errors.payloads()[0]["message"], report.message.as_str());
+        assert!(report.occurred_at >= before);
+        // Reading it doesn't consume it, for a screen that mounts twice
+        assert!(last_auth_error(app.handle()).is_some());
+
+        clear_auth_error(app.handle());
+        assert!(last_auth_error(app.handle()).is_none());
+
+        handle_callback(app.handle(), &callback("forged")).unwrap_err();
+        assert!(last_auth_error(app.handle()).is_some());
+        handle_callback(app.handle(), &callback(&login.state)).unwrap();
+        assert!(last_auth_error(app.handle()).is_none());
+    }
+
+    #[test]
+    fn callback_moves_the_login_screen_to_authenticated() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let _headless = headless::simulate();
+        let app = test_app::create();
+        let phase = || *app.state::<AuthPhase>().0.lock().unwrap();
+        let states = test_app::record(&app, "auth-state-changed");
+        let order = test_app::record_order(&app, &["auth-state-changed", "auth-success"]);
+
+        let login = start_login(&app);
+        assert_eq!(phase(), LoginPhase::LoginPending);
+        assert_eq!(states.payloads()[0]["phase"], "login_pending");
+
+        let query = format!(
+            "sealed_session=sealed&user_id=user_1&email=a%40example.com&state={}",
+            login.state
+        );
+        handle_callback(
+            app.handle(),
+            &url::Url::parse(&callback_url(&query)).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(phase(), LoginPhase::Authenticated);
+        let states = states.payloads();
+        let last = states.last().unwrap();
+        assert_eq!(last["phase"], "authenticated");
+        assert_eq!(last["user"]["id"], "user_1");
+        // The hook QA reads shows the same transition
+        assert_eq!(
+            emitted_auth_states(),
+            [AuthStatus::Unauthenticated, AuthStatus::Authenticated]
+        );
+        // The state moves before auth-success, so the UI has left login when it handles it
+        assert_eq!(
+            order.payloads().last().unwrap(),
+            &serde_json::json!("auth-success")
+        );
+    }
+
+    #[test]
+    fn auth_events_say_which_platform_they_came_from() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let successes = test_app::record(&app, "auth-success");
+        let errors = test_app::record(&app, "auth-error");
+        let logged_out = test_app::record(&app, "logged-out");
+        let expected = serde_json::json!({
+            "os": std::env::consts::OS,
+            "arch": std::env::consts::ARCH,
+            "app_version": app.package_info().version.to_string(),
+        });
+
+        let login = start_login(&app);
+        let query = format!(
+            "sealed_session=sealed&user_id=user_1&email=a%40example.com&state={}",
+            login.state
+        );
+        handle_callback(
+            app.handle(),
+            &url::Url::parse(&callback_url(&query)).unwrap(),
+        )
+        .unwrap();
+        report_auth_error(app.handle(), "Login failed".to_string());
+        force_logout_local(app.handle(), LogoutReason::UserRequested, None).unwrap();
+
+        // Exactly the build info, with nothing about the user in it
+        assert_eq!(successes.payloads()[0]["platform"], expected);
+        assert_eq!(errors.payloads()[0]["platform"], expected);
+        assert_eq!(logged_out.payloads()[0]["platform"], expected);
+        // Same as get_build_info returns
+        assert_eq!(
+            serde_json::to_value(BuildInfo::current(app.handle())).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn deeplink_selftest_passes_every_stage_without_signing_in() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let _headless = headless::simulate();
+        let app = test_app::create();
+        let successes = test_app::record(&app, "auth-success");
+        let states = test_app::record(&app, "auth-state-changed");
+
+        let report = run_deeplink_selftest(app.handle());
+
+        assert!(report.passed);
+        let stages: Vec<_> = report
+            .stages
+            .iter()
+            .map(|stage| (stage.name, stage.passed))
+            .collect();
+        assert_eq!(
+            stages,
+            [
+                ("scheme_registered", true),
+                ("url_parses", true),
+                ("recognized_as_callback", true),
+                ("kept_in_batch", true),
+                ("session_extracted", true)
+            ]
+        );
+        assert_eq!(get_session().unwrap(), None);
+        assert!(successes.payloads().is_empty());
+        assert!(states.payloads().is_empty());
+    }
+
+    #[test]
+    fn selftest_shaped_link_from_outside_cannot_sign_in() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+
+        let url = callback_url(
+            "sealed_session=selftest-abc&user_id=selftest&email=selftest%40invalid&state=abc",
+        );
+        let result = crate::deep_link::route_deep_link(app.handle(), &url);
+
+        assert!(matches!(result, Err(AuthError::StateMismatch)));
+        assert_eq!(get_session().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn callback_after_a_restart_verifies_against_the_restored_login() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let login = start_login(&test_app::create());
+
+        // The first app is gone, as after a crash; only the saved file is left
+        let app = test_app::restarted();
+        let successes = test_app::record(&app, "auth-success");
+        let api = mock_api::serve(vec![Some((
+            200,
+            r#"{"sealed_session":"sealed","user_id":"user_1","email":"a@example.com"}"#,
+        ))]);
+        let query = format!("code=abc&state={}", login.state);
+        handle_callback(
+            app.handle(),
+            &url::Url::parse(&callback_url(&query)).unwrap(),
+        )
+        .unwrap();
+        for _ in 0..200 {
+            if !successes.payloads().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let body: serde_json::Value = serde_json::from_str(&api.received()[0].body).unwrap();
+        assert_eq!(body["code_verifier"], login.code_verifier.unwrap().as_str());
+        assert_eq!(get_session().unwrap().unwrap().user_id, "user_1");
+        assert_eq!(successes.payloads().len(), 1);
+        // Completing the login cleared the saved file too
+        assert!(PendingLogins::restore().0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restored_logins_expire_and_their_file_is_removed() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let mut login = start_login(&test_app::create());
+        login.started_at -= PENDING_LOGIN_TTL_SECS;
+        persist_pending_logins(&[login.clone()]);
+
+        let app = test_app::restarted();
+
+        assert!(app.state::<PendingLogins>().0.lock().unwrap().is_empty());
+        assert!(!data_file_path(PENDING_LOGINS_FILE).unwrap().exists());
+        let query = format!(
+            "sealed_session=sealed&user_id=user_1&email=a%40example.com&state={}",
+            login.state
+        );
+        let result = handle_callback(
+            app.handle(),
+            &url::Url::parse(&callback_url(&query)).unwrap(),
+        );
+        assert!(matches!(result, Err(AuthError::StateMismatch)));
+    }
+
+    fn locked_keychain() -> keyring::Error {
+        keyring::Error::NoStorageAccess("keychain is locked".into())
+    }
+
+    #[test]
+    fn storage_health_when_everything_works() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let _keychain = fake_keychain::script(vec![Ok("{}".to_string())]);
+        assert!(storage_health().last_write.is_none());
+
+        save_session_verified(&stored_account("user_1")).unwrap();
+        let health = storage_health();
+
+        assert_eq!(health.backend, "keychain");
+        assert!(health.keychain_reachable);
+        assert!(health.data_dir_writable);
+        assert!(health.encrypted);
+        assert!(health.last_write.unwrap().ok);
+        assert!(health.last_read.unwrap().ok);
+    }
+
+    #[test]
+    fn storage_health_with_a_missing_keychain_entry() {
+        let _dir = temp_data_dir::create();
+        // Nothing stored yet: the keychain answered, it just has no entry
+        let _keychain = fake_keychain::script(vec![Err(keyring::Error::NoEntry)]);
+
+        let health = storage_health();
+
+        assert!(health.keychain_reachable);
+        assert_eq!(health.backend, "keychain");
+        assert!(health.last_read.is_none() && health.last_write.is_none());
+    }
+
+    #[test]
+    fn storage_health_with_a_locked_keychain() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::failing_reads());
+        let _keychain = fake_keychain::script(vec![Err(locked_keychain())]);
+        get_session().unwrap_err();
+
+        let health = storage_health();
+
+        assert!(!health.keychain_reachable);
+        assert_eq!(health.backend, "file");
+        assert!(!health.encrypted);
+        let last_read = health.last_read.unwrap();
+        assert!(!last_read.ok);
+        assert!(last_read.error.is_some());
+    }
+
+    #[test]
+    fn storage_health_with_a_readonly_data_dir() {
+        let dir = temp_data_dir::create();
+        let _keychain = fake_keychain::script(vec![Err(locked_keychain())]);
+        set_storage_readonly();
+        // Gone from under the app, so nothing can be created in it
+        std::fs::remove_dir_all(dir.path()).unwrap();
+
+        let health = storage_health();
+
+        assert_eq!(health.backend, "memory_readonly_fallback");
+        assert!(!health.data_dir_writable);
+        assert!(!health.encrypted);
+    }
+
+    #[test]
+    fn storage_health_reports_no_secrets() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let _keychain = fake_keychain::script(vec![Ok("{}".to_string())]);
+        save_session_verified(&stored_account("user_1")).unwrap();
+
+        let json = serde_json::to_string(&storage_health()).unwrap();
+
+        assert!(!json.contains("tok_user_1"), "{}", json);
+        assert!(!json.contains("user_1@example.com"), "{}", json);
+    }
+
+    #[test]
+    fn storage_health_when_headless() {
+        let _headless = headless::simulate();
+
+        let health = storage_health();
+
+        assert_eq!(health.backend, "memory");
+        assert!(!health.keychain_reachable && !health.data_dir_writable && !health.encrypted);
+    }
+
+    #[tokio::test]
+    async fn forced_refresh_against_a_server_without_it_keeps_the_session() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = refreshable_account();
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let incompatible = test_app::record(&app, "server-incompatible");
+        let logged_out = test_app::record(&app, "logged-out");
+        let _api = mock_api::serve(vec![Some((404, "{}"))]);
+
+        let err = refresh_session(app.handle()).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            AuthError::Network(ApiError::EndpointUnavailable { ref path }) if path == "/auth/refresh"
+        ));
+        assert_eq!(get_session().unwrap(), Some(session));
+        assert!(logged_out.payloads().is_empty());
+        assert_eq!(incompatible.payloads().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn verification_against_a_server_without_it_keeps_the_session() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = stored_account("user_1");
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let incompatible = test_app::record(&app, "server-incompatible");
+        let logged_out = test_app::record(&app, "logged-out");
+        let _api = mock_api::serve(vec![Some((404, "{}"))]);
+
+        let status = verify_session(app.handle()).await.unwrap();
+
+        assert!(status.server_incompatible && !status.revoked && !status.expired);
+        assert_eq!(get_session().unwrap(), Some(session));
+        assert!(logged_out.payloads().is_empty());
+        assert_eq!(
+            incompatible.payloads(),
+            [serde_json::json!({ "path": "/auth/me" })]
+        );
+    }
+
+    #[tokio::test]
+    async fn logout_cancels_a_call_in_flight() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        // Answers only after the client has long given up on it
+        let _api = mock_api::serve_with(1, |_| {
+            std::thread::sleep(std::time::Duration::from_secs(2));
+            Some((200, ME_JSON.to_string()))
+        });
+
+        let started = std::time::Instant::now();
+        let (me, _) = tokio::join!(api::get_me(Credentials::bearer("tok_user_1")), async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            force_logout_local(app.handle(), LogoutReason::UserRequested, None).unwrap();
+        });
+
+        assert!(matches!(me, Err(ApiError::Cancelled)), "{:?}", me);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+        assert_eq!(get_session().unwrap(), None);
+    }
+
+    /// A payload that can't be serialized, standing in for whatever might break one
+    #[derive(Clone)]
+    struct Unserializable;
+
+    impl serde::Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("payload can't be serialized"))
+        }
+    }
+
+    #[test]
+    fn critical_event_falls_back_to_a_minimal_payload() {
+        let app = test_app::create();
+        let successes = test_app::record(&app, "auth-success");
+        let fallback = serde_json::json!({ "user": { "id": "user_1" }, "return_to": null });
+
+        emit_critical(
+            app.handle(),
+            "auth-success",
+            Unserializable,
+            fallback.clone(),
+        );
+        assert_eq!(successes.payloads(), std::slice::from_ref(&fallback));
+
+        // A payload that serializes is sent as is
+        emit_critical(
+            app.handle(),
+            "auth-success",
+            serde_json::json!({ "user": { "id": "user_1", "email": "a@example.com" } }),
+            fallback,
+        );
+        assert_eq!(successes.payloads()[1]["user"]["email"], "a@example.com");
+    }
+
+    #[test]
+    fn unserializable_ordinary_event_is_logged_not_sent() {
+        let app = test_app::create();
+        let events = test_app::record(&app, "deep-link");
+
+        emit_logged(app.handle(), "deep-link", Unserializable);
+
+        assert!(events.payloads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_mode_keeps_the_raw_token_in_the_backend() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let api = mock_api::serve(vec![Some((200, r#"{"ok":true}"#))]);
+
+        let handle = session_token(app.handle(), true).await.unwrap().unwrap();
+        assert!(handle.starts_with("addie_h_"), "{}", handle);
+        assert_ne!(handle, "tok_user_1");
+        // Asking again within the TTL gives the same handle
+        assert_eq!(
+            session_token(app.handle(), true).await.unwrap().unwrap(),
+            handle
+        );
+
+        let response =
+            authenticated_request(app.handle(), Some(&handle), "GET", "/campaigns", None, None)
+                .await
+                .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            api.received()[0].header("Authorization"),
+            Some("Bearer tok_user_1")
+        );
+
+        // Without the mode the frontend gets the sealed session itself
+        assert_eq!(
+            session_token(app.handle(), false).await.unwrap().as_deref(),
+            Some("tok_user_1")
+        );
+    }
+
+    #[tokio::test]
+    async fn token_handle_dies_with_the_session() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+        let app = test_app::create();
+        let handle = session_token(app.handle(), true).await.unwrap().unwrap();
+
+        force_logout_local(app.handle(), LogoutReason::UserRequested, None).unwrap();
+        fake_store::set_accounts(Some(&stored_account("user_1")), &[]);
+
+        let err =
+            authenticated_request(app.handle(), Some(&handle), "GET", "/campaigns", None, None)
+                .await
+                .unwrap_err();
+        assert_eq!(err.code, "invalid_handle");
+        let forged = authenticated_request(
+            app.handle(),
+            Some("addie_h_0"),
+            "GET",
+            "/campaigns",
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(forged.code, "invalid_handle");
+    }
+}
@@ -0,0 +1,240 @@
+//! Signing in several accounts at once from an `addie://auth/import-batch` link
+//!
+//! Enterprise onboarding tools provision accounts by handing out a link with a batch token
+//! the server signed with Ed25519: `addie://auth/import-batch?payload=<b64>&signature=<b64>`.
+//! The token is redeemed at `/auth/redeem-batch` for one session per account, and each is
+//! added to the account store. The verifying key is set at compile time with
+//! ADDIE_BATCH_IMPORT_KEY (base64); builds without one reject every batch link.
+
+use base64::Engine;
+use serde::Deserialize;
+use tauri::{AppHandle, Runtime};
+
+use crate::api;
+use crate::auth::{self, now_secs, AuthError};
+use crate::UserInfo;
+
+/// Public key batch links must be signed with (base64 Ed25519)
+const BATCH_IMPORT_KEY: Option<&str> = option_env!("ADDIE_BATCH_IMPORT_KEY");
+
+/// Most accounts one batch may sign in
+const MAX_BATCH_ACCOUNTS: usize = 20;
+
+/// What the signed payload holds
+#[derive(Debug, Deserialize)]
+struct BatchPayload {
+    /// Opaque, single-use token for /auth/redeem-batch
+    token: String,
+    /// Unix time (seconds) after which the link is refused
+    expires_at: u64,
+}
+
+/// Verify an import-batch link and redeem it in the background (emits accounts-imported)
+///
+/// Reached through deep_link::route_deep_link. A link that doesn't verify is rejected
+/// here, before anything is sent to the server.
+pub(crate) fn handle_import_batch<R: Runtime>(
+    app: &AppHandle<R>,
+    parsed: &url::Url,
+) -> Result<(), AuthError> {
+    let token = batch_key()
+        .and_then(|key| verify_batch_link(parsed, &key, now_secs()))
+        .inspect_err(|e| log::warn!("Rejected account batch link: {}", e))?;
+
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = import_batch(&app, &token).await {
+            log::warn!("Account batch import failed: {}", e);
+            auth::report_auth_error(&app, e.to_string());
+        }
+    });
+    Ok(())
+}
+
+fn batch_key() -> Result<Vec<u8>, AuthError> {
+    let key = BATCH_IMPORT_KEY.ok_or_else(|| {
+        AuthError::InvalidBatch("batch import isn't enabled in this build".to_string())
+    })?;
+    base64::engine::general_purpose::STANDARD
+        .decode(key)
+        .map_err(|e| {
+            log::error!("ADDIE_BATCH_IMPORT_KEY is not valid base64: {}", e);
+            AuthError::InvalidBatch("batch import isn't enabled in this build".to_string())
+        })
+}
+
+/// Check a link's payload against its signature and `public_key`, returning the batch token
+fn verify_batch_link(parsed: &url::Url, public_key: &[u8], now: u64) -> Result<String, AuthError> {
+    let param = |name: &'static str| {
+        let mut values = parsed.query_pairs().filter(|(key, _)| key == name);
+        match (values.next(), values.next()) {
+            (Some((_, value)), None) => Ok(value.into_owned()),
+            (None, _) => Err(AuthError::MissingParam(name)),
+            (Some(_), Some(_)) => Err(AuthError::DuplicateParam(name.to_string())),
+        }
+    };
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let payload = b64
+        .decode(param("payload")?)
+        .map_err(|_| AuthError::InvalidParam("payload"))?;
+    let signature = b64
+        .decode(param("signature")?)
+        .map_err(|_| AuthError::InvalidParam("signature"))?;
+
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(&payload, &signature)
+        .map_err(|_| AuthError::InvalidBatch("signature does not verify".to_string()))?;
+
+    let payload: BatchPayload =
+        serde_json::from_slice(&payload).map_err(|_| AuthError::InvalidParam("payload"))?;
+    if payload.expires_at <= now {
+        return Err(AuthError::InvalidBatch("link has expired".to_string()));
+    }
+    if payload.token.is_empty() {
+        return Err(AuthError::InvalidParam("token"));
+    }
+    Ok(payload.token)
+}
+
+/// Redeem a verified batch token and add every account it holds, or none of them
+async fn import_batch<R: Runtime>(
+    app: &AppHandle<R>,
+    token: &str,
+) -> Result<Vec<UserInfo>, AuthError> {
+    let redeemed = api::redeem_batch(token).await?;
+    if redeemed.sessions.is_empty() || redeemed.sessions.len() > MAX_BATCH_ACCOUNTS {
+        return Err(AuthError::InvalidBatch(format!(
+            "must hold 1 to {} accounts, got {}",
+            MAX_BATCH_ACCOUNTS,
+            redeemed.sessions.len()
+        )));
+    }
+    // Device tokens belong to an interactive login on this device, so they're not kept
+    let sessions = redeemed
+        .sessions
+        .into_iter()
+        .map(|token| auth::session_from_token(token).map(|(session, _)| session))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let users = auth::add_imported_accounts(app, sessions)?;
+    log::info!("Imported {} account(s) from a batch", users.len());
+    auth::emit_logged(app, "accounts-imported", &users);
+    Ok(users)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::mock_api;
+    use crate::auth::{fake_store, get_session, list_accounts, test_app};
+    use ring::signature::KeyPair;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn key_pair() -> ring::signature::Ed25519KeyPair {
+        let pkcs8 =
+            ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                .unwrap();
+        ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn batch_link(key: &ring::signature::Ed25519KeyPair, expires_at: u64) -> url::Url {
+        let payload = serde_json::to_vec(
+            &serde_json::json!({ "token": "batch_1", "expires_at": expires_at }),
+        )
+        .unwrap();
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let mut url = url::Url::parse("addie://auth/import-batch").unwrap();
+        url.query_pairs_mut()
+            .append_pair("payload", &b64.encode(&payload))
+            .append_pair("signature", &b64.encode(key.sign(&payload).as_ref()));
+        url
+    }
+
+    fn redeemed(count: usize) -> String {
+        let sessions: Vec<_> = (1..=count)
+            .map(|n| {
+                serde_json::json!({
+                    "sealed_session": format!("tok_{}", n),
+                    "user_id": format!("user_{}", n),
+                    "email": format!("user{}@example.com", n),
+                })
+            })
+            .collect();
+        serde_json::json!({ "sessions": sessions }).to_string()
+    }
+
+    #[test]
+    fn signed_batch_link_yields_its_token() {
+        let key = key_pair();
+        let public_key = key.public_key().as_ref();
+
+        let token = verify_batch_link(&batch_link(&key, NOW + 60), public_key, NOW).unwrap();
+        assert_eq!(token, "batch_1");
+
+        let err = verify_batch_link(&batch_link(&key, NOW), public_key, NOW).unwrap_err();
+        assert!(matches!(err, AuthError::InvalidBatch(_)));
+    }
+
+    #[test]
+    fn unsigned_or_missigned_batch_links_are_rejected() {
+        let key = key_pair();
+        let public_key = key.public_key().as_ref();
+
+        let mut unsigned = batch_link(&key, NOW + 60);
+        let payload = unsigned
+            .query_pairs()
+            .find(|(name, _)| name == "payload")
+            .map(|(_, value)| value.into_owned())
+            .unwrap();
+        unsigned
+            .query_pairs_mut()
+            .clear()
+            .append_pair("payload", &payload);
+        let err = verify_batch_link(&unsigned, public_key, NOW).unwrap_err();
+        assert!(matches!(err, AuthError::MissingParam("signature")));
+
+        let other_key = key_pair();
+        let err =
+            verify_batch_link(&batch_link(&other_key, NOW + 60), public_key, NOW).unwrap_err();
+        assert!(matches!(err, AuthError::InvalidBatch(_)));
+
+        let mut doubled = batch_link(&key, NOW + 60);
+        doubled.query_pairs_mut().append_pair("payload", &payload);
+        let err = verify_batch_link(&doubled, public_key, NOW).unwrap_err();
+        assert!(matches!(err, AuthError::DuplicateParam(_)));
+    }
+
+    #[tokio::test]
+    async fn redeemed_batch_adds_every_account() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let imported = test_app::record(&app, "accounts-imported");
+        let batch = redeemed(2);
+        let api = mock_api::serve_with(1, move |_| Some((200, batch.clone())));
+
+        let users = import_batch(app.handle(), "batch_1").await.unwrap();
+
+        assert_eq!(users.len(), 2);
+        let received = api.received();
+        assert_eq!(received[0].path, "/auth/redeem-batch");
+        assert!(received[0].body.contains("batch_1"));
+        // Nobody was signed in, so the first imported account became the active one
+        assert_eq!(get_session().unwrap().unwrap().user_id, "user_1");
+        assert_eq!(list_accounts().unwrap().len(), 2);
+        assert_eq!(imported.payloads()[0][1]["id"], "user_2");
+    }
+
+    #[tokio::test]
+    async fn oversized_batch_is_rejected_whole() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let batch = redeemed(MAX_BATCH_ACCOUNTS + 1);
+        let _api = mock_api::serve_with(1, move |_| Some((200, batch.clone())));
+
+        let err = import_batch(app.handle(), "batch_1").await.unwrap_err();
+
+        assert!(matches!(err, AuthError::InvalidBatch(_)));
+        assert!(list_accounts().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,290 @@
+//! Effective runtime configuration, for support
+//!
+//! Resolves every setting the way the code that uses it does and records where each
+//! value came from. Nothing here is secret, and nothing secret may be added.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{api, auth, locale, prefs, routes, session_timer, DEEP_LINK_SCHEME};
+
+/// Where a configuration value came from, highest precedence first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Env,
+    Preference,
+    Server,
+    Default,
+}
+
+/// One resolved setting and its source
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigValue {
+    pub value: serde_json::Value,
+    pub source: ConfigSource,
+}
+
+impl ConfigValue {
+    fn new(value: impl Into<serde_json::Value>, source: ConfigSource) -> Self {
+        Self {
+            value: value.into(),
+            source,
+        }
+    }
+
+    /// Env when the variable is set, else Default
+    fn env_flag(var: &str, value: impl Into<serde_json::Value>) -> Self {
+        let source = if std::env::var_os(var).is_some() {
+            ConfigSource::Env
+        } else {
+            ConfigSource::Default
+        };
+        Self::new(value, source)
+    }
+
+    /// The first candidate that is set, with its source, else `default` from Default
+    ///
+    /// Candidates are given highest precedence first.
+    fn resolve(
+        candidates: impl IntoIterator<Item = (ConfigSource, Option<serde_json::Value>)>,
+        default: impl Into<serde_json::Value>,
+    ) -> Self {
+        candidates
+            .into_iter()
+            .find_map(|(source, value)| Some(Self::new(value?, source)))
+            .unwrap_or_else(|| Self::new(default, ConfigSource::Default))
+    }
+}
+
+/// Every setting that applies right now, keyed by name
+pub fn effective_config() -> BTreeMap<&'static str, ConfigValue> {
+    let session = auth::get_session().ok().flatten();
+    let mut config = BTreeMap::new();
+
+    config.insert(
+        "api_base",
+        ConfigValue::env_flag("ADDIE_API_URL", auth::get_api_base_url()),
+    );
+    // Compile-time env, so whitelabel builds show up as Env
+    let scheme_source = match option_env!("ADDIE_DEEP_LINK_SCHEME") {
+        Some(_) => ConfigSource::Env,
+        None => ConfigSource::Default,
+    };
+    config.insert(
+        "deep_link_scheme",
+        ConfigValue::new(DEEP_LINK_SCHEME, scheme_source),
+    );
+    config.insert(
+        "request_timeout_secs",
+        ConfigValue::new(api::REQUEST_TIMEOUT.as_secs(), ConfigSource::Default),
+    );
+    config.insert(
+        "max_attempts",
+        ConfigValue::new(api::MAX_ATTEMPTS, ConfigSource::Default),
+    );
+    config.insert(
+        "retry_backoff_ms",
+        ConfigValue::new(api::RETRY_BACKOFF.as_millis() as u64, ConfigSource::Default),
+    );
+    config.insert(
+        "route_allowlist",
+        ConfigValue::resolve(
+            [(
+                ConfigSource::Server,
+                routes::server_routes().map(Into::into),
+            )],
+            routes::INTERNAL_ROUTES,
+        ),
+    );
+    config.insert(
+        "trusted_link_domains",
+        ConfigValue::new(auth::TRUSTED_LINK_DOMAINS, ConfigSource::Default),
+    );
+    config.insert(
+        "token_handles",
+        ConfigValue::env_flag("ADDIE_TOKEN_HANDLES", api::token_handles_enabled()),
+    );
+    config.insert(
+        "headless",
+        ConfigValue::env_flag("ADDIE_HEADLESS", auth::is_headless()),
+    );
+    config.insert(
+        "require_pkce",
+        ConfigValue::env_flag("ADDIE_REQUIRE_PKCE", auth::is_pkce_required()),
+    );
+    config.insert(
+        "session_import",
+        ConfigValue::env_flag(
+            "ADDIE_ALLOW_SESSION_IMPORT",
+            auth::is_session_import_enabled(),
+        ),
+    );
+
+    // The server's value wins over the env default, as in session_deadline
+    let server_max_age = session.as_ref().and_then(|session| session.max_session_age);
+    config.insert(
+        "max_session_age_secs",
+        ConfigValue::resolve(
+            [
+                (ConfigSource::Server, server_max_age.map(Into::into)),
+                (
+                    ConfigSource::Env,
+                    auth::get_default_max_session_age().map(Into::into),
+                ),
+            ],
+            serde_json::Value::Null,
+        ),
+    );
+
+    config.insert(
+        "expiry_warning_secs",
+        ConfigValue::env_flag(
+            "ADDIE_EXPIRY_WARNING_SECS",
+            session_timer::expiry_warning_lead().as_secs(),
+        ),
+    );
+
+    config.insert(
+        "login_locale",
+        ConfigValue::resolve(
+            [(
+                ConfigSource::Preference,
+                locale::get_override().map(Into::into),
+            )],
+            locale::get_login_locale(),
+        ),
+    );
+
+    let user_id = session.as_ref().map(|session| session.user_id.as_str());
+    let warming = user_id
+        .and_then(|user_id| prefs::get_preferences(user_id).ok())
+        .and_then(|prefs| prefs.get("warm_connection")?.as_bool());
+    config.insert(
+        "warm_connection",
+        ConfigValue::resolve(
+            [(ConfigSource::Preference, warming.map(Into::into))],
+            prefs::is_connection_warming_enabled(None),
+        ),
+    );
+    let background_refresh = user_id
+        .and_then(|user_id| prefs::get_preferences(user_id).ok())
+        .and_then(|prefs| prefs.get("background_refresh")?.as_bool());
+    config.insert(
+        "background_refresh",
+        ConfigValue::resolve(
+            [(ConfigSource::Preference, background_refresh.map(Into::into))],
+            prefs::is_background_refresh_enabled(None),
+        ),
+    );
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn resolve(env: Option<u64>, preference: Option<u64>) -> ConfigValue {
+        ConfigValue::resolve(
+            [
+                (ConfigSource::Env, env.map(Into::into)),
+                (ConfigSource::Preference, preference.map(Into::into)),
+            ],
+            1,
+        )
+    }
+
+    #[test]
+    fn preference_overrides_default() {
+        let value = resolve(None, Some(5));
+        assert_eq!(
+            (value.value, value.source),
+            (json!(5), ConfigSource::Preference)
+        );
+    }
+
+    #[test]
+    fn env_overrides_preference() {
+        let value = resolve(Some(9), Some(5));
+        assert_eq!((value.value, value.source), (json!(9), ConfigSource::Env));
+    }
+
+    #[test]
+    fn falls_back_to_default() {
+        let value = resolve(None, None);
+        assert_eq!(
+            (value.value, value.source),
+            (json!(1), ConfigSource::Default)
+        );
+    }
+
+    #[test]
+    fn resolves_with_no_data_dir_and_lists_server_max_age() {
+        let _store = auth::fake_store::inject(auth::fake_store::FakeStore::default());
+        let session: crate::UserSession = serde_json::from_value(json!({
+            "sealed_session": "sealed",
+            "user_id": "user_1",
+            "email": "a@example.com",
+            "first_name": null,
+            "last_name": null,
+            "max_session_age": 3600,
+        }))
+        .unwrap();
+        auth::fake_store::set_accounts(Some(&session), &[]);
+
+        let config = effective_config();
+        let max_age = &config["max_session_age_secs"];
+        assert_eq!(
+            (&max_age.value, max_age.source),
+            (&json!(3600), ConfigSource::Server)
+        );
+        assert_eq!(config["warm_connection"].source, ConfigSource::Default);
+        assert_eq!(config["background_refresh"].value, json!(true));
+    }
+
+    #[test]
+    fn sources_reflect_env_and_preferences_in_effect() {
+        let _dir = auth::temp_data_dir::create();
+        let _store = auth::fake_store::inject(auth::fake_store::FakeStore::default());
+        let session: crate::UserSession = serde_json::from_value(json!({
+            "sealed_session": "sealed",
+            "user_id": "user_1",
+            "email": "a@example.com",
+            "first_name": null,
+            "last_name": null,
+        }))
+        .unwrap();
+        auth::fake_store::set_accounts(Some(&session), &[]);
+        prefs::set_preferences(
+            "user_1",
+            json!({ "warm_connection": false, "background_refresh": false }),
+        )
+        .unwrap();
+
+        let config = effective_config();
+        for name in ["warm_connection", "background_refresh"] {
+            assert_eq!(
+                (&config[name].value, config[name].source),
+                (&json!(false), ConfigSource::Preference),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(config["expiry_warning_secs"].source, ConfigSource::Default);
+
+        // The only env var here no other test depends on
+        std::env::set_var("ADDIE_EXPIRY_WARNING_SECS", "45");
+        let config = effective_config();
+        std::env::remove_var("ADDIE_EXPIRY_WARNING_SECS");
+        assert_eq!(
+            (
+                &config["expiry_warning_secs"].value,
+                config["expiry_warning_secs"].source
+            ),
+            (&json!(45), ConfigSource::Env)
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Routing of incoming `addie://` links
+//!
+//! Every deep link, whether it launched the app or arrived while it was running, comes
+//! through route_deep_link. Auth links are handled in the backend; app links (from
+//! notifications and the like) are handed to the frontend as a `deep-link` event so it
+//! can navigate.
+
+use serde::Serialize;
+use tauri::{AppHandle, Runtime};
+
+use crate::auth::{self, AuthError};
+use crate::batch_import;
+use crate::redact;
+use crate::routes;
+
+const MAX_CAMPAIGN_ID_LEN: usize = 64;
+
+/// Payload of the deep-link event, discriminated by `route`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "route", rename_all = "snake_case")]
+pub enum DeepLinkRoute {
+    /// `addie://campaign/<id>`
+    Campaign { id: String },
+    /// `addie://open/<path>`, where path is an allowed internal route
+    Open { path: String },
+}
+
+/// Parse a deep link once and dispatch it by host and path
+///
+/// Links for unknown routes are logged and ignored, never treated as an error.
+pub fn route_deep_link<R: Runtime>(app: &AppHandle<R>, url: &str) -> Result<(), AuthError> {
+    // Reject oversized links before parsing so junk can't exhaust memory
+    if url.len() > auth::MAX_CALLBACK_URL_LEN {
+        log::warn!(
+            "Rejecting deep link: {} bytes exceeds limit of {}",
+            url.len(),
+            auth::MAX_CALLBACK_URL_LEN
+        );
+        return Err(AuthError::CallbackTooLarge);
+    }
+
+    let parsed = url::Url::parse(url)?;
+    if parsed.scheme() != crate::deep_link_scheme() {
+        log::info!("Ignoring deep link for scheme {}", parsed.scheme());
+        return Ok(());
+    }
+
+    // The url crate keeps a custom scheme's host as given, and some OSes uppercase it
+    let host = parsed.host_str().unwrap_or_default().to_ascii_lowercase();
+    match (host.as_str(), parsed.path()) {
+        ("auth", "/callback") => auth::handle_callback(app, &parsed),
+        ("auth", "/force-logout") => auth::handle_force_logout(app, &parsed),
+        ("auth", "/profile-updated") => auth::handle_profile_updated(app, &parsed),
+        ("auth", "/import-batch") => batch_import::handle_import_batch(app, &parsed),
+        ("campaign", path) => match path.strip_prefix('/').filter(|id| is_valid_campaign_id(id)) {
+            Some(id) => emit_route(app, DeepLinkRoute::Campaign { id: id.to_string() }),
+            None => ignore(&host, path),
+        },
+        ("open", path) if routes::is_allowed_route(path) => {
+            let path = match parsed.query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.to_string(),
+            };
+            emit_route(app, DeepLinkRoute::Open { path })
+        }
+        (_, path) => ignore(&host, path),
+    }
+}
+
+/// Campaign ids are opaque server ids; anything else in that position is junk
+fn is_valid_campaign_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_CAMPAIGN_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn emit_route<R: Runtime>(app: &AppHandle<R>, route: DeepLinkRoute) -> Result<(), AuthError> {
+    log::info!("Routing deep link to frontend: {}", redact::redact(&route));
+    auth::emit_logged(app, "deep-link", route);
+    Ok(())
+}
+
+/// Log an unroutable link by host and path only; the query may carry tokens
+fn ignore(host: &str, path: &str) -> Result<(), AuthError> {
+    log::info!(
+        "Ignoring deep link with unknown route: {}://{}{}",
+        crate::deep_link_scheme(),
+        host,
+        path
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{fake_store, test_app};
+
+    fn link(rest: &str) -> String {
+        format!("{}://{}", crate::DEEP_LINK_SCHEME, rest)
+    }
+
+    #[test]
+    fn app_routes_are_emitted_for_the_frontend() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let routed = test_app::record(&app, "deep-link");
+
+        route_deep_link(app.handle(), &link("campaign/cmp_42")).unwrap();
+        route_deep_link(app.handle(), &link("OPEN/chat?thread=7")).unwrap();
+
+        assert_eq!(
+            routed.payloads(),
+            [
+                serde_json::json!({ "route": "campaign", "id": "cmp_42" }),
+                serde_json::json!({ "route": "open", "path": "/chat?thread=7" })
+            ]
+        );
+    }
+
+    #[test]
+    fn unroutable_links_are_ignored() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let routed = test_app::record(&app, "deep-link");
+
+        for rest in [
+            "nowhere/chat",
+            "auth/unknown",
+            "campaign/",
+            "campaign/has%20space",
+            "open/admin",
+            "open//evil.example.com",
+            "open/chat/../admin",
+            "open/chat/%2e%2e/admin",
+        ] {
+            route_deep_link(app.handle(), &link(rest)).unwrap();
+        }
+        route_deep_link(app.handle(), "https://example.com/open/chat").unwrap();
+
+        assert!(routed.payloads().is_empty());
+    }
+
+    #[test]
+    fn oversized_links_are_rejected_before_parsing() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        let url = link(&format!(
+            "open/chat?{}",
+            "x".repeat(auth::MAX_CALLBACK_URL_LEN)
+        ));
+
+        let result = route_deep_link(app.handle(), &url);
+
+        assert!(matches!(result, Err(AuthError::CallbackTooLarge)));
+    }
+
+    #[test]
+    fn only_links_for_the_built_in_scheme_are_routed() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let _scheme = crate::scheme_override::set("acme");
+        let app = test_app::create();
+        let routed = test_app::record(&app, "deep-link");
+
+        route_deep_link(app.handle(), "addie://campaign/cmp_1").unwrap();
+        route_deep_link(app.handle(), "acme://campaign/cmp_2").unwrap();
+
+        assert_eq!(
+            routed.payloads(),
+            [serde_json::json!({ "route": "campaign", "id": "cmp_2" })]
+        );
+    }
+}
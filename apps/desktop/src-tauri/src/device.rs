@@ -0,0 +1,126 @@
+//! Trusted-device ("remember this device") token
+//!
+//! Issued by the server on login so later logins from this install can skip MFA.
+//! Kept apart from the session so it survives logout; only forget_device removes it.
+
+use std::sync::Mutex;
+
+use crate::auth::{data_file_path, is_headless, write_private_file};
+
+const DEVICE_TOKEN_FILE: &str = ".addie-device-token";
+
+/// Longest device token we'll store
+const MAX_DEVICE_TOKEN_LEN: usize = 512;
+
+/// Token held in memory instead of on disk (headless mode)
+static MEMORY_DEVICE_TOKEN: Mutex<Option<String>> = Mutex::new(None);
+
+/// Whether a device token is a plain opaque value, safe to put in a URL unencoded
+pub fn is_valid_device_token(token: &str) -> bool {
+    !token.is_empty()
+        && token.len() <= MAX_DEVICE_TOKEN_LEN
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~'))
+}
+
+/// The stored device token, if the server has issued one to this install
+pub fn device_token() -> Option<String> {
+    if is_headless() {
+        return MEMORY_DEVICE_TOKEN.lock().unwrap().clone();
+    }
+
+    data_file_path(DEVICE_TOKEN_FILE)
+        .and_then(std::fs::read_to_string)
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| is_valid_device_token(token))
+}
+
+/// Store the device token from a login, replacing any earlier one
+pub fn save_device_token(token: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !is_valid_device_token(token) {
+        return Err("Invalid device token".into());
+    }
+
+    if is_headless() {
+        *MEMORY_DEVICE_TOKEN.lock().unwrap() = Some(token.to_string());
+        return Ok(());
+    }
+
+    // It lets a login skip MFA, so other local users must not be able to read it
+    write_private_file(&data_file_path(DEVICE_TOKEN_FILE)?, token.as_bytes())?;
+    Ok(())
+}
+
+/// Forget this device, so the next login asks for MFA again
+pub fn forget_device() -> Result<(), Box<dyn std::error::Error>> {
+    *MEMORY_DEVICE_TOKEN.lock().unwrap() = None;
+    if is_headless() {
+        return Ok(());
+    }
+
+    let path = data_file_path(DEVICE_TOKEN_FILE)?;
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{fake_store, temp_data_dir, test_app};
+
+    #[test]
+    fn only_plain_opaque_tokens_are_valid() {
+        assert!(is_valid_device_token("dev_AZ09-_.~"));
+        assert!(is_valid_device_token(&"a".repeat(MAX_DEVICE_TOKEN_LEN)));
+
+        assert!(!is_valid_device_token(""));
+        assert!(!is_valid_device_token(
+            &"a".repeat(MAX_DEVICE_TOKEN_LEN + 1)
+        ));
+        for token in ["dev 1", "dev&prompt=none", "dev%20", "dév"] {
+            assert!(!is_valid_device_token(token), "{}", token);
+        }
+    }
+
+    #[test]
+    fn saved_token_is_read_back_and_replaces_the_last() {
+        let _dir = temp_data_dir::create();
+        assert_eq!(device_token(), None);
+
+        save_device_token("dev_1").unwrap();
+        save_device_token("dev_2").unwrap();
+        assert_eq!(device_token().as_deref(), Some("dev_2"));
+
+        assert!(save_device_token("dev&x").is_err());
+        assert_eq!(device_token().as_deref(), Some("dev_2"));
+    }
+
+    #[test]
+    fn tampered_token_file_is_ignored() {
+        let dir = temp_data_dir::create();
+        std::fs::write(dir.path().join(DEVICE_TOKEN_FILE), "dev 1&prompt=none").unwrap();
+
+        assert_eq!(device_token(), None);
+    }
+
+    #[test]
+    fn token_survives_logout_until_the_device_is_forgotten() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let app = test_app::create();
+        save_device_token("dev_1").unwrap();
+
+        crate::auth::force_logout_local(app.handle(), crate::LogoutReason::UserRequested, None)
+            .unwrap();
+        assert_eq!(device_token().as_deref(), Some("dev_1"));
+
+        forget_device().unwrap();
+        assert_eq!(device_token(), None);
+        // Forgetting again, with nothing stored, is fine
+        forget_device().unwrap();
+    }
+}
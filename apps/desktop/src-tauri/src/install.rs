@@ -0,0 +1,109 @@
+//! Per-install support id
+//!
+//! A random, non-PII id that stays the same across logins so support can tie a
+//! user's report to server logs. Sent as `X-Addie-Install-Id` on API requests, and
+//! replaced by a new one when local data is purged.
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::auth::{data_file_path, is_headless, write_private_file};
+
+const INSTALL_ID_FILE: &str = ".addie-install-id";
+
+/// Loaded id, so the file is read at most once per run
+static INSTALL_ID: Mutex<Option<String>> = Mutex::new(None);
+
+/// Get this install's support id, creating and persisting one on first use
+///
+/// If the id can't be persisted it's still stable for the rest of the run.
+pub fn install_id() -> String {
+    let mut cached = INSTALL_ID.lock().unwrap();
+    if let Some(id) = cached.as_ref() {
+        return id.clone();
+    }
+
+    let path = if is_headless() {
+        None
+    } else {
+        data_file_path(INSTALL_ID_FILE).ok()
+    };
+    let id = load_or_create(path.as_deref());
+    *cached = Some(id.clone());
+    id
+}
+
+/// The id stored at `path`, or a new one written there
+fn load_or_create(path: Option<&Path>) -> String {
+    let stored = path.and_then(|path| {
+        std::fs::read_to_string(path)
+            .ok()
+            .map(|id| id.trim().to_string())
+            .filter(|id| uuid::Uuid::parse_str(id).is_ok())
+    });
+
+    stored.unwrap_or_else(|| {
+        let id = uuid::Uuid::new_v4().to_string();
+        if let Some(path) = path {
+            if let Err(e) = write_private_file(path, id.as_bytes()) {
+                log::warn!("Failed to persist install id: {}", e);
+            }
+        }
+        id
+    })
+}
+
+/// Forget the support id, so the next request or support screen gets a new one
+pub fn reset_install_id() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cached = INSTALL_ID.lock().unwrap();
+    *cached = None;
+    if is_headless() {
+        return Ok(());
+    }
+
+    let path = data_file_path(INSTALL_ID_FILE)?;
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{self, mock_api};
+    use crate::auth::temp_data_dir;
+
+    #[test]
+    fn persisted_id_is_reused_by_the_next_run() {
+        let dir = temp_data_dir::create();
+        let path = dir.path().join(INSTALL_ID_FILE);
+
+        let id = load_or_create(Some(&path));
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), id);
+        assert_eq!(load_or_create(Some(&path)), id);
+    }
+
+    #[test]
+    fn reset_replaces_the_persisted_id() {
+        let dir = temp_data_dir::create();
+        let path = dir.path().join(INSTALL_ID_FILE);
+        let id = load_or_create(Some(&path));
+
+        reset_install_id().unwrap();
+        assert!(!path.exists());
+        assert!(INSTALL_ID.lock().unwrap().is_none());
+        assert_ne!(load_or_create(Some(&path)), id);
+    }
+
+    #[tokio::test]
+    async fn requests_carry_the_install_id() {
+        let api = mock_api::serve(vec![Some((200, "{}"))]);
+
+        let _: serde_json::Value = api::get("/app/version", None).await.unwrap();
+        let received = api.received();
+        let id = received[0].header("X-Addie-Install-Id").unwrap();
+        assert!(uuid::Uuid::parse_str(id).is_ok(), "{}", id);
+    }
+}
@@ -4,27 +4,193 @@
 //! - OAuth deep link authentication (addie://auth/callback)
 //! - Secure session storage via system keychain
 //! - API communication with AgenticAdvertising.org
+//! - Per-user UI preferences
 
 use serde::{Deserialize, Serialize};
-use tauri::AppHandle;
+use tauri::{AppHandle, Manager, Runtime};
 
+mod api;
 mod auth;
+mod batch_import;
+mod config;
+mod deep_link;
+mod device;
+mod install;
+mod locale;
+mod prefs;
+mod quarantine;
+mod redact;
+mod routes;
+mod session_timer;
+mod telemetry;
+#[cfg(desktop)]
+mod window;
+
+// Exposed for the fuzz targets in fuzz/, not part of the app's API
+#[doc(hidden)]
+pub use auth::{parse_callback, AuthCallback, CallbackGrant};
+
+/// Custom URL scheme the app handles deep links for
+///
+/// Whitelabel builds override it with ADDIE_DEEP_LINK_SCHEME at compile time (lowercase,
+/// and matching the deep-link schemes in the Tauri config, which build.rs enforces). Both
+/// the login redirect_uri and callback validation read it, so they can't disagree.
+const DEEP_LINK_SCHEME: &str = match option_env!("ADDIE_DEEP_LINK_SCHEME") {
+    Some(scheme) => scheme,
+    None => "addie",
+};
+
+/// The scheme deep links are built and checked with, always DEEP_LINK_SCHEME outside tests
+#[cfg(not(test))]
+fn deep_link_scheme() -> &'static str {
+    DEEP_LINK_SCHEME
+}
+
+/// Overridable per thread in unit tests, to run the flow as a whitelabel build would
+#[cfg(test)]
+fn deep_link_scheme() -> &'static str {
+    scheme_override::current().unwrap_or(DEEP_LINK_SCHEME)
+}
+
+/// Build-time scheme override, simulated for one test thread
+#[cfg(test)]
+pub(crate) mod scheme_override {
+    use std::cell::Cell;
+
+    thread_local! {
+        static SCHEME: Cell<Option<&'static str>> = const { Cell::new(None) };
+    }
+
+    /// This thread uses `scheme` as if built with ADDIE_DEEP_LINK_SCHEME, until dropped
+    pub(crate) struct SchemeOverride;
+
+    impl Drop for SchemeOverride {
+        fn drop(&mut self) {
+            SCHEME.with(|scheme| scheme.set(None));
+        }
+    }
+
+    pub(crate) fn set(scheme: &'static str) -> SchemeOverride {
+        SCHEME.with(|current| current.set(Some(scheme)));
+        SchemeOverride
+    }
+
+    pub(super) fn current() -> Option<&'static str> {
+        SCHEME.with(Cell::get)
+    }
+}
+
+/// Events the backend emits to the frontend, the only ones debug_emit_event will send
+const AUTH_EVENTS: &[&str] = &[
+    "auth-success",
+    "auth-error",
+    "auth-state-changed",
+    "logged-out",
+    "storage-readonly",
+    "server-incompatible",
+    "login-metric",
+    "session-migrated",
+    "auth-refreshed",
+    "auth-logout",
+    "keychain-locked",
+    "account-merged",
+    "accounts-pruned",
+    "accounts-refreshed",
+    "accounts-imported",
+    "session-expiring-soon",
+    "deep-link",
+];
+
+/// Authorization scheme used when the server doesn't say otherwise
+const DEFAULT_TOKEN_TYPE: &str = "Bearer";
+
+fn default_token_type() -> String {
+    DEFAULT_TOKEN_TYPE.to_string()
+}
+
+/// Version of the stored UserSession layout; bump when a field needs migrating
+const SESSION_SCHEMA_VERSION: u32 = 2;
 
 /// User session data stored securely
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UserSession {
     pub sealed_session: String,
     pub user_id: String,
     pub email: String,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
+    /// Unix time (seconds) of the login that produced this session
+    #[serde(default)]
+    pub created_at: Option<u64>,
+    /// Server-mandated maximum session age in seconds, regardless of token validity
+    #[serde(default)]
+    pub max_session_age: Option<u64>,
+    /// Authorization scheme for the token (sessions saved before this existed are Bearer)
+    #[serde(default = "default_token_type")]
+    pub token_type: String,
+    /// Scopes granted at login
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Layout version this session was saved with (0 for sessions saved before versioning)
+    #[serde(default)]
+    pub schema_version: u32,
+    /// Unix time (seconds) the sealed session stops being accepted, when the server says
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Exchanged at /auth/refresh for a new sealed session before this one expires
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+}
+
+/// Whether the user is signed in, as far as the backend can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthStatus {
+    Authenticated,
+    Unauthenticated,
+    /// The session store couldn't be read; retry rather than treating this as logged out
+    Unknown,
+}
+
+/// Where the login lifecycle is; only changed through auth::transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginPhase {
+    SignedOut,
+    /// The browser was sent to the login page and no callback has arrived yet
+    LoginPending,
+    Authenticated,
+    /// The last login attempt failed; the user is still signed out
+    Failed,
+}
+
+impl LoginPhase {
+    /// Whether the lifecycle may move from this phase to `next`
+    ///
+    /// Leaving Authenticated is only possible by signing out, so a failed or pending
+    /// re-login can never hide a session that is still stored.
+    pub fn can_transition_to(self, next: LoginPhase) -> bool {
+        use LoginPhase::*;
+        match (self, next) {
+            (Authenticated, Authenticated | SignedOut) => true,
+            (Authenticated, LoginPending | Failed) => false,
+            // A callback can arrive without a pending phase, e.g. after a restart
+            (SignedOut | LoginPending | Failed, _) => true,
+        }
+    }
 }
 
 /// Auth state for the frontend
 #[derive(Debug, Clone, Serialize)]
 pub struct AuthState {
+    pub status: AuthStatus,
+    pub phase: LoginPhase,
     pub is_authenticated: bool,
     pub user: Option<UserInfo>,
+    /// Scopes granted to the session, for gating features (empty when signed out)
+    pub scopes: Vec<String>,
+    /// What went wrong when status is Unknown or phase is Failed
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -33,102 +199,1203 @@ pub struct UserInfo {
     pub email: String,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
+    /// Set with set_account_label; only list_accounts fills these in
+    pub label: Option<String>,
+    pub color: Option<String>,
 }
 
-/// Get current authentication state
-#[tauri::command]
-async fn get_auth_state() -> Result<AuthState, String> {
-    match auth::get_session() {
-        Ok(Some(session)) => Ok(AuthState {
+impl AuthState {
+    pub fn authenticated(session: UserSession) -> Self {
+        AuthState {
+            status: AuthStatus::Authenticated,
+            phase: LoginPhase::Authenticated,
             is_authenticated: true,
             user: Some(UserInfo {
                 id: session.user_id,
                 email: session.email,
                 first_name: session.first_name,
                 last_name: session.last_name,
+                label: None,
+                color: None,
             }),
-        }),
-        Ok(None) => Ok(AuthState {
+            scopes: session.scopes,
+            error: None,
+        }
+    }
+
+    pub fn unauthenticated() -> Self {
+        AuthState {
+            status: AuthStatus::Unauthenticated,
+            phase: LoginPhase::SignedOut,
+            is_authenticated: false,
+            user: None,
+            scopes: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn pending() -> Self {
+        AuthState {
+            phase: LoginPhase::LoginPending,
+            ..AuthState::unauthenticated()
+        }
+    }
+
+    pub fn failed(error: String) -> Self {
+        AuthState {
+            phase: LoginPhase::Failed,
+            error: Some(error),
+            ..AuthState::unauthenticated()
+        }
+    }
+
+    pub fn unknown(error: String) -> Self {
+        AuthState {
+            status: AuthStatus::Unknown,
+            phase: LoginPhase::SignedOut,
             is_authenticated: false,
             user: None,
-        }),
-        Err(e) => Err(format!("Failed to get auth state: {}", e)),
+            scopes: Vec::new(),
+            error: Some(error),
+        }
+    }
+
+    /// State for a session read; a store that couldn't be read is Unknown, not signed out
+    pub fn from_session_read<E: std::fmt::Display>(
+        read: Result<Option<UserSession>, E>,
+        signed_out: impl FnOnce() -> AuthState,
+    ) -> Self {
+        match read {
+            Ok(Some(session)) => AuthState::authenticated(session),
+            Ok(None) => signed_out(),
+            Err(e) => {
+                log::error!("Failed to get auth state: {}", e);
+                AuthState::unknown(format!("Failed to get auth state: {}", e))
+            }
+        }
+    }
+}
+
+/// Why the user was signed out, sent with the `logged-out` event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogoutReason {
+    /// The user chose to log out
+    UserRequested,
+    /// The session outlived the maximum session age
+    MaxSessionAge,
+    /// The server reports the token as expired
+    Expired,
+    /// The server no longer accepts the session
+    Revoked,
+    /// The server asked for a logout via addie://auth/force-logout
+    ServerRequested,
+    /// The stored session was too old to migrate to the current layout
+    SessionOutdated,
+    /// The stored session failed its integrity check and was quarantined
+    Corrupted,
+}
+
+/// Options the frontend can pass to start_login
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LoginOptions {
+    /// Friendly provider name: google, microsoft, apple or github
+    pub provider: Option<String>,
+    /// WorkOS SSO connection id (conn_...)
+    pub connection: Option<String>,
+    /// Internal route to land on after login, echoed back in auth-success
+    pub return_to: Option<String>,
+    /// Open the login page even if already signed in (step-up or adding an account)
+    #[serde(default)]
+    pub force: bool,
+    /// What the identity provider should ask for: none (default), login, select_account or consent
+    pub prompt: Option<String>,
+}
+
+/// Result of checking the stored session against the server
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionVerification {
+    pub valid: bool,
+    pub expired: bool,
+    pub revoked: bool,
+    pub network_error: bool,
+    /// The server doesn't have the endpoint; the session is kept
+    pub server_incompatible: bool,
+    pub server_user_matches_local: bool,
+}
+
+/// How a stored account fared when refresh_all_accounts checked it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountHealth {
+    /// The server accepts the session
+    Valid,
+    /// The session was close to expiring and has been exchanged for a new one
+    Refreshed,
+    /// The server no longer accepts the session, so the account was signed out
+    Dead,
+    /// The server couldn't say (offline, timed out, endpoint missing); the account is kept
+    Unknown,
+}
+
+/// One account's result from refresh_all_accounts, also sent in accounts-refreshed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccountStatus {
+    pub user_id: String,
+    pub health: AccountHealth,
+}
+
+/// Where the app is running, attached to auth events so forwarded telemetry is self-describing
+///
+/// Deliberately holds nothing about the user or machine beyond the platform.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub app_version: String,
+}
+
+impl BuildInfo {
+    pub fn current<R: tauri::Runtime>(app: &AppHandle<R>) -> Self {
+        BuildInfo {
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            app_version: app.package_info().version.to_string(),
+        }
+    }
+}
+
+/// One check run by login_preflight
+#[derive(Debug, Clone, Serialize)]
+pub struct PreflightCheck {
+    pub name: &'static str,
+    pub passed: bool,
+    /// What the user can do about a failed check
+    pub hint: Option<String>,
+}
+
+impl PreflightCheck {
+    pub fn pass(name: &'static str) -> Self {
+        PreflightCheck {
+            name,
+            passed: true,
+            hint: None,
+        }
+    }
+
+    pub fn fail(name: &'static str, hint: impl Into<String>) -> Self {
+        PreflightCheck {
+            name,
+            passed: false,
+            hint: Some(hint.into()),
+        }
     }
 }
 
+/// Everything that could stop a login from working, checked up front
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginPreflight {
+    /// All checks passed
+    pub ready: bool,
+    pub checks: Vec<PreflightCheck>,
+}
+
+/// A login failure the UI may have missed the auth-error event for
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthErrorReport {
+    pub message: String,
+    /// Unix time (seconds) the error happened
+    pub occurred_at: u64,
+}
+
+/// Outcome of run_deeplink_selftest, one entry per pipeline stage
+#[derive(Debug, Clone, Serialize)]
+pub struct DeepLinkSelfTest {
+    pub passed: bool,
+    pub stages: Vec<PreflightCheck>,
+}
+
+/// How one session read or write went
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageOutcome {
+    pub ok: bool,
+    pub error: Option<String>,
+    /// Unix time (seconds) of the operation
+    pub at: u64,
+}
+
+/// Status and JSON body of a successful api_request call
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiResponse {
+    pub status: u16,
+    /// null for empty responses
+    pub body: serde_json::Value,
+}
+
+/// Why an api_request call failed, with a stable code the UI can branch on
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiRequestError {
+    /// e.g. not_signed_in, invalid_path, unauthorized, rate_limited, http_error, network,
+    /// or an AuthError code when the session couldn't be loaded
+    pub code: &'static str,
+    /// HTTP status when the server answered
+    pub status: Option<u16>,
+    pub message: String,
+    /// JSON body the server sent with an error status (e.g. validation details)
+    pub body: Option<serde_json::Value>,
+}
+
+impl ApiRequestError {
+    /// A failure caught before anything was sent
+    pub fn local(code: &'static str, message: impl Into<String>) -> Self {
+        ApiRequestError {
+            code,
+            status: None,
+            message: message.into(),
+            body: None,
+        }
+    }
+}
+
+/// What a logout managed to clear, so the UI can warn about partial failures
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LogoutOutcome {
+    /// The session is gone from this device (memory, keychain and session file); logged-out
+    /// is only emitted when this is true
+    pub local_cleared: bool,
+    pub keychain_cleared: bool,
+    pub file_cleared: bool,
+    pub server_revoked: bool,
+    /// Nobody was signed in, so there was nothing to clear or revoke (not an error)
+    pub already_logged_out: bool,
+    pub errors: Vec<String>,
+}
+
+/// State of session storage for the diagnostics screen (never includes the session)
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageHealth {
+    /// Where sessions are kept: keychain, file (keychain unavailable), memory (headless)
+    /// or memory_readonly_fallback
+    pub backend: &'static str,
+    pub keychain_reachable: bool,
+    pub data_dir_writable: bool,
+    /// Whether the stored session is encrypted at rest (the keychain is; the session file is plain JSON)
+    pub encrypted: bool,
+    pub last_write: Option<StorageOutcome>,
+    pub last_read: Option<StorageOutcome>,
+}
+
+/// Result of checking the server for a newer app build
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateInfo {
+    pub update_available: bool,
+    pub latest_version: String,
+    pub notes_url: Option<String>,
+    /// The UI should block usage until the user updates
+    pub mandatory: bool,
+}
+
+/// Get current authentication state
+///
+/// A storage read failure is reported as status Unknown rather than an error,
+/// so the UI can retry instead of treating it as a logout.
+#[tauri::command]
+async fn get_auth_state(app: AppHandle) -> Result<AuthState, auth::AuthError> {
+    Ok(AuthState::from_session_read(
+        auth::get_valid_session(&app),
+        || auth::signed_out_state(&app),
+    ))
+}
+
+/// Every signed-in account on this device, for the account switcher
+#[tauri::command]
+async fn list_accounts() -> Result<Vec<UserInfo>, auth::AuthError> {
+    auth::list_accounts().map_err(|e| auth::AuthError::StorageFailed(e.to_string()))
+}
+
+/// Make another signed-in account the active one (emits auth-state-changed)
+#[tauri::command]
+async fn switch_account(app: AppHandle, user_id: String) -> Result<AuthState, auth::AuthError> {
+    auth::switch_account(&app, &user_id)
+}
+
+/// Name and color an account in the account picker; None clears either
+#[tauri::command]
+async fn set_account_label(
+    user_id: String,
+    label: Option<String>,
+    color: Option<String>,
+) -> Result<UserInfo, auth::AuthError> {
+    auth::set_account_label(&user_id, label, color)
+}
+
+/// Forget stored accounts that need a new login to use (emits accounts-pruned)
+#[tauri::command]
+async fn prune_accounts(app: AppHandle) -> Result<Vec<String>, auth::AuthError> {
+    auth::prune_accounts(&app)
+}
+
+/// Check every stored account with the server, for the account picker (emits
+/// accounts-refreshed)
+#[tauri::command]
+async fn refresh_all_accounts(app: AppHandle) -> Result<Vec<AccountStatus>, auth::AuthError> {
+    auth::refresh_all_accounts(&app).await
+}
+
 /// Get the sealed session token for API calls
+///
+/// With ADDIE_TOKEN_HANDLES=1 this is an opaque, short-lived handle for api_request instead.
 #[tauri::command]
-async fn get_session_token() -> Result<Option<String>, String> {
-    match auth::get_session() {
-        Ok(Some(session)) => Ok(Some(session.sealed_session)),
-        Ok(None) => Ok(None),
-        Err(e) => Err(format!("Failed to get session: {}", e)),
+async fn get_session_token(app: AppHandle) -> Result<Option<String>, auth::AuthError> {
+    auth::session_token(&app, api::token_handles_enabled()).await
+}
+
+/// Exchange the refresh token for a new sealed session (emits auth-refreshed)
+#[tauri::command]
+async fn refresh_session(app: AppHandle) -> Result<AuthState, String> {
+    match auth::refresh_session(&app).await {
+        Ok(Some(session)) => Ok(AuthState::authenticated(session)),
+        Ok(None) => Ok(auth::signed_out_state(&app)),
+        Err(e) => Err(format!("Failed to refresh session: {}", e)),
     }
 }
 
+/// Authenticated call to `{api_base}{path}` made in the backend, so the token stays here
+///
+/// `handle` is optional; when given (token-handle mode) it must be the live one. Pass the
+/// same `idempotency_key` when retrying a write; otherwise each call gets a new one.
+#[tauri::command]
+async fn api_request(
+    app: AppHandle,
+    method: String,
+    path: String,
+    body: Option<serde_json::Value>,
+    handle: Option<String>,
+    idempotency_key: Option<String>,
+) -> Result<ApiResponse, ApiRequestError> {
+    auth::authenticated_request(
+        &app,
+        handle.as_deref(),
+        &method,
+        &path,
+        body.as_ref(),
+        idempotency_key.as_deref(),
+    )
+    .await
+}
+
+/// Re-read the session from storage and broadcast the result as auth-state-changed
+#[tauri::command]
+async fn reload_session_from_store(app: AppHandle) -> Result<AuthState, String> {
+    auth::reload_session_from_store(&app).map_err(|e| format!("Failed to reload session: {}", e))
+}
+
+/// Seconds until the session expires (negative if already expired, None if unknown)
+#[tauri::command]
+async fn seconds_until_expiry() -> Result<Option<i64>, String> {
+    auth::seconds_until_expiry().map_err(|e| e.to_string())
+}
+
+/// Which storage backend is active and whether it's healthy, for diagnostics
+#[tauri::command]
+async fn storage_health() -> Result<StorageHealth, String> {
+    Ok(auth::storage_health())
+}
+
+/// Non-secret claims of the session token for diagnostics (None for opaque tokens)
+#[tauri::command]
+async fn session_claims() -> Result<Option<auth::Claims>, String> {
+    auth::session_claims().map_err(|e| e.to_string())
+}
+
+/// Re-fetch the signed-in user's name and email from the server
+#[tauri::command]
+async fn fetch_profile(app: AppHandle) -> Result<AuthState, String> {
+    match auth::fetch_profile(&app).await {
+        Ok(Some(session)) => Ok(AuthState::authenticated(session)),
+        Ok(None) => Ok(auth::signed_out_state(&app)),
+        Err(e) => Err(format!("Failed to fetch profile: {}", e)),
+    }
+}
+
+/// Verify the stored session with the server and report why it's unusable
+#[tauri::command]
+async fn verify_session(app: AppHandle) -> Result<SessionVerification, String> {
+    auth::verify_session(&app).await.map_err(|e| e.to_string())
+}
+
+/// Report whether login is likely to work, with a hint for each failing check
+#[tauri::command]
+async fn login_preflight(app: AppHandle) -> Result<LoginPreflight, String> {
+    Ok(auth::login_preflight(&app).await)
+}
+
+/// Sign-in options to show, from the server when reachable
+#[tauri::command]
+async fn get_login_providers() -> Result<Vec<api::LoginProviderInfo>, String> {
+    Ok(auth::get_login_providers().await)
+}
+
+/// Locale the login pages will be shown in (None leaves it to the server)
+#[tauri::command]
+async fn get_login_locale() -> Result<Option<String>, String> {
+    Ok(locale::get_login_locale())
+}
+
+/// Override the login page locale with a BCP 47 tag, or pass None to follow the OS again
+#[tauri::command]
+async fn set_login_locale(locale: Option<String>) -> Result<(), String> {
+    locale::set_login_locale(locale.as_deref()).map_err(|e| e.to_string())
+}
+
 /// Start OAuth login flow - opens system browser
 #[tauri::command]
-async fn start_login(app: AppHandle) -> Result<(), String> {
-    auth::start_oauth_flow(&app).map_err(|e| e.to_string())
+async fn start_login(
+    app: AppHandle,
+    webview_window: tauri::WebviewWindow,
+    options: Option<LoginOptions>,
+) -> Result<(), auth::AuthError> {
+    let options = options.unwrap_or_default();
+    auth::start_oauth_flow(&app, &options, Some(webview_window.label()))
+}
+
+/// Re-open the browser for the login in progress after a failed launch
+#[tauri::command]
+async fn retry_open_login(app: AppHandle) -> Result<(), String> {
+    auth::retry_open_login(&app).map_err(|e| e.to_string())
+}
+
+/// Authorize URL of the login in progress, for the UI to copy to the clipboard
+#[tauri::command]
+async fn copy_login_url(app: AppHandle) -> Result<String, String> {
+    auth::copy_login_url(&app).map_err(|e| e.to_string())
+}
+
+/// Open the account/billing dashboard already signed in
+#[tauri::command]
+async fn open_account_portal(app: AppHandle, path: String) -> Result<(), String> {
+    auth::open_account_portal(&app, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Open an http(s) link in the system browser
+///
+/// Set server_supplied for links that came from server content; those must point
+/// at a trusted domain.
+#[tauri::command]
+async fn open_external(
+    app: AppHandle,
+    url: String,
+    server_supplied: Option<bool>,
+) -> Result<(), String> {
+    auth::open_external(&app, &url, server_supplied.unwrap_or(false)).map_err(|e| e.to_string())
+}
+
+/// Re-register the app as the addie:// handler, returning whether the re-check passes
+///
+/// Only supported where schemes can be registered at runtime (Windows, Linux); on Linux
+/// this writes or updates the app's .desktop handler entry.
+#[tauri::command]
+async fn register_deep_link_scheme(app: AppHandle) -> Result<bool, String> {
+    use tauri_plugin_deep_link::DeepLinkExt;
+
+    let deep_link = app.deep_link();
+    reregister_scheme(
+        |scheme| deep_link.register(scheme),
+        |scheme| deep_link.is_registered(scheme),
+    )
+}
+
+/// Register DEEP_LINK_SCHEME (never any other) with `register`, then re-check it
+fn reregister_scheme<E: std::fmt::Display>(
+    register: impl FnOnce(&str) -> Result<(), E>,
+    is_registered: impl FnOnce(&str) -> Result<bool, E>,
+) -> Result<bool, String> {
+    register(DEEP_LINK_SCHEME)
+        .map_err(|e| format!("Failed to register {}:// handler: {}", DEEP_LINK_SCHEME, e))?;
+
+    let registered = is_registered(DEEP_LINK_SCHEME)
+        .map_err(|e| format!("Failed to check {}:// handler: {}", DEEP_LINK_SCHEME, e))?;
+
+    log::info!(
+        "Deep link scheme re-registered, handler active: {}",
+        registered
+    );
+    Ok(registered)
+}
+
+/// Check the deep link pipeline with a synthetic callback that is never saved
+#[tauri::command]
+async fn run_deeplink_selftest(app: AppHandle) -> Result<DeepLinkSelfTest, String> {
+    Ok(auth::run_deeplink_selftest(&app))
+}
+
+/// Feed a deep link through the normal handler, as if the OS had delivered it
+///
+/// Only available in headless mode (ADDIE_HEADLESS=1) for end-to-end tests.
+#[tauri::command]
+async fn simulate_deep_link<R: Runtime>(app: AppHandle<R>, url: String) -> Result<(), String> {
+    if !auth::is_headless() {
+        return Err("simulate_deep_link is only available in headless mode".to_string());
+    }
+    deep_link::route_deep_link(&app, &url).map_err(|e| e.to_string())
+}
+
+/// URL the app would have opened in the browser last (headless mode only)
+#[tauri::command]
+async fn last_opened_url() -> Result<Option<String>, String> {
+    if !auth::is_headless() {
+        return Err("last_opened_url is only available in headless mode".to_string());
+    }
+    Ok(auth::last_opened_url())
+}
+
+/// Why the last login failed, if it hasn't succeeded since
+#[tauri::command]
+async fn last_auth_error(app: AppHandle) -> Result<Option<AuthErrorReport>, String> {
+    Ok(auth::last_auth_error(&app))
+}
+
+/// Dismiss the last login failure
+#[tauri::command]
+async fn clear_auth_error(app: AppHandle) -> Result<(), String> {
+    auth::clear_auth_error(&app);
+    Ok(())
+}
+
+/// Statuses sent in auth-state-changed so far, oldest first (headless mode only)
+///
+/// Lets end-to-end tests assert that a callback moved the app from the login screen to authenticated.
+#[tauri::command]
+async fn emitted_auth_states() -> Result<Vec<AuthStatus>, String> {
+    if !auth::is_headless() {
+        return Err("emitted_auth_states is only available in headless mode".to_string());
+    }
+    Ok(auth::emitted_auth_states())
+}
+
+/// Sign in with a pasted sealed session (only when ADDIE_ALLOW_SESSION_IMPORT=1)
+#[tauri::command]
+async fn import_session_token(app: AppHandle, token: String) -> Result<(), String> {
+    auth::import_session_token(&app, &token)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Get the signed-in user's UI preferences
+#[tauri::command]
+async fn get_preferences(app: AppHandle) -> Result<serde_json::Value, String> {
+    let session = auth::get_valid_session(&app)
+        .map_err(|e| e.to_string())?
+        .ok_or("Not signed in")?;
+    prefs::get_preferences(&session.user_id).map_err(|e| e.to_string())
+}
+
+/// Replace the signed-in user's UI preferences
+#[tauri::command]
+async fn set_preferences(app: AppHandle, preferences: serde_json::Value) -> Result<(), String> {
+    let session = auth::get_valid_session(&app)
+        .map_err(|e| e.to_string())?
+        .ok_or("Not signed in")?;
+    prefs::set_preferences(&session.user_id, preferences).map_err(|e| e.to_string())
+}
+
+/// Turn refreshing the session ahead of its expiry on or off for the signed-in user
+#[tauri::command]
+async fn set_background_refresh(app: AppHandle, enabled: bool) -> Result<(), String> {
+    session_timer::set_background_refresh(&app, enabled).map_err(|e| e.to_string())
+}
+
+/// Ask the server whether a newer build exists; never downloads anything
+#[tauri::command]
+async fn check_for_update(app: AppHandle, channel: Option<String>) -> Result<UpdateInfo, String> {
+    let channel = channel.unwrap_or_else(|| "stable".to_string());
+    update_info(&app.package_info().version, &channel).await
+}
+
+/// What the server says about builds newer than `current` on `channel`
+async fn update_info(current: &semver::Version, channel: &str) -> Result<UpdateInfo, String> {
+    let latest = api::get_latest_version(std::env::consts::OS, channel, &current.to_string())
+        .await
+        .map_err(|e| format!("Failed to check for updates: {}", e))?;
+
+    let latest_version = semver::Version::parse(&latest.latest_version)
+        .map_err(|e| format!("Server sent an invalid version: {}", e))?;
+    let update_available = latest_version > *current;
+
+    Ok(UpdateInfo {
+        update_available,
+        latest_version: latest.latest_version,
+        notes_url: latest.notes_url,
+        mandatory: update_available && latest.mandatory,
+    })
+}
+
+/// Simulate slow or offline networking for API calls (debug builds only, for QA)
+#[tauri::command]
+async fn set_network_simulation(mode: api::NetworkSimulation) -> Result<(), String> {
+    api::set_network_simulation(mode)
+}
+
+/// Emit one of the backend's auth events with a made-up payload (debug builds only)
+///
+/// Lets frontend work on every auth state without going through a real login.
+#[tauri::command]
+async fn debug_emit_event(
+    app: AppHandle,
+    name: String,
+    payload: serde_json::Value,
+) -> Result<(), String> {
+    check_debug_event(&name, cfg!(debug_assertions))?;
+
+    use tauri::Emitter;
+    app.emit(&name, payload).map_err(|e| e.to_string())
+}
+
+/// Whether debug_emit_event may send `name` in this build
+fn check_debug_event(name: &str, debug_build: bool) -> Result<(), String> {
+    if !debug_build {
+        return Err("debug_emit_event is only available in debug builds".to_string());
+    }
+    if !AUTH_EVENTS.contains(&name) {
+        return Err(format!("Unknown event: {}", name));
+    }
+    Ok(())
+}
+
+/// Forget this device's trusted-device token, so the next login asks for MFA again
+#[tauri::command]
+async fn forget_device() -> Result<(), String> {
+    device::forget_device().map_err(|e| e.to_string())
+}
+
+/// Sessions set aside after failing integrity checks, for diagnostics (never the bytes)
+#[tauri::command]
+async fn list_quarantined() -> Result<Vec<quarantine::QuarantinedSession>, String> {
+    quarantine::list_quarantined().map_err(|e| e.to_string())
+}
+
+/// Delete a quarantined session, or all of them when no id is given
+#[tauri::command]
+async fn discard_quarantined(id: Option<String>) -> Result<(), String> {
+    quarantine::discard_quarantined(id.as_deref()).map_err(|e| e.to_string())
 }
 
-/// Log out - clear stored session
+/// Fully resolved, secret-free configuration with the source of each value, for support
 #[tauri::command]
-async fn logout() -> Result<(), String> {
-    auth::clear_session().map_err(|e| e.to_string())
+async fn effective_config(
+) -> Result<std::collections::BTreeMap<&'static str, config::ConfigValue>, String> {
+    Ok(config::effective_config())
+}
+
+/// OS, architecture and version of this build
+#[tauri::command]
+async fn get_build_info(app: AppHandle) -> Result<BuildInfo, String> {
+    Ok(BuildInfo::current(&app))
+}
+
+/// Opt in or out of login metrics tagged with the install id (off by default)
+#[tauri::command]
+async fn set_telemetry(enabled: bool) -> Result<(), String> {
+    telemetry::set_enabled(enabled).map_err(|e| e.to_string())
+}
+
+/// Local login funnel counts since the app started, for the user's own diagnostics
+#[tauri::command]
+async fn telemetry_counters() -> Result<std::collections::BTreeMap<&'static str, u64>, String> {
+    Ok(telemetry::counters())
+}
+
+/// Stable, non-PII id for this install, for the "contact support" screen
+#[tauri::command]
+async fn get_support_id() -> Result<String, String> {
+    Ok(install::install_id())
+}
+
+/// Wipe everything this install keeps locally, as if it were freshly installed
+///
+/// Logs out every account, then forgets the device token, login locale, telemetry
+/// opt-in, quarantined sessions and support id. Every step runs even if one fails.
+#[tauri::command]
+async fn purge_local_data(app: AppHandle) -> Result<LogoutOutcome, auth::AuthError> {
+    let outcome = log_out_accounts(&app, true).await?;
+
+    let steps = [
+        ("device token", device::forget_device()),
+        ("login locale", locale::set_login_locale(None)),
+        ("telemetry opt-in", telemetry::set_enabled(false)),
+        (
+            "quarantined sessions",
+            quarantine::discard_quarantined(None),
+        ),
+        ("support id", install::reset_install_id()),
+    ];
+    let failed: Vec<&str> = steps
+        .into_iter()
+        .filter_map(|(what, result)| {
+            let e = result.err()?;
+            log::warn!("Failed to purge {}: {}", what, e);
+            Some(what)
+        })
+        .collect();
+    if !failed.is_empty() {
+        return Err(auth::AuthError::StorageFailed(format!(
+            "Could not purge: {}",
+            failed.join(", ")
+        )));
+    }
+    Ok(outcome)
+}
+
+/// Log out the active account - clear its stored session and preferences
+///
+/// Other signed-in accounts stay signed in; see logout_all.
+#[tauri::command]
+async fn logout(app: AppHandle) -> Result<LogoutOutcome, auth::AuthError> {
+    log_out_accounts(&app, false).await
+}
+
+/// Log out every account signed in on this device
+#[tauri::command]
+async fn logout_all(app: AppHandle) -> Result<LogoutOutcome, auth::AuthError> {
+    log_out_accounts(&app, true).await
+}
+
+async fn log_out_accounts(app: &AppHandle, all: bool) -> Result<LogoutOutcome, auth::AuthError> {
+    let user_ids: Vec<String> = if all {
+        auth::list_accounts()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|user| user.id)
+            .collect()
+    } else {
+        auth::get_session()
+            .ok()
+            .flatten()
+            .map(|session| session.user_id)
+            .into_iter()
+            .collect()
+    };
+    let outcome = auth::logout(app, all).await;
+
+    if outcome.local_cleared {
+        for user_id in user_ids {
+            if let Err(e) = prefs::clear_preferences(&user_id) {
+                log::warn!("Failed to clear preferences: {}", e);
+            }
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// Handle a batch of deep links delivered together, at most one login per batch
+///
+/// Each URL is handled independently, so one bad URL never stops the rest of the batch.
+fn handle_deep_links(handle: &AppHandle, urls: &[url::Url]) {
+    // Some platforms deliver an empty list; there's nothing to do
+    if urls.is_empty() {
+        log::info!("Deep link event carried no URLs, ignoring");
+        return;
+    }
+
+    let urls: Vec<String> = urls.iter().map(|url| url.to_string()).collect();
+    for url in auth::coalesce_deep_links(&urls) {
+        log::debug!("Processing deep link: {}", redact::redact_url(&url));
+        if let Err(e) = deep_link::route_deep_link(handle, &url) {
+            log::warn!(
+                "Failed to handle deep link, continuing with the rest: {}",
+                e
+            );
+        }
+    }
+}
+
+/// Deep links for our scheme among command-line arguments; anything else is ignored
+fn deep_links_from_args(args: impl IntoIterator<Item = String>) -> Vec<url::Url> {
+    args.into_iter()
+        .filter_map(|arg| url::Url::parse(&arg).ok())
+        .filter(|url| url.scheme() == deep_link_scheme())
+        .collect()
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    tauri::Builder::default()
+    // Registered first so everything from setup on is logged; secrets go through redact
+    let log_level = if cfg!(debug_assertions) {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+    let builder = tauri::Builder::default()
+        .plugin(tauri_plugin_log::Builder::new().level(log_level).build())
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_deep_link::init())
+        .manage(auth::LastAuthError::default())
+        .manage(session_timer::SessionTimer::default());
+
+    // Track window focus so deep links bring the right window forward
+    #[cfg(desktop)]
+    let builder = builder
+        .manage(window::FocusTracker::default())
+        .on_window_event(window::on_window_event);
+
+    builder
         .setup(|app| {
-            // Register deep link handler for OAuth callback
+            // Everything below may read stored state, so the data dir comes first
+            auth::init_data_dir(app.handle());
+            app.manage(auth::PendingLogins::restore());
+            app.manage(auth::AuthPhase::restore());
+
+            // Route deep links the same way at cold start and while running
             let handle = app.handle().clone();
 
             {
                 use tauri_plugin_deep_link::DeepLinkExt;
 
-                println!("Setting up deep link handler...");
+                log::debug!("Setting up deep link handler...");
 
                 // Check if app was launched via deep link (covers cold start case)
-                if let Ok(Some(urls)) = app.deep_link().get_current() {
-                    println!("App launched with deep link URLs: {:?}", urls);
-                    for url in urls {
-                        println!("Processing startup URL: {}", url.as_str());
-                        if let Err(e) = auth::handle_deep_link(&handle, url.as_str()) {
-                            eprintln!("Failed to handle startup deep link: {}", e);
-                        }
+                let mut urls = match app.deep_link().get_current() {
+                    Ok(urls) => urls.unwrap_or_default(),
+                    Err(e) => {
+                        log::warn!("Failed to read startup deep link: {}", e);
+                        Vec::new()
                     }
+                };
+
+                // Some Linux desktops only pass the link as a process argument. The plugin may
+                // have seen it too, but the batch is deduplicated so it's handled once.
+                urls.extend(deep_links_from_args(std::env::args().skip(1)));
+
+                if !urls.is_empty() {
+                    log::info!("App launched with {} deep link URL(s)", urls.len());
+                    handle_deep_links(&handle, &urls);
                 }
 
                 // Handle deep links while app is running
                 let handle_clone = handle.clone();
                 app.deep_link().on_open_url(move |event| {
-                    println!("Deep link received while running!");
                     let urls = event.urls();
-                    println!("URLs: {:?}", urls);
-                    for url in urls {
-                        println!("Processing URL: {}", url.as_str());
-                        if let Err(e) = auth::handle_deep_link(&handle_clone, url.as_str()) {
-                            eprintln!("Failed to handle deep link: {}", e);
-                        }
-                    }
+                    log::info!("Deep link received while running ({} URL(s))", urls.len());
+                    handle_deep_links(&handle_clone, &urls);
                 });
 
-                println!("Deep link handler registered");
+                log::debug!("Deep link handler registered");
             }
 
+            let user_id = auth::get_session()
+                .ok()
+                .flatten()
+                .map(|session| session.user_id);
+            if prefs::is_connection_warming_enabled(user_id.as_deref()) {
+                tauri::async_runtime::spawn(api::warm_connection());
+            }
+            // Until it arrives (or if it doesn't verify) the built-in routes apply
+            tauri::async_runtime::spawn(routes::refresh_from_server());
+            session_timer::reschedule(app.handle());
+
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             get_auth_state,
+            list_accounts,
+            switch_account,
+            set_account_label,
+            prune_accounts,
+            refresh_all_accounts,
             get_session_token,
+            refresh_session,
+            api_request,
+            reload_session_from_store,
+            seconds_until_expiry,
+            session_claims,
+            storage_health,
+            verify_session,
+            fetch_profile,
+            login_preflight,
+            get_login_providers,
+            get_login_locale,
+            set_login_locale,
             start_login,
+            retry_open_login,
+            copy_login_url,
+            open_account_portal,
+            open_external,
+            register_deep_link_scheme,
+            run_deeplink_selftest,
+            simulate_deep_link,
+            last_opened_url,
+            emitted_auth_states,
+            import_session_token,
+            last_auth_error,
+            clear_auth_error,
+            get_preferences,
+            set_preferences,
+            set_background_refresh,
+            check_for_update,
+            get_build_info,
+            effective_config,
+            list_quarantined,
+            discard_quarantined,
+            set_network_simulation,
+            debug_emit_event,
+            get_support_id,
+            purge_local_data,
+            set_telemetry,
+            telemetry_counters,
+            forget_device,
             logout,
+            logout_all,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::fake_store::{self, FakeStore};
+
+    fn session(user_id: &str) -> UserSession {
+        serde_json::from_value(serde_json::json!({
+            "sealed_session": "sealed",
+            "user_id": user_id,
+            "email": format!("{}@example.com", user_id),
+            "first_name": null,
+            "last_name": null,
+        }))
+        .unwrap()
+    }
+
+    fn auth_state() -> AuthState {
+        AuthState::from_session_read(auth::get_session(), AuthState::unauthenticated)
+    }
+
+    #[test]
+    fn stored_session_is_authenticated() {
+        let _store = fake_store::inject(FakeStore::default());
+        fake_store::set_accounts(Some(&session("user_1")), &[]);
+
+        let state = auth_state();
+        assert_eq!(state.status, AuthStatus::Authenticated);
+        assert_eq!(state.user.unwrap().id, "user_1");
+        assert!(state.error.is_none());
+    }
+
+    #[test]
+    fn empty_store_is_unauthenticated() {
+        let _store = fake_store::inject(FakeStore::default());
+
+        let state = auth_state();
+        assert_eq!(state.status, AuthStatus::Unauthenticated);
+        assert!(state.error.is_none());
+    }
+
+    #[test]
+    fn unreadable_store_is_unknown_with_the_error() {
+        let _store = fake_store::inject(FakeStore::failing_reads());
+
+        let state = auth_state();
+        assert_eq!(state.status, AuthStatus::Unknown);
+        assert!(!state.is_authenticated);
+        assert!(state.error.unwrap().contains("keychain is locked"));
+    }
+
+    #[test]
+    fn unparseable_arg_does_not_stop_a_valid_auth_link() {
+        let callback = format!(
+            "{}://auth/callback?sealed_session=s&user_id=u&email=e",
+            DEEP_LINK_SCHEME
+        );
+        let args = [
+            "/path/to/addie",
+            "not a url",
+            "https://example.com/",
+            &callback,
+        ];
+
+        let links = deep_links_from_args(args.map(str::to_string));
+        assert_eq!(links, vec![url::Url::parse(&callback).unwrap()]);
+
+        let batch: Vec<String> = links.iter().map(url::Url::to_string).collect();
+        assert_eq!(auth::coalesce_deep_links(&batch), vec![callback]);
+    }
+
+    #[test]
+    fn no_args_means_no_links() {
+        assert!(deep_links_from_args(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn tauri_config_registers_the_deep_link_scheme() {
+        // Whitelabel builds replace the plugin config with --config, which build.rs checks
+        if option_env!("TAURI_CONFIG").is_some() {
+            return;
+        }
+        let config: serde_json::Value =
+            serde_json::from_str(include_str!("../tauri.conf.json")).unwrap();
+        let deep_link = &config["plugins"]["deep-link"];
+
+        assert_eq!(
+            deep_link["desktop"]["schemes"],
+            serde_json::json!([DEEP_LINK_SCHEME])
+        );
+        assert_eq!(
+            deep_link["mobile"][0]["scheme"],
+            serde_json::json!([DEEP_LINK_SCHEME])
+        );
+    }
+
+    #[test]
+    fn debug_events_are_limited_to_known_ones_in_debug_builds() {
+        for name in ["auth-success", "deep-link", "session-expiring-soon"] {
+            assert_eq!(check_debug_event(name, true), Ok(()));
+            assert_eq!(
+                check_debug_event(name, false),
+                Err("debug_emit_event is only available in debug builds".to_string())
+            );
+        }
+
+        for name in ["tauri://close-requested", "auth-succes", ""] {
+            assert_eq!(
+                check_debug_event(name, true),
+                Err(format!("Unknown event: {}", name))
+            );
+        }
+    }
+
+    #[test]
+    fn reregistering_the_scheme_re_checks_it() {
+        let registered = std::cell::RefCell::new(Vec::new());
+        let register = |scheme: &str| {
+            registered.borrow_mut().push(scheme.to_string());
+            Ok::<_, String>(())
+        };
+        assert_eq!(reregister_scheme(register, |_| Ok(true)), Ok(true));
+        // Registered, but another app still owns the handler
+        assert_eq!(reregister_scheme(register, |_| Ok(false)), Ok(false));
+        assert_eq!(*registered.borrow(), [DEEP_LINK_SCHEME, DEEP_LINK_SCHEME]);
+
+        let err = reregister_scheme(register, |_| Err("no xdg-mime".to_string())).unwrap_err();
+        assert!(err.starts_with("Failed to check"), "{}", err);
+    }
+
+    #[test]
+    fn failed_registration_is_reported_without_a_re_check() {
+        let err = reregister_scheme(
+            |_| Err("read-only applications dir".to_string()),
+            |_| -> Result<bool, String> { panic!("re-checked after a failed register") },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            format!(
+                "Failed to register {}:// handler: read-only applications dir",
+                DEEP_LINK_SCHEME
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_deep_link_signs_in_headless() {
+        let _headless = auth::headless::simulate();
+        let _store = fake_store::inject(FakeStore::default());
+        let app = auth::test_app::create();
+        let successes = auth::test_app::record(&app, "auth-success");
+
+        auth::start_oauth_flow(app.handle(), &LoginOptions::default(), None).unwrap();
+        // The browser isn't launched; the login URL is recorded for the test to follow
+        let login_url = url::Url::parse(&auth::last_opened_url().unwrap()).unwrap();
+        let state = login_url
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .map(|(_, value)| value.into_owned())
+            .unwrap();
+        let callback = format!(
+            "{}://auth/callback?sealed_session=sealed&user_id=user_1&email=a%40example.com&state={}",
+            DEEP_LINK_SCHEME, state
+        );
+
+        simulate_deep_link(app.handle().clone(), callback)
+            .await
+            .unwrap();
+
+        assert_eq!(auth_state().status, AuthStatus::Authenticated);
+        assert_eq!(successes.payloads()[0]["user"]["id"], "user_1");
+        assert_eq!(
+            auth::emitted_auth_states().last(),
+            Some(&AuthStatus::Authenticated)
+        );
+    }
+
+    #[tokio::test]
+    async fn simulate_deep_link_needs_headless_mode() {
+        let _store = fake_store::inject(FakeStore::default());
+        let app = auth::test_app::create();
+        let callback = format!(
+            "{}://auth/callback?sealed_session=sealed&user_id=user_1&email=a%40example.com",
+            DEEP_LINK_SCHEME
+        );
+
+        let err = simulate_deep_link(app.handle().clone(), callback)
+            .await
+            .unwrap_err();
+
+        assert!(err.contains("only available in headless mode"), "{}", err);
+        assert_eq!(auth_state().status, AuthStatus::Unauthenticated);
+    }
+
+    async fn update_from(reply: &'static str) -> (UpdateInfo, api::mock_api::MockApi) {
+        let api = api::mock_api::serve(vec![Some((200, reply))]);
+        let current = semver::Version::parse("1.2.0").unwrap();
+        let info = update_info(&current, "beta").await.unwrap();
+        (info, api)
+    }
+
+    #[tokio::test]
+    async fn up_to_date_build_has_no_update() {
+        let (info, api) = update_from(r#"{"latest_version":"1.2.0","mandatory":true}"#).await;
+
+        assert!(!info.update_available);
+        // Mandatory only applies to builds older than the latest
+        assert!(!info.mandatory);
+        let received = api.received();
+        assert!(received[0].path.starts_with("/app/version?"));
+        assert!(received[0].path.contains("channel=beta"));
+        assert!(received[0].path.contains("version=1.2.0"));
+        // Works signed out: no credentials are sent
+        assert_eq!(received[0].header("authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn newer_build_is_an_optional_update() {
+        let (info, _api) =
+            update_from(r#"{"latest_version":"1.3.0","notes_url":"https://example.com/notes"}"#)
+                .await;
+
+        assert!(info.update_available);
+        assert!(!info.mandatory);
+        assert_eq!(info.latest_version, "1.3.0");
+        assert_eq!(info.notes_url.as_deref(), Some("https://example.com/notes"));
+    }
+
+    #[tokio::test]
+    async fn newer_mandatory_build_must_be_installed() {
+        let (info, _api) = update_from(r#"{"latest_version":"2.0.0","mandatory":true}"#).await;
+
+        assert!(info.update_available);
+        assert!(info.mandatory);
+    }
+
+    #[tokio::test]
+    async fn invalid_server_version_is_an_error() {
+        let _api = api::mock_api::serve(vec![Some((200, r#"{"latest_version":"soon"}"#))]);
+        let current = semver::Version::parse("1.2.0").unwrap();
+
+        let err = update_info(&current, "stable").await.unwrap_err();
+        assert!(err.starts_with("Server sent an invalid version"), "{}", err);
+    }
+}
@@ -0,0 +1,143 @@
+//! Locale for the hosted login pages
+//!
+//! Defaults to the OS locale; the user can override it, and the override is kept
+//! per install since login happens before we know who the user is.
+
+use std::sync::Mutex;
+
+use crate::auth::{data_file_path, is_headless, write_private_file};
+
+const LOGIN_LOCALE_FILE: &str = ".addie-login-locale";
+
+/// Longest language tag we pass on (BCP 47 recommends supporting at least 35)
+const MAX_LOCALE_LEN: usize = 35;
+
+/// Override held in memory instead of on disk (headless mode)
+static MEMORY_LOCALE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Whether a string is a well-formed BCP 47 language tag (en, en-US, zh-Hant-TW, ...)
+///
+/// Checks the shape only: a 2-8 letter language followed by 1-8 character alphanumeric subtags.
+pub fn is_valid_locale(tag: &str) -> bool {
+    if tag.is_empty() || tag.len() > MAX_LOCALE_LEN {
+        return false;
+    }
+
+    let mut subtags = tag.split('-');
+    let language_ok = subtags.next().is_some_and(|lang| {
+        (2..=8).contains(&lang.len()) && lang.chars().all(|c| c.is_ascii_alphabetic())
+    });
+
+    language_ok
+        && subtags.all(|sub| {
+            (1..=8).contains(&sub.len()) && sub.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+/// Turn a POSIX-style locale (en_US.UTF-8, de_DE@euro) into a language tag
+fn normalize_os_locale(locale: &str) -> String {
+    locale
+        .split(['.', '@'])
+        .next()
+        .unwrap_or(locale)
+        .replace('_', "-")
+}
+
+/// Locale the user picked for login pages, if any
+pub(crate) fn get_override() -> Option<String> {
+    if is_headless() {
+        return MEMORY_LOCALE.lock().unwrap().clone();
+    }
+
+    data_file_path(LOGIN_LOCALE_FILE)
+        .and_then(std::fs::read_to_string)
+        .ok()
+        .map(|locale| locale.trim().to_string())
+        .filter(|locale| is_valid_locale(locale))
+}
+
+/// Locale to render login pages in: the user's override, else the OS locale
+///
+/// None when neither yields a valid tag (e.g. the POSIX "C" locale), leaving it to the server.
+pub fn get_login_locale() -> Option<String> {
+    get_override().or_else(|| {
+        sys_locale::get_locale()
+            .map(|locale| normalize_os_locale(&locale))
+            .filter(|locale| is_valid_locale(locale))
+    })
+}
+
+/// Set or clear (None) the login page locale override
+pub fn set_login_locale(locale: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(locale) = locale {
+        if !is_valid_locale(locale) {
+            return Err(format!("Invalid locale: {}", locale).into());
+        }
+    }
+
+    if is_headless() {
+        *MEMORY_LOCALE.lock().unwrap() = locale.map(str::to_string);
+        return Ok(());
+    }
+
+    let path = data_file_path(LOGIN_LOCALE_FILE)?;
+    match locale {
+        Some(locale) => write_private_file(&path, locale.as_bytes())?,
+        None if path.exists() => std::fs::remove_file(&path)?,
+        None => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_well_formed_language_tags() {
+        for tag in ["en", "en-US", "zh-Hant-TW", "es-419", "de-CH-1996"] {
+            assert!(is_valid_locale(tag), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_language_tags() {
+        for tag in [
+            "",
+            "e",
+            "en_US",
+            "en-",
+            "-US",
+            "en-US-toolongsubtag",
+            "en US",
+            "fr&x=1",
+            "1en",
+        ] {
+            assert!(!is_valid_locale(tag), "{}", tag);
+        }
+        assert!(!is_valid_locale(&format!("en-{}", ["abc"; 12].join("-"))));
+    }
+
+    #[test]
+    fn normalizes_posix_locales() {
+        assert_eq!(normalize_os_locale("en_US.UTF-8"), "en-US");
+        assert_eq!(normalize_os_locale("de_DE@euro"), "de-DE");
+        assert!(!is_valid_locale(&normalize_os_locale("C")));
+    }
+
+    #[test]
+    fn override_is_saved_and_cleared() {
+        let _dir = crate::auth::temp_data_dir::create();
+        assert_eq!(get_override(), None);
+
+        set_login_locale(Some("pt-BR")).unwrap();
+        assert_eq!(get_override().as_deref(), Some("pt-BR"));
+        assert_eq!(get_login_locale().as_deref(), Some("pt-BR"));
+
+        assert!(set_login_locale(Some("pt_BR")).is_err());
+        assert_eq!(get_override().as_deref(), Some("pt-BR"));
+
+        set_login_locale(None).unwrap();
+        assert_eq!(get_override(), None);
+    }
+}
@@ -0,0 +1,169 @@
+//! Per-user UI preferences (theme, last view, ...)
+//!
+//! Stored as an opaque JSON object per user_id, in a file separate from the session
+//! so preferences never sit next to secrets.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{data_file_path, is_headless, write_private_file};
+
+const PREFERENCES_FILE: &str = ".addie-preferences.json";
+
+/// Largest preferences blob we accept for one user, in serialized bytes
+const MAX_PREFERENCES_BYTES: usize = 64 * 1024;
+
+/// Preferences held in memory instead of on disk (headless mode)
+static MEMORY_PREFERENCES: Mutex<Option<PreferencesFile>> = Mutex::new(None);
+
+/// On-disk layout: preferences blob per user_id
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct PreferencesFile {
+    users: HashMap<String, serde_json::Value>,
+}
+
+fn load() -> Result<PreferencesFile, Box<dyn std::error::Error>> {
+    if is_headless() {
+        return Ok(MEMORY_PREFERENCES
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_default());
+    }
+
+    let path = data_file_path(PREFERENCES_FILE)?;
+    if !path.exists() {
+        return Ok(PreferencesFile::default());
+    }
+    let json = std::fs::read_to_string(&path)?;
+    // A damaged file would otherwise fail every user's reads and writes for good
+    Ok(serde_json::from_str(&json).unwrap_or_else(|e| {
+        log::warn!("Preferences file is unreadable, starting afresh: {}", e);
+        PreferencesFile::default()
+    }))
+}
+
+fn store(file: &PreferencesFile) -> Result<(), Box<dyn std::error::Error>> {
+    if is_headless() {
+        *MEMORY_PREFERENCES.lock().unwrap() = Some(file.clone());
+        return Ok(());
+    }
+
+    let json = serde_json::to_string(file)?;
+    write_private_file(&data_file_path(PREFERENCES_FILE)?, json.as_bytes())?;
+    Ok(())
+}
+
+/// Get a user's preferences, or an empty object if none are stored
+pub fn get_preferences(user_id: &str) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    Ok(load()?
+        .users
+        .remove(user_id)
+        .unwrap_or_else(|| serde_json::json!({})))
+}
+
+/// Whether to warm the API connection at startup (the `warm_connection` preference, on by default)
+pub fn is_connection_warming_enabled(user_id: Option<&str>) -> bool {
+    user_id
+        .and_then(|user_id| get_preferences(user_id).ok())
+        .and_then(|prefs| prefs.get("warm_connection")?.as_bool())
+        .unwrap_or(true)
+}
+
+/// Whether the session is refreshed ahead of its expiry (the `background_refresh` preference,
+/// on by default)
+pub fn is_background_refresh_enabled(user_id: Option<&str>) -> bool {
+    user_id
+        .and_then(|user_id| get_preferences(user_id).ok())
+        .and_then(|prefs| prefs.get("background_refresh")?.as_bool())
+        .unwrap_or(true)
+}
+
+/// Set a user's `background_refresh` preference, keeping the rest of their preferences
+pub fn set_background_refresh(
+    user_id: &str,
+    enabled: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut preferences = get_preferences(user_id)?;
+    preferences["background_refresh"] = enabled.into();
+    set_preferences(user_id, preferences)
+}
+
+/// Replace a user's preferences; the blob must be a JSON object within the size limit
+pub fn set_preferences(
+    user_id: &str,
+    preferences: serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !preferences.is_object() {
+        return Err("Preferences must be a JSON object".into());
+    }
+
+    let size = serde_json::to_vec(&preferences)?.len();
+    if size > MAX_PREFERENCES_BYTES {
+        return Err(format!(
+            "Preferences too large: {} bytes (limit {})",
+            size, MAX_PREFERENCES_BYTES
+        )
+        .into());
+    }
+
+    let mut file = load()?;
+    file.users.insert(user_id.to_string(), preferences);
+    store(&file)
+}
+
+/// Forget a user's preferences (on account removal)
+pub fn clear_preferences(user_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = load()?;
+    if file.users.remove(user_id).is_some() {
+        store(&file)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::temp_data_dir;
+
+    #[test]
+    fn each_user_only_sees_their_own_preferences() {
+        let _dir = temp_data_dir::create();
+        set_preferences("user_1", serde_json::json!({ "theme": "dark" })).unwrap();
+        set_preferences("user_2", serde_json::json!({ "theme": "light" })).unwrap();
+
+        assert_eq!(get_preferences("user_1").unwrap()["theme"], "dark");
+        assert_eq!(get_preferences("user_2").unwrap()["theme"], "light");
+
+        clear_preferences("user_1").unwrap();
+        assert_eq!(get_preferences("user_1").unwrap(), serde_json::json!({}));
+        assert_eq!(get_preferences("user_2").unwrap()["theme"], "light");
+    }
+
+    #[test]
+    fn rejects_oversized_and_non_object_preferences() {
+        let _dir = temp_data_dir::create();
+        let big = serde_json::json!({ "notes": "x".repeat(MAX_PREFERENCES_BYTES) });
+        assert!(set_preferences("user_1", big).is_err());
+        for value in [
+            serde_json::json!([1, 2]),
+            serde_json::json!("dark"),
+            serde_json::Value::Null,
+        ] {
+            assert!(set_preferences("user_1", value).is_err());
+        }
+        assert_eq!(get_preferences("user_1").unwrap(), serde_json::json!({}));
+    }
+
+    #[test]
+    fn a_corrupt_preferences_file_reads_as_empty() {
+        let dir = temp_data_dir::create();
+        std::fs::write(dir.path().join(PREFERENCES_FILE), "{\"users\": {\"user_1").unwrap();
+
+        assert_eq!(get_preferences("user_1").unwrap(), serde_json::json!({}));
+        set_preferences("user_1", serde_json::json!({ "theme": "dark" })).unwrap();
+        assert_eq!(get_preferences("user_1").unwrap()["theme"], "dark");
+    }
+}
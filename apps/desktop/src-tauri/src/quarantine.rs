@@ -0,0 +1,224 @@
+//! Quarantine for stored sessions that fail integrity checks
+//!
+//! A stored session we can't trust is moved aside instead of deleted, so support can look
+//! at what was there (e.g. when investigating a spoofing attempt). Quarantined sessions
+//! are never loaded again; the app stays logged out until the next real login.
+//! A session from the session file is kept as-is (it was already on disk in plain text),
+//! in an owner-only file. One from the keychain holds every account's secrets, so it's
+//! never copied out: only its size and SHA-256 are recorded.
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{data_file_path, write_private_file};
+
+const QUARANTINE_DIR: &str = ".addie-quarantine";
+
+/// A quarantined session as listed for diagnostics (never includes the bytes)
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantinedSession {
+    pub id: String,
+    pub reason: String,
+    /// Unix time (seconds) it was quarantined
+    pub quarantined_at: u64,
+    pub size_bytes: usize,
+    pub sha256: Option<String>,
+}
+
+/// On-disk layout of one quarantined session
+#[derive(Serialize, Deserialize)]
+struct QuarantineFile {
+    reason: String,
+    quarantined_at: u64,
+    /// The session file exactly as found, base64 so non-UTF-8 content survives; None for
+    /// keychain contents, which stay out of the data directory
+    #[serde(default)]
+    bytes: Option<String>,
+    #[serde(default)]
+    size_bytes: Option<usize>,
+    /// Hex SHA-256 of the bytes, so support can match reports without seeing them
+    #[serde(default)]
+    sha256: Option<String>,
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn quarantine_path(id: &uuid::Uuid) -> std::io::Result<std::path::PathBuf> {
+    Ok(data_file_path(QUARANTINE_DIR)?.join(format!("{}.json", id)))
+}
+
+/// Record untrusted session bytes, returning the quarantine id
+///
+/// The bytes themselves are only kept when `keep_bytes` is set (session file contents);
+/// otherwise just their size and hash are.
+pub fn quarantine(
+    bytes: &[u8],
+    reason: &str,
+    keep_bytes: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let id = uuid::Uuid::new_v4();
+    let file = QuarantineFile {
+        reason: reason.to_string(),
+        quarantined_at: crate::auth::now_secs(),
+        bytes: keep_bytes.then(|| base64::engine::general_purpose::STANDARD.encode(bytes)),
+        size_bytes: Some(bytes.len()),
+        sha256: Some(sha256_hex(bytes)),
+    };
+
+    std::fs::create_dir_all(data_file_path(QUARANTINE_DIR)?)?;
+    write_private_file(
+        &quarantine_path(&id)?,
+        serde_json::to_string(&file)?.as_bytes(),
+    )?;
+    Ok(id.to_string())
+}
+
+/// Quarantined sessions, oldest first; unreadable entries are skipped
+pub fn list_quarantined() -> Result<Vec<QuarantinedSession>, Box<dyn std::error::Error>> {
+    let dir = data_file_path(QUARANTINE_DIR)?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut sessions: Vec<QuarantinedSession> = std::fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let id = uuid::Uuid::parse_str(path.file_stem()?.to_str()?).ok()?;
+            let file: QuarantineFile =
+                serde_json::from_str(&std::fs::read_to_string(&path).ok()?).ok()?;
+            // Older entries only have the bytes
+            let size_bytes = match (file.size_bytes, &file.bytes) {
+                (Some(size), _) => size,
+                (None, Some(bytes)) => base64::engine::general_purpose::STANDARD
+                    .decode(bytes)
+                    .ok()?
+                    .len(),
+                (None, None) => return None,
+            };
+            Some(QuarantinedSession {
+                id: id.to_string(),
+                reason: file.reason,
+                quarantined_at: file.quarantined_at,
+                size_bytes,
+                sha256: file.sha256,
+            })
+        })
+        .collect();
+    sessions.sort_by_key(|session| session.quarantined_at);
+    Ok(sessions)
+}
+
+/// Delete one quarantined session, or all of them when id is None
+pub fn discard_quarantined(id: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(id) = id else {
+        let dir = data_file_path(QUARANTINE_DIR)?;
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+        return Ok(());
+    };
+
+    // Parsing as a uuid also rules out path tricks in the id
+    let id = uuid::Uuid::parse_str(id).map_err(|_| "Invalid quarantine id")?;
+    let path = quarantine_path(&id)?;
+    if !path.exists() {
+        return Err("No quarantined session with that id".into());
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::temp_data_dir;
+
+    const SECRET: &[u8] = br#"{"accounts":{"user_1":{"sealed_session":"secret"}}}"#;
+
+    fn stored(id: &str) -> QuarantineFile {
+        let path = quarantine_path(&uuid::Uuid::parse_str(id).unwrap()).unwrap();
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn keychain_contents_keep_only_their_size_and_hash() {
+        let _dir = temp_data_dir::create();
+
+        let id = quarantine(SECRET, "bad json", false).unwrap();
+
+        let file = stored(&id);
+        assert_eq!(file.bytes, None);
+        assert_eq!(file.size_bytes, Some(SECRET.len()));
+        assert_eq!(file.sha256.as_deref(), Some(sha256_hex(SECRET).as_str()));
+        let listed = list_quarantined().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(
+            (listed[0].id.as_str(), listed[0].size_bytes),
+            (id.as_str(), SECRET.len())
+        );
+    }
+
+    #[test]
+    fn session_file_contents_are_kept_verbatim() {
+        let _dir = temp_data_dir::create();
+        let bytes = b"\xff not utf-8";
+
+        let id = quarantine(bytes, "bad json", true).unwrap();
+
+        let kept = base64::engine::general_purpose::STANDARD
+            .decode(stored(&id).bytes.unwrap())
+            .unwrap();
+        assert_eq!(kept, bytes);
+    }
+
+    #[test]
+    fn unreadable_entries_are_skipped_when_listing() {
+        let dir = temp_data_dir::create();
+        quarantine(SECRET, "bad json", false).unwrap();
+        let quarantine_dir = dir.path().join(QUARANTINE_DIR);
+        std::fs::write(
+            quarantine_dir.join(format!("{}.json", uuid::Uuid::new_v4())),
+            "{ not json",
+        )
+        .unwrap();
+        std::fs::write(quarantine_dir.join("not-a-uuid.json"), "{}").unwrap();
+        // Neither a size nor bytes to take one from
+        let no_size = serde_json::json!({ "reason": "old", "quarantined_at": 1 });
+        std::fs::write(
+            quarantine_dir.join(format!("{}.json", uuid::Uuid::new_v4())),
+            no_size.to_string(),
+        )
+        .unwrap();
+
+        let listed = list_quarantined().unwrap();
+
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].reason, "bad json");
+    }
+
+    #[test]
+    fn discarding_needs_a_uuid_id() {
+        let dir = temp_data_dir::create();
+        let id = quarantine(SECRET, "bad json", false).unwrap();
+        let outside = dir.path().join("keep.json");
+        std::fs::write(&outside, "{}").unwrap();
+
+        for bad in ["../keep", "not-a-uuid", ""] {
+            let err = discard_quarantined(Some(bad)).unwrap_err();
+            assert_eq!(err.to_string(), "Invalid quarantine id", "{:?}", bad);
+        }
+        assert!(outside.exists());
+        let missing = uuid::Uuid::new_v4().to_string();
+        assert!(discard_quarantined(Some(&missing)).is_err());
+
+        discard_quarantined(Some(&id)).unwrap();
+        assert!(list_quarantined().unwrap().is_empty());
+    }
+}
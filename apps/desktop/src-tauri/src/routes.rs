@@ -0,0 +1,318 @@
+//! Allowlist of internal routes that return_to and the account portal may point at
+//!
+//! One list for both, so a path allowed for one can't be forgotten in the other. The
+//! compiled-in list can be replaced by one the server signs with Ed25519, so new pages
+//! don't need an app release. The verifying key is set at compile time with
+//! ADDIE_ROUTE_ALLOWLIST_KEY (base64); builds without one only ever use the built-in list.
+
+use std::sync::Mutex;
+
+use base64::Engine;
+use serde::Deserialize;
+
+use crate::api;
+use crate::auth::now_secs;
+
+/// Routes (and their sub-routes) allowed when the server hasn't sent a signed list
+pub(crate) const INTERNAL_ROUTES: &[&str] = &[
+    "/",
+    "/chat",
+    "/history",
+    "/settings",
+    "/account",
+    "/dashboard",
+];
+
+/// Most routes a server list may hold
+const MAX_SERVER_ROUTES: usize = 64;
+
+/// Longest route a server list may hold
+const MAX_ROUTE_LEN: usize = 128;
+
+/// Public key server route lists must be signed with (base64 Ed25519)
+const ROUTE_ALLOWLIST_KEY: Option<&str> = option_env!("ADDIE_ROUTE_ALLOWLIST_KEY");
+
+/// Verified server list and when it stops applying
+static SERVER_ROUTES: Mutex<Option<(Vec<String>, u64)>> = Mutex::new(None);
+
+/// Route list as returned by `/app/routes`: a base64 payload and its signature
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignedRouteList {
+    pub payload: String,
+    pub signature: String,
+}
+
+/// What the signed payload holds
+#[derive(Debug, Deserialize)]
+struct RouteListPayload {
+    routes: Vec<String>,
+    /// Unix time (seconds) after which the built-in list applies again
+    expires_at: u64,
+}
+
+/// The routes currently allowed: the server's verified list while it's valid, else the built-in one
+pub(crate) fn internal_route_allowlist() -> Vec<String> {
+    server_routes().unwrap_or_else(|| {
+        INTERNAL_ROUTES
+            .iter()
+            .map(|route| route.to_string())
+            .collect()
+    })
+}
+
+/// The server's verified list, if one is in effect
+pub(crate) fn server_routes() -> Option<Vec<String>> {
+    match &*SERVER_ROUTES.lock().unwrap() {
+        Some((routes, expires_at)) if now_secs() < *expires_at => Some(routes.clone()),
+        _ => None,
+    }
+}
+
+/// Whether a path is on the internal route allowlist (rejects external URLs and `//host` tricks)
+pub(crate) fn is_allowed_route(path: &str) -> bool {
+    if !path.starts_with('/')
+        || path.starts_with("//")
+        || path.contains('\\')
+        || path.contains("://")
+    {
+        return false;
+    }
+
+    let route = path.split(['?', '#']).next().unwrap_or(path);
+    if has_traversal(route) {
+        return false;
+    }
+    internal_route_allowlist().iter().any(|allowed| {
+        route == allowed || (allowed != "/" && route.starts_with(&format!("{}/", allowed)))
+    })
+}
+
+/// Whether a route has `.`/`..` segments or encoded dots or slashes
+///
+/// Url parsing resolves these, so `/chat/../admin` would otherwise pass as a `/chat` sub-route.
+fn has_traversal(route: &str) -> bool {
+    let lower = route.to_ascii_lowercase();
+    lower.contains("%2e")
+        || lower.contains("%2f")
+        || lower.contains("%5c")
+        || route
+            .split('/')
+            .any(|segment| segment == "." || segment == "..")
+}
+
+/// Whether a server-sent route is a plain path, with nothing that could reach another origin
+fn is_valid_route(route: &str) -> bool {
+    route.len() <= MAX_ROUTE_LEN
+        && route.starts_with('/')
+        && !route.starts_with("//")
+        && (route == "/" || !route.ends_with('/'))
+        && route
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '_' | '.'))
+        && !route.split('/').any(|segment| segment == "..")
+}
+
+/// Check a signed route list against `public_key`, returning its routes and expiry
+///
+/// All or nothing: one malformed route rejects the whole list.
+fn verify_route_list(
+    signed: &SignedRouteList,
+    public_key: &[u8],
+    now: u64,
+) -> Result<(Vec<String>, u64), String> {
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let payload = b64
+        .decode(&signed.payload)
+        .map_err(|e| format!("Invalid payload encoding: {}", e))?;
+    let signature = b64
+        .decode(&signed.signature)
+        .map_err(|e| format!("Invalid signature encoding: {}", e))?;
+
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+        .verify(&payload, &signature)
+        .map_err(|_| "Signature does not verify".to_string())?;
+
+    let payload: RouteListPayload =
+        serde_json::from_slice(&payload).map_err(|e| format!("Invalid payload: {}", e))?;
+    if payload.expires_at <= now {
+        return Err("Route list has expired".to_string());
+    }
+    if payload.routes.is_empty() || payload.routes.len() > MAX_SERVER_ROUTES {
+        return Err(format!(
+            "Route list must hold 1 to {} routes",
+            MAX_SERVER_ROUTES
+        ));
+    }
+    if let Some(route) = payload.routes.iter().find(|route| !is_valid_route(route)) {
+        return Err(format!("Invalid route in list: {}", route));
+    }
+    Ok((payload.routes, payload.expires_at))
+}
+
+/// Fetch the server's route list and use it if it verifies; otherwise keep the current one
+pub async fn refresh_from_server() {
+    let Some(key) = ROUTE_ALLOWLIST_KEY else {
+        log::debug!("No route allowlist key in this build, using the built-in routes");
+        return;
+    };
+    let key = match base64::engine::general_purpose::STANDARD.decode(key) {
+        Ok(key) => key,
+        Err(e) => {
+            log::error!("ADDIE_ROUTE_ALLOWLIST_KEY is not valid base64: {}", e);
+            return;
+        }
+    };
+
+    let signed = match api::get_route_list().await {
+        Ok(signed) => signed,
+        Err(e) => {
+            log::info!(
+                "Could not fetch the server route list, keeping the current one: {}",
+                e
+            );
+            return;
+        }
+    };
+    match verify_route_list(&signed, &key, now_secs()) {
+        Ok((routes, expires_at)) => {
+            log::info!("Using the server route list ({} routes)", routes.len());
+            *SERVER_ROUTES.lock().unwrap() = Some((routes, expires_at));
+        }
+        Err(e) => log::warn!("Rejected the server route list: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::KeyPair;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn key_pair() -> ring::signature::Ed25519KeyPair {
+        let pkcs8 =
+            ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                .unwrap();
+        ring::signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn signed(
+        key: &ring::signature::Ed25519KeyPair,
+        payload: serde_json::Value,
+    ) -> SignedRouteList {
+        let payload = serde_json::to_vec(&payload).unwrap();
+        let b64 = base64::engine::general_purpose::STANDARD;
+        SignedRouteList {
+            payload: b64.encode(&payload),
+            signature: b64.encode(key.sign(&payload).as_ref()),
+        }
+    }
+
+    fn routes_payload(routes: &[&str]) -> serde_json::Value {
+        serde_json::json!({ "routes": routes, "expires_at": NOW + 3600 })
+    }
+
+    #[test]
+    fn allows_internal_routes_and_their_sub_routes() {
+        for path in [
+            "/",
+            "/chat",
+            "/chat/123",
+            "/settings?tab=privacy",
+            "/account#billing",
+        ] {
+            assert!(is_allowed_route(path), "{}", path);
+        }
+        assert!(!is_allowed_route("/chatroom"));
+        assert!(!is_allowed_route("/admin"));
+    }
+
+    #[test]
+    fn rejects_external_urls() {
+        for path in [
+            "https://evil.example/",
+            "//evil.example/chat",
+            "/\\evil.example",
+            "chat",
+            "/chat?next=https://x",
+            "",
+        ] {
+            assert!(!is_allowed_route(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_dot_segments_out_of_an_allowed_route() {
+        for path in [
+            "/chat/../x",
+            "/chat/%2e%2e/x",
+            "/chat/%2E%2E/x",
+            "/dashboard/./../x",
+            "/dashboard/../../anything",
+            "/chat/.",
+            "/chat%2f..%2fadmin",
+            "/chat/..?tab=1",
+        ] {
+            assert!(!is_allowed_route(path), "{}", path);
+        }
+        assert!(is_allowed_route("/chat/v1.2"));
+    }
+
+    #[test]
+    fn accepts_a_list_signed_with_the_key() {
+        let key = key_pair();
+        let list = signed(&key, routes_payload(&["/chat", "/reports"]));
+
+        let (routes, expires_at) =
+            verify_route_list(&list, key.public_key().as_ref(), NOW).unwrap();
+        assert_eq!(routes, ["/chat", "/reports"]);
+        assert_eq!(expires_at, NOW + 3600);
+    }
+
+    #[test]
+    fn rejects_a_list_signed_with_another_key_or_altered() {
+        let key = key_pair();
+        let list = signed(&key_pair(), routes_payload(&["/chat"]));
+        assert_eq!(
+            verify_route_list(&list, key.public_key().as_ref(), NOW).unwrap_err(),
+            "Signature does not verify"
+        );
+
+        let mut altered = signed(&key, routes_payload(&["/chat"]));
+        altered.payload = base64::engine::general_purpose::STANDARD
+            .encode(serde_json::to_vec(&routes_payload(&["/chat", "/admin"])).unwrap());
+        assert!(verify_route_list(&altered, key.public_key().as_ref(), NOW).is_err());
+    }
+
+    #[test]
+    fn rejects_an_expired_list() {
+        let key = key_pair();
+        let list = signed(
+            &key,
+            serde_json::json!({ "routes": ["/chat"], "expires_at": NOW }),
+        );
+        assert_eq!(
+            verify_route_list(&list, key.public_key().as_ref(), NOW).unwrap_err(),
+            "Route list has expired"
+        );
+    }
+
+    #[test]
+    fn rejects_a_signed_list_with_an_unsafe_route() {
+        let key = key_pair();
+        for route in [
+            "https://evil.example",
+            "//evil.example",
+            "/a/../b",
+            "/chat?x=1",
+            "/chat/",
+        ] {
+            let list = signed(&key, routes_payload(&["/chat", route]));
+            let err = verify_route_list(&list, key.public_key().as_ref(), NOW).unwrap_err();
+            assert!(err.starts_with("Invalid route"), "{}: {}", route, err);
+        }
+
+        let empty = signed(&key, routes_payload(&[]));
+        assert!(verify_route_list(&empty, key.public_key().as_ref(), NOW).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! Background timer for the active session's expiry
+//!
+//! A lead time before the session stops working (see auth::expiry_deadline) the UI gets
+//! `session-expiring-soon`, so it can offer to refresh_session. If it doesn't, the timer
+//! wakes again at the deadline and get_valid_session signs out as it would on next use.
+//!
+//! With the `background_refresh` preference on (the default) a second task refreshes the
+//! session shortly before it expires; with it off the session is refreshed on next use.
+//! Both are re-armed whenever the auth state changes and after every refresh.
+
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::auth::{self, now_secs};
+use crate::prefs;
+
+/// Lead time for session-expiring-soon unless ADDIE_EXPIRY_WARNING_SECS says otherwise
+const DEFAULT_EXPIRY_WARNING: Duration = Duration::from_secs(2 * 60);
+
+/// The active session's timer tasks, where running (managed state)
+#[derive(Default)]
+pub struct SessionTimer {
+    expiry: Mutex<Option<JoinHandle<()>>>,
+    refresh: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Payload of the session-expiring-soon event
+#[derive(Clone, serde::Serialize)]
+struct ExpiringSoonPayload {
+    seconds_remaining: u64,
+}
+
+/// How long before the deadline session-expiring-soon is sent
+pub(crate) fn expiry_warning_lead() -> Duration {
+    std::env::var("ADDIE_EXPIRY_WARNING_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_EXPIRY_WARNING)
+}
+
+/// Restart the timers for whichever session is active now (stopping them if none is)
+pub fn reschedule<R: Runtime>(app: &AppHandle<R>) {
+    stop(app);
+    let Some(session) = auth::get_session().ok().flatten() else {
+        return;
+    };
+
+    let timer = app.state::<SessionTimer>();
+    *timer.expiry.lock().unwrap() = spawn(watch_session(app.clone(), expiry_warning_lead()));
+    if prefs::is_background_refresh_enabled(Some(&session.user_id)) {
+        *timer.refresh.lock().unwrap() = spawn(refresh_ahead(app.clone()));
+    }
+}
+
+/// Stop the timers, including a background refresh that hasn't started yet (on logout)
+pub fn stop<R: Runtime>(app: &AppHandle<R>) {
+    let timer = app.state::<SessionTimer>();
+    for task in [&timer.expiry, &timer.refresh] {
+        if let Some(task) = task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+/// Turn background refresh on or off for the signed-in user, starting or stopping it now
+pub fn set_background_refresh<R: Runtime>(
+    app: &AppHandle<R>,
+    enabled: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session = auth::get_valid_session(app)?.ok_or("Not signed in")?;
+    prefs::set_background_refresh(&session.user_id, enabled)?;
+    log::info!(
+        "Background refresh {}",
+        if enabled { "enabled" } else { "disabled" }
+    );
+    reschedule(app);
+    Ok(())
+}
+
+#[cfg(not(test))]
+fn spawn(task: impl Future<Output = ()> + Send + 'static) -> Option<JoinHandle<()>> {
+    Some(tauri::async_runtime::spawn(task))
+}
+
+/// On the test's own runtime in unit tests, so the timer sees that thread's fake store;
+/// tests without a runtime get no timer
+#[cfg(test)]
+fn spawn(task: impl Future<Output = ()> + Send + 'static) -> Option<JoinHandle<()>> {
+    let runtime = tokio::runtime::Handle::try_current().ok()?;
+    Some(JoinHandle::Tokio(runtime.spawn(task)))
+}
+
+/// Warn `lead` before the active session's deadline, then re-check it once that has passed
+///
+/// Ends when there's no session, its deadline isn't known, or the deadline passed without
+/// signing out (a session with a refresh token is refreshed on next use instead).
+pub(crate) async fn watch_session<R: Runtime>(app: AppHandle<R>, lead: Duration) {
+    let mut warned = false;
+    loop {
+        let session = match auth::get_valid_session(&app) {
+            Ok(Some(session)) => session,
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("Session timer stopped, could not read the session: {}", e);
+                return;
+            }
+        };
+        let Some(deadline) = auth::expiry_deadline(&session) else {
+            return;
+        };
+        let remaining = deadline.saturating_sub(now_secs());
+        if remaining == 0 {
+            return;
+        }
+
+        if remaining > lead.as_secs() {
+            tokio::time::sleep(Duration::from_secs(remaining - lead.as_secs())).await;
+            continue;
+        }
+        if !warned {
+            log::info!("Session expires in {}s, warning the UI", remaining);
+            auth::emit_logged(
+                &app,
+                "session-expiring-soon",
+                ExpiringSoonPayload {
+                    seconds_remaining: remaining,
+                },
+            );
+            warned = true;
+        }
+        // A second past, so the max-age check (strictly after the deadline) agrees it's over
+        tokio::time::sleep(Duration::from_secs(remaining + 1)).await;
+    }
+}
+
+/// Refresh the active session just before get_fresh_session would on its next use
+///
+/// A refresh that fails is left to the next use, as if background refresh were off.
+async fn refresh_ahead<R: Runtime>(app: AppHandle<R>) {
+    let Some(session) = auth::get_session().ok().flatten() else {
+        return;
+    };
+    let (Some(expires_at), Some(_)) = (session.expires_at, &session.refresh_token) else {
+        return;
+    };
+    let due = expires_at.saturating_sub(auth::REFRESH_MARGIN_SECS);
+    tokio::time::sleep(Duration::from_secs(due.saturating_sub(now_secs()))).await;
+
+    // Detached: rescheduling mid-refresh mustn't drop the call after the server has
+    // rotated the refresh token. refresh_session re-arms this task when it succeeds.
+    spawn(async move {
+        if let Err(e) = auth::get_fresh_session(&app).await {
+            log::warn!("Background session refresh failed: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{fake_store, get_session, temp_data_dir, test_app};
+    use crate::UserSession;
+
+    fn session_expiring_at(expires_at: u64, refresh_token: Option<&str>) -> UserSession {
+        serde_json::from_value(serde_json::json!({
+            "sealed_session": "sealed",
+            "user_id": "user_1",
+            "email": "a@example.com",
+            "first_name": null,
+            "last_name": null,
+            "schema_version": crate::SESSION_SCHEMA_VERSION,
+            "expires_at": expires_at,
+            "refresh_token": refresh_token,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn warning_comes_before_the_session_expires() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = session_expiring_at(now_secs() + 2, None);
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let order = test_app::record_order(&app, &["session-expiring-soon", "logged-out"]);
+        let warned = test_app::record(&app, "session-expiring-soon");
+        let logged_out = test_app::record(&app, "logged-out");
+
+        watch_session(app.handle().clone(), Duration::from_secs(1)).await;
+
+        assert_eq!(
+            order.payloads(),
+            [
+                serde_json::json!("session-expiring-soon"),
+                serde_json::json!("logged-out")
+            ]
+        );
+        let seconds_remaining = warned.payloads()[0]["seconds_remaining"].as_u64().unwrap();
+        assert!((1..=2).contains(&seconds_remaining));
+        assert_eq!(logged_out.payloads()[0]["reason"], "expired");
+        assert_eq!(get_session().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn toggling_background_refresh_starts_and_stops_it() {
+        let _dir = temp_data_dir::create();
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = session_expiring_at(now_secs() + 3600, Some("refresh"));
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let timer = app.state::<SessionTimer>();
+        let refreshing = || timer.refresh.lock().unwrap().is_some();
+        reschedule(app.handle());
+        assert!(refreshing());
+
+        set_background_refresh(app.handle(), false).unwrap();
+        assert!(!refreshing());
+        assert!(!prefs::is_background_refresh_enabled(Some("user_1")));
+        // Only the proactive refresh stops; the expiry warning still runs
+        assert!(timer.expiry.lock().unwrap().is_some());
+
+        set_background_refresh(app.handle(), true).unwrap();
+        assert!(refreshing());
+    }
+
+    #[tokio::test]
+    async fn logout_stops_a_pending_background_refresh() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = session_expiring_at(now_secs() + 3600, Some("refresh"));
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let timer = app.state::<SessionTimer>();
+        reschedule(app.handle());
+        assert!(timer.refresh.lock().unwrap().is_some());
+
+        auth::force_logout_local(app.handle(), crate::LogoutReason::UserRequested, None).unwrap();
+
+        assert!(timer.refresh.lock().unwrap().is_none());
+    }
+}
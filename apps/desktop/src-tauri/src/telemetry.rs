@@ -0,0 +1,155 @@
+//! Login funnel metrics, opt-in
+//!
+//! Every metric bumps a local in-memory counter the user can inspect. A `login-metric`
+//! event is emitted for the frontend to forward, and only carries the install id when
+//! the user has opted in. No metric ever includes anything about the user.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use tauri::{AppHandle, Runtime};
+
+use crate::auth::{data_file_path, emit_logged, is_headless};
+
+const TELEMETRY_FILE: &str = ".addie-telemetry";
+
+/// Local counts per metric since the app started
+#[cfg(not(test))]
+static COUNTERS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+
+/// Opt-in held in memory instead of on disk (headless mode)
+#[cfg(not(test))]
+static MEMORY_ENABLED: Mutex<bool> = Mutex::new(false);
+
+// Per thread in unit tests, so each test counts only its own metrics
+#[cfg(test)]
+thread_local! {
+    static COUNTERS: Mutex<BTreeMap<&'static str, u64>> = const { Mutex::new(BTreeMap::new()) };
+    static MEMORY_ENABLED: Mutex<bool> = const { Mutex::new(false) };
+}
+
+fn with_counters<T>(f: impl FnOnce(&mut BTreeMap<&'static str, u64>) -> T) -> T {
+    #[cfg(not(test))]
+    {
+        f(&mut COUNTERS.lock().unwrap())
+    }
+    #[cfg(test)]
+    COUNTERS.with(|counters| f(&mut counters.lock().unwrap()))
+}
+
+fn with_memory_enabled<T>(f: impl FnOnce(&mut bool) -> T) -> T {
+    #[cfg(not(test))]
+    {
+        f(&mut MEMORY_ENABLED.lock().unwrap())
+    }
+    #[cfg(test)]
+    MEMORY_ENABLED.with(|enabled| f(&mut enabled.lock().unwrap()))
+}
+
+/// Steps of the login funnel we count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    LoginStarted,
+    LoginSucceeded,
+    LoginFailed,
+    LoggedOut,
+}
+
+impl Metric {
+    fn name(&self) -> &'static str {
+        match self {
+            Metric::LoginStarted => "login_started",
+            Metric::LoginSucceeded => "login_succeeded",
+            Metric::LoginFailed => "login_failed",
+            Metric::LoggedOut => "logged_out",
+        }
+    }
+}
+
+/// Whether the user opted in to sending metrics tagged with the install id (off by default)
+pub fn is_enabled() -> bool {
+    if is_headless() {
+        return with_memory_enabled(|enabled| *enabled);
+    }
+    data_file_path(TELEMETRY_FILE)
+        .and_then(std::fs::read_to_string)
+        .is_ok_and(|v| v.trim() == "1")
+}
+
+/// Opt in or out of tagged metrics; local counters are kept either way
+pub fn set_enabled(enabled: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if is_headless() {
+        with_memory_enabled(|memory| *memory = enabled);
+        return Ok(());
+    }
+    std::fs::write(
+        data_file_path(TELEMETRY_FILE)?,
+        if enabled { "1" } else { "0" },
+    )?;
+    Ok(())
+}
+
+/// Count a metric locally and emit login-metric for the frontend to forward
+pub fn record<R: Runtime>(app: &AppHandle<R>, metric: Metric) {
+    with_counters(|counters| *counters.entry(metric.name()).or_insert(0) += 1);
+
+    let install_id = is_enabled().then(crate::install::install_id);
+    emit_logged(
+        app,
+        "login-metric",
+        serde_json::json!({
+            "metric": metric.name(),
+            "install_id": install_id,
+        }),
+    );
+}
+
+/// Local counts per metric since the app started
+pub fn counters() -> BTreeMap<&'static str, u64> {
+    with_counters(|counters| counters.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{headless, temp_data_dir, test_app};
+
+    #[test]
+    fn metrics_carry_no_install_id_unless_opted_in() {
+        let _dir = temp_data_dir::create();
+        let app = test_app::create();
+        let metrics = test_app::record(&app, "login-metric");
+        let errors = test_app::record(&app, "auth-error");
+        assert!(!is_enabled());
+
+        record(app.handle(), Metric::LoginStarted);
+        record(app.handle(), Metric::LoginFailed);
+        crate::auth::report_auth_error(app.handle(), "Login failed".to_string());
+
+        let install_id = crate::install::install_id();
+        for payload in metrics.payloads().into_iter().chain(errors.payloads()) {
+            assert_eq!(payload["install_id"], serde_json::Value::Null);
+            assert!(!payload.to_string().contains(&install_id), "{}", payload);
+        }
+        // Still counted locally, the auth error included
+        assert_eq!(
+            counters(),
+            BTreeMap::from([("login_failed", 2), ("login_started", 1)])
+        );
+
+        set_enabled(true).unwrap();
+        record(app.handle(), Metric::LoginStarted);
+        assert_eq!(metrics.payloads()[3]["install_id"], install_id.as_str());
+        assert_eq!(counters()["login_started"], 2);
+    }
+
+    #[test]
+    fn opt_in_is_kept_in_memory_when_headless() {
+        let _headless = headless::simulate();
+        assert!(!is_enabled());
+        set_enabled(true).unwrap();
+        assert!(is_enabled());
+        set_enabled(false).unwrap();
+        assert!(!is_enabled());
+    }
+}
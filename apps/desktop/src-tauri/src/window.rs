@@ -0,0 +1,213 @@
+//! Picking which window to bring forward after a deep link (desktop only)
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tauri::{AppHandle, Manager, Runtime};
+
+/// When each window last gained focus (held in Tauri managed state)
+#[derive(Default)]
+pub struct FocusTracker(Mutex<HashMap<String, Instant>>);
+
+/// Window event hook: remember when each window gains focus and forget closed ones
+///
+/// Tauri has no suspend/resume events, so regaining focus (which is what waking a laptop
+/// looks like) re-evaluates the session's expiry against the wall clock. It's one small
+/// file read, cheap enough to do on every focus.
+pub fn on_window_event(window: &tauri::Window, event: &tauri::WindowEvent) {
+    let tracker = window.state::<FocusTracker>();
+    let mut last_focus = tracker.0.lock().unwrap();
+    match event {
+        tauri::WindowEvent::Focused(true) => {
+            last_focus.insert(window.label().to_string(), Instant::now());
+            drop(last_focus);
+            recheck_session(window.app_handle());
+        }
+        tauri::WindowEvent::Destroyed => {
+            last_focus.remove(window.label());
+        }
+        _ => {}
+    }
+}
+
+/// Log out (emitting logged-out) if the session expired while we were away
+fn recheck_session<R: Runtime>(app: &AppHandle<R>) {
+    if let Err(e) = crate::auth::get_valid_session(app) {
+        log::warn!("Failed to re-check session on focus: {}", e);
+    }
+}
+
+/// Choose a window among the open ones: the preferred one if still open, otherwise the
+/// most recently focused, otherwise "main", otherwise any
+fn pick_window<'a>(
+    open: &'a [String],
+    last_focus: &HashMap<String, Instant>,
+    preferred: Option<&str>,
+) -> Option<&'a String> {
+    if let Some(preferred) = preferred {
+        if let Some(label) = open.iter().find(|label| label.as_str() == preferred) {
+            return Some(label);
+        }
+    }
+
+    open.iter()
+        .filter(|label| last_focus.contains_key(label.as_str()))
+        .max_by_key(|label| last_focus[label.as_str()])
+        .or_else(|| open.iter().find(|label| label.as_str() == "main"))
+        .or_else(|| open.first())
+}
+
+/// Show and focus the best window, creating the main window if none exist
+///
+/// `preferred` is the window that started the login, when known.
+pub fn bring_to_front<R: Runtime>(
+    app: &AppHandle<R>,
+    preferred: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let windows = app.webview_windows();
+    let open: Vec<String> = windows.keys().cloned().collect();
+
+    let chosen = {
+        let tracker = app.state::<FocusTracker>();
+        let last_focus = tracker.0.lock().unwrap();
+        pick_window(&open, &last_focus, preferred).cloned()
+    };
+
+    let window = match chosen.and_then(|label| windows.get(&label).cloned()) {
+        Some(window) => window,
+        None => {
+            log::info!("No window open, creating main window");
+            let config = app
+                .config()
+                .app
+                .windows
+                .first()
+                .ok_or("No window configured")?;
+            tauri::WebviewWindowBuilder::from_config(app, config)?.build()?
+        }
+    };
+
+    // A minimized window ignores show/focus on some platforms
+    window.unminimize()?;
+    window.show()?;
+    window.set_focus()?;
+    log::debug!("Window focused: {}", window.label());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{fake_store, get_session, now_secs, test_app};
+    use std::time::Duration;
+
+    fn labels(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn prefers_the_window_that_started_login() {
+        let open = labels(&["main", "settings"]);
+        let last_focus = HashMap::from([("main".to_string(), Instant::now())]);
+        assert_eq!(
+            pick_window(&open, &last_focus, Some("settings")).unwrap(),
+            "settings"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_most_recently_focused() {
+        let open = labels(&["main", "settings", "report"]);
+        let earlier = Instant::now();
+        let later = earlier + Duration::from_secs(5);
+        let last_focus = HashMap::from([
+            ("main".to_string(), earlier),
+            ("report".to_string(), later),
+            // Closed since, so not a candidate
+            ("gone".to_string(), later + Duration::from_secs(5)),
+        ]);
+        assert_eq!(
+            pick_window(&open, &last_focus, Some("gone")).unwrap(),
+            "report"
+        );
+    }
+
+    #[test]
+    fn without_focus_history_picks_main_then_any() {
+        let no_focus = HashMap::new();
+        assert_eq!(
+            pick_window(&labels(&["settings", "main"]), &no_focus, None).unwrap(),
+            "main"
+        );
+        assert_eq!(
+            pick_window(&labels(&["settings"]), &no_focus, None).unwrap(),
+            "settings"
+        );
+        assert!(pick_window(&[], &no_focus, None).is_none());
+    }
+
+    #[test]
+    fn creates_and_shows_the_main_window_when_none_is_open() {
+        let mut context = tauri::test::mock_context(tauri::test::noop_assets());
+        context.config_mut().app.windows = vec![tauri::utils::config::WindowConfig {
+            label: "main".to_string(),
+            visible: false,
+            ..Default::default()
+        }];
+        let app = tauri::test::mock_builder().build(context).unwrap();
+        app.manage(FocusTracker::default());
+        assert!(app.webview_windows().is_empty());
+
+        bring_to_front(app.handle(), Some("settings")).unwrap();
+
+        // The mock runtime can't report visibility or focus, only that the window exists
+        assert_eq!(app.webview_windows().keys().collect::<Vec<_>>(), ["main"]);
+        // Once it's open a second wake reuses it
+        bring_to_front(app.handle(), None).unwrap();
+        assert_eq!(app.webview_windows().len(), 1);
+    }
+
+    fn session_expiring_at(expires_at: u64) -> crate::UserSession {
+        serde_json::from_value(serde_json::json!({
+            "sealed_session": "sealed",
+            "user_id": "user_1",
+            "email": "a@example.com",
+            "first_name": null,
+            "last_name": null,
+            "schema_version": crate::SESSION_SCHEMA_VERSION,
+            "expires_at": expires_at,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn waking_with_an_expired_session_logs_out_at_once() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        // Expired an hour into a sleep no timer could see
+        fake_store::set_accounts(Some(&session_expiring_at(now_secs() - 60)), &[]);
+        let app = test_app::create();
+        let logged_out = test_app::record(&app, "logged-out");
+
+        recheck_session(app.handle());
+
+        assert_eq!(get_session().unwrap(), None);
+        let payloads = logged_out.payloads();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0]["reason"], "expired");
+    }
+
+    #[test]
+    fn waking_with_a_live_session_keeps_it() {
+        let _store = fake_store::inject(fake_store::FakeStore::default());
+        let session = session_expiring_at(now_secs() + 3600);
+        fake_store::set_accounts(Some(&session), &[]);
+        let app = test_app::create();
+        let logged_out = test_app::record(&app, "logged-out");
+
+        recheck_session(app.handle());
+
+        assert_eq!(get_session().unwrap(), Some(session));
+        assert!(logged_out.payloads().is_empty());
+    }
+}